@@ -1,4 +1,4 @@
 #!/usr/bin/env bash
 
-rm $HOME/.chain/chain_db
+rm -f "${XDG_DATA_HOME:-$HOME/.local/share}/chain/chain.db"
 cargo run
@@ -0,0 +1,560 @@
+use super::chain_error::ChainError;
+use super::database;
+use super::structs::{Chain, ImportSummary, Kind, Link, Note, Rule, State};
+use anyhow::Result;
+use chrono::{NaiveDate, NaiveTime};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+
+/// The version of the backup format, increased whenever a change would
+/// make older versions misread a backup.
+pub const VERSION: u32 = 1;
+
+/// Every chain with its links and notes, written by `export --format json`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Backup {
+    pub version: u32,
+    pub chains: Vec<BackupChain>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BackupChain {
+    pub name: String,
+    pub kind: String,
+    pub unit: Option<String>,
+    pub target: Option<f64>,
+    pub quota: Option<i32>,
+    pub description: Option<String>,
+    pub created: Option<NaiveDate>,
+    pub start: Option<NaiveDate>,
+    pub end: Option<NaiveDate>,
+    pub color: Option<String>,
+    pub icon: Option<String>,
+    pub goal: Option<String>,
+    pub archived: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub aliases: Vec<String>,
+    /// How the links of a composite chain are derived from `children`.
+    pub rule: Option<String>,
+    /// The names of the chains a composite chain is made of.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub links: Vec<BackupLink>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub notes: Vec<BackupNote>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BackupLink {
+    pub date: NaiveDate,
+    #[serde(default)]
+    pub time: Option<NaiveTime>,
+    #[serde(default)]
+    pub value: Option<f64>,
+    #[serde(default = "default_state")]
+    pub state: String,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BackupNote {
+    pub date: NaiveDate,
+    pub text: String,
+}
+
+fn default_state() -> String {
+    State::Done.as_str().to_string()
+}
+
+/// Collect `chains` with their links, notes and aliases into a backup.
+pub fn export(conn: &Connection, chains: &[Chain]) -> Result<Backup> {
+    let aliases = database::get_aliases(conn)?;
+    let mut backup_chains: Vec<BackupChain> = Vec::new();
+
+    for chain in chains.iter() {
+        let mut backup_chain = export_settings(conn, chain, &aliases)?;
+
+        backup_chain.links = database::get_links_for_chain_id(conn, chain.id as i32)?
+            .into_iter()
+            .map(|link| BackupLink {
+                date: link.date,
+                time: link.time,
+                value: link.value,
+                state: link.state.as_str().to_string(),
+                reason: link.reason,
+            })
+            .collect();
+
+        backup_chain.notes = database::get_notes_for_chain_id(conn, chain.id as i32)?
+            .into_iter()
+            .map(|note| BackupNote {
+                date: note.date,
+                text: note.text,
+            })
+            .collect();
+
+        backup_chains.push(backup_chain);
+    }
+
+    Ok(Backup {
+        version: VERSION,
+        chains: backup_chains,
+    })
+}
+
+/// The settings, tags, aliases and children of a chain, without its links
+/// and notes. `aliases` are every alias with the name of its chain.
+pub fn export_settings(conn: &Connection, chain: &Chain, aliases: &[(String, String)]) -> Result<BackupChain> {
+    let mut children: Vec<String> = Vec::new();
+    for child_id in chain.children.iter() {
+        children.push(database::get_chain_for_id(conn, *child_id as i32)?.name);
+    }
+
+    Ok(BackupChain {
+        name: chain.name.clone(),
+        kind: chain.kind.as_str().to_string(),
+        unit: chain.unit.clone(),
+        target: chain.target,
+        quota: chain.quota,
+        description: chain.description.clone(),
+        created: chain.created,
+        start: chain.start,
+        end: chain.end,
+        color: chain.color.clone(),
+        icon: chain.icon.clone(),
+        goal: chain.goal.clone(),
+        archived: chain.archived,
+        tags: chain.tags.clone(),
+        aliases: aliases
+            .iter()
+            .filter(|(_, name)| *name == chain.name)
+            .map(|(alias, _)| alias.clone())
+            .collect(),
+        rule: chain.rule.map(|rule| rule.as_str().to_string()),
+        children,
+        ..Default::default()
+    })
+}
+
+pub fn to_json(backup: &Backup) -> Result<String> {
+    Ok(serde_json::to_string_pretty(backup)?)
+}
+
+pub fn from_json(json: &str) -> Result<Backup> {
+    let backup: Backup = serde_json::from_str(json)?;
+
+    if backup.version > VERSION {
+        return Err(ChainError::new(&format!(
+            "The backup has version {}, this version of {} reads up to version {}",
+            backup.version,
+            env!("CARGO_PKG_NAME"),
+            VERSION
+        ))
+        .into());
+    }
+
+    Ok(backup)
+}
+
+/// Import a backup into the database.
+///
+/// Chains which already exist keep their settings and only gain the links
+/// and notes they don't have yet, every chain and link which could not be
+/// imported as is is reported as a conflict. With `replace` every chain is
+/// deleted first.
+pub fn import(conn: &Connection, backup: &Backup, replace: bool) -> Result<ImportSummary> {
+    let mut summary = ImportSummary::default();
+
+    // Check every chain first, so nothing is deleted or half imported.
+    for backup_chain in backup.chains.iter() {
+        check_chain(backup_chain)?;
+    }
+
+    if replace {
+        database::delete_chains(conn)?;
+    }
+
+    for backup_chain in backup.chains.iter() {
+        let chain = match database::find_chain_for_name(conn, &backup_chain.name)? {
+            Some(chain) => {
+                summary.conflicts.push(format!(
+                    "A chain named \"{}\" already exists, its links and notes were merged",
+                    chain.name
+                ));
+                summary.chains_merged += 1;
+
+                chain
+            }
+            None => {
+                add_chain(conn, backup_chain, &mut summary)?;
+                summary.added.push(backup_chain.name.clone());
+                summary.chains_added += 1;
+
+                database::find_chain_for_name(conn, &backup_chain.name)?.unwrap()
+            }
+        };
+
+        import_links(conn, &chain, backup_chain, &mut summary)?;
+        import_notes(conn, &chain, backup_chain, &mut summary)?;
+    }
+
+    // Composite chains can refer to chains later in the backup, so their
+    // children are resolved once every chain exists.
+    for backup_chain in backup.chains.iter().filter(|chain| chain.rule.is_some()) {
+        let chain = database::find_chain_for_name(conn, &backup_chain.name)?.unwrap();
+
+        if chain.rule.is_none() || !chain.children.is_empty() {
+            continue;
+        }
+
+        let mut children: Vec<i64> = Vec::new();
+
+        for name in backup_chain.children.iter() {
+            match database::find_chain_for_name(conn, name)? {
+                Some(child) => children.push(child.id),
+                None => summary.conflicts.push(format!(
+                    "\"{}\" is made of \"{}\", which doesn't exist",
+                    chain.name, name
+                )),
+            }
+        }
+
+        database::set_children(conn, chain.id, &children)?;
+    }
+
+    Ok(summary)
+}
+
+/// Check that the kind, rule and link states of a backup chain are ones
+/// `c` knows.
+pub fn check_chain(backup_chain: &BackupChain) -> Result<()> {
+    // Backups written by hand may leave out the kind of positive chains.
+    if !["", Kind::Positive.as_str(), Kind::Negative.as_str()].contains(&backup_chain.kind.as_str()) {
+        return Err(ChainError::new(&format!(
+            "\"{}\" has an unknown kind \"{}\", expected \"positive\" or \"negative\"",
+            backup_chain.name, backup_chain.kind
+        ))
+        .into());
+    }
+
+    if let Some(rule) = backup_chain.rule.as_deref().filter(|rule| Rule::parse(rule).is_none()) {
+        return Err(ChainError::new(&format!(
+            "\"{}\" has an unknown rule \"{}\", expected \"all\" or \"any\"",
+            backup_chain.name, rule
+        ))
+        .into());
+    }
+
+    // Missed days are days without links, they are never stored.
+    for link in backup_chain.links.iter() {
+        let is_valid = match State::parse(&link.state) {
+            State::Done => link.state == State::Done.as_str(),
+            State::Missed => false,
+            _ => true,
+        };
+
+        if is_valid {
+            continue;
+        }
+
+        return Err(ChainError::new(&format!(
+            "\"{}\" has a link with the invalid state \"{}\" on {}",
+            backup_chain.name, link.state, link.date
+        ))
+        .into());
+    }
+
+    Ok(())
+}
+
+/// The settings of a backup chain as a chain, without its tags, aliases and
+/// children.
+pub fn to_chain(backup_chain: &BackupChain) -> Chain {
+    Chain {
+        name: backup_chain.name.clone(),
+        unit: backup_chain.unit.clone(),
+        target: backup_chain.target,
+        quota: backup_chain.quota,
+        kind: Kind::parse(&backup_chain.kind),
+        description: backup_chain.description.clone(),
+        created: backup_chain.created,
+        start: backup_chain.start,
+        color: backup_chain.color.clone(),
+        icon: backup_chain.icon.clone(),
+        goal: backup_chain.goal.clone(),
+        end: backup_chain.end,
+        archived: backup_chain.archived,
+        rule: backup_chain.rule.as_deref().and_then(Rule::parse),
+        ..Default::default()
+    }
+}
+
+fn add_chain(conn: &Connection, backup_chain: &BackupChain, summary: &mut ImportSummary) -> Result<()> {
+    let chain = to_chain(backup_chain);
+
+    database::add_chain(conn, &chain)?;
+
+    let chain = database::find_chain_for_name(conn, &chain.name)?.unwrap();
+
+    for tag in backup_chain.tags.iter() {
+        database::add_tag(conn, &chain, tag)?;
+    }
+
+    let aliases = database::get_aliases(conn)?;
+
+    for alias in backup_chain.aliases.iter() {
+        match aliases.iter().find(|(existing, _)| existing == alias) {
+            Some((_, name)) => summary.conflicts.push(format!(
+                "The alias \"{}\" of \"{}\" already refers to \"{}\"",
+                alias, chain.name, name
+            )),
+            None => database::add_alias(conn, &chain, alias)?,
+        }
+    }
+
+    Ok(())
+}
+
+fn import_links(
+    conn: &Connection,
+    chain: &Chain,
+    backup_chain: &BackupChain,
+    summary: &mut ImportSummary,
+) -> Result<()> {
+    let mut existing = database::get_links_for_chain_id(conn, chain.id as i32)?;
+
+    // Each link which was there before the import stands in for one link of
+    // the backup, so repeated check-ins of a day are all imported.
+    let mut before = database::get_links_for_chain_id(conn, chain.id as i32)?;
+
+    for backup_link in backup_chain.links.iter() {
+        let state = State::parse(&backup_link.state);
+
+        // Formats like org-mode keep times to the minute.
+        let minute = |time: Option<NaiveTime>| time.map(|time| time.format("%H:%M").to_string());
+
+        let duplicate = before.iter().position(|link| {
+            link.date == backup_link.date
+                && minute(link.time) == minute(backup_link.time)
+                && link.state == state
+                && link.value == backup_link.value
+        });
+
+        if let Some(duplicate) = duplicate {
+            before.remove(duplicate);
+            summary.links_skipped += 1;
+            continue;
+        }
+
+        let day: Vec<State> = existing
+            .iter()
+            .filter(|link| link.date == backup_link.date)
+            .map(|link| link.state)
+            .collect();
+
+        // Marks replace each other, a day can't be both skipped and failed,
+        // and a day which is done can't be marked.
+        if state != State::Done {
+            if let Some(existing_state) = day.first() {
+                summary.conflicts.push(format!(
+                    "\"{}\" is already {} on {}, the {} mark was skipped",
+                    chain.name,
+                    existing_state.as_str(),
+                    backup_link.date,
+                    backup_link.state
+                ));
+                continue;
+            }
+        }
+
+        let link = Link {
+            id: 0,
+            chain_id: chain.id as i32,
+            date: backup_link.date,
+            time: backup_link.time,
+            value: backup_link.value,
+            state,
+            reason: backup_link.reason.clone(),
+        };
+
+        // A link replaces the mark of its day, like `add` does.
+        if state == State::Done {
+            if let Some(mark) = day.iter().find(|state| **state != State::Done) {
+                summary.conflicts.push(format!(
+                    "\"{}\" was {} on {}, the mark was replaced by a link",
+                    chain.name,
+                    mark.as_str(),
+                    backup_link.date
+                ));
+
+                database::delete_marks(conn, &link)?;
+                existing.retain(|existing| existing.date != link.date || existing.state == State::Done);
+                before.retain(|before| before.date != link.date || before.state == State::Done);
+            }
+        }
+
+        database::insert_link(conn, &link)?;
+        existing.push(link);
+        summary.links_added += 1;
+    }
+
+    Ok(())
+}
+
+fn import_notes(
+    conn: &Connection,
+    chain: &Chain,
+    backup_chain: &BackupChain,
+    summary: &mut ImportSummary,
+) -> Result<()> {
+    let mut existing = database::get_notes_for_chain_id(conn, chain.id as i32)?;
+
+    for backup_note in backup_chain.notes.iter() {
+        let is_duplicate = existing
+            .iter()
+            .any(|note| note.date == backup_note.date && note.text == backup_note.text);
+
+        if is_duplicate {
+            summary.notes_skipped += 1;
+            continue;
+        }
+
+        let note = Note {
+            id: 0,
+            chain_id: chain.id as i32,
+            date: backup_note.date,
+            text: backup_note.text.clone(),
+        };
+
+        database::add_note(conn, &note)?;
+        existing.push(note);
+        summary.notes_added += 1;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chain(notes: &[&str]) -> BackupChain {
+        BackupChain {
+            name: "read".to_string(),
+            kind: Kind::Positive.as_str().to_string(),
+            notes: notes
+                .iter()
+                .map(|text| BackupNote {
+                    date: NaiveDate::from_ymd_opt(2024, 1, 1).unwrap(),
+                    text: text.to_string(),
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn backup(chains: Vec<BackupChain>) -> Backup {
+        Backup {
+            version: VERSION,
+            chains,
+        }
+    }
+
+    #[test]
+    fn notes_repeated_in_a_backup_are_imported_once() {
+        let conn = Connection::open_in_memory().unwrap();
+        database::setup_tables(&conn).unwrap();
+
+        let summary = import(&conn, &backup(vec![chain(&["ill", "ill", "tired"])]), false).unwrap();
+
+        assert_eq!(summary.notes_added, 2);
+        assert_eq!(summary.notes_skipped, 1);
+
+        let summary = import(&conn, &backup(vec![chain(&["ill", "tired"])]), false).unwrap();
+
+        assert_eq!(summary.notes_added, 0);
+        assert_eq!(summary.notes_skipped, 2);
+    }
+
+    #[test]
+    fn repeated_check_ins_survive_a_round_trip() {
+        let conn = Connection::open_in_memory().unwrap();
+        database::setup_tables(&conn).unwrap();
+
+        let water = BackupChain {
+            name: "water".to_string(),
+            quota: Some(3),
+            links: (0..3)
+                .map(|_| BackupLink {
+                    date: NaiveDate::from_ymd_opt(2024, 1, 1).unwrap(),
+                    time: None,
+                    value: None,
+                    state: default_state(),
+                    reason: None,
+                })
+                .collect(),
+            ..chain(&[])
+        };
+        import(&conn, &backup(vec![water]), false).unwrap();
+
+        let exported = export(&conn, &database::get_chains(&conn).unwrap()).unwrap();
+        assert_eq!(exported.chains[0].links.len(), 3);
+
+        let copy = Connection::open_in_memory().unwrap();
+        database::setup_tables(&copy).unwrap();
+
+        let summary = import(&copy, &exported, false).unwrap();
+        assert_eq!((summary.links_added, summary.links_skipped), (3, 0));
+
+        // Importing it again adds nothing.
+        let summary = import(&copy, &exported, false).unwrap();
+        assert_eq!((summary.links_added, summary.links_skipped), (0, 3));
+    }
+
+    #[test]
+    fn unknown_kinds_and_rules_are_refused() {
+        let conn = Connection::open_in_memory().unwrap();
+        database::setup_tables(&conn).unwrap();
+        import(&conn, &backup(vec![chain(&[])]), false).unwrap();
+
+        let kind = BackupChain {
+            kind: "negativ".to_string(),
+            ..chain(&[])
+        };
+        let err = import(&conn, &backup(vec![kind]), true).unwrap_err();
+        assert!(err.to_string().contains("unknown kind \"negativ\""));
+
+        let rule = BackupChain {
+            rule: Some("every".to_string()),
+            ..chain(&[])
+        };
+        let err = import(&conn, &backup(vec![rule]), true).unwrap_err();
+        assert!(err.to_string().contains("unknown rule \"every\""));
+
+        for state in ["bogus", "missed"] {
+            let link = BackupChain {
+                links: vec![BackupLink {
+                    date: NaiveDate::from_ymd_opt(2024, 1, 2).unwrap(),
+                    time: None,
+                    value: None,
+                    state: state.to_string(),
+                    reason: None,
+                }],
+                ..chain(&[])
+            };
+            let err = import(&conn, &backup(vec![link]), true).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("\"read\" has a link with the invalid state \"{}\" on 2024-01-02", state)
+            );
+        }
+
+        // Nothing was replaced.
+        assert_eq!(database::get_chains(&conn).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,312 @@
+use super::{ADD_CHAIN, CHAINS, COMPLETE, CURRENT, DATES, MV, NAME, NEW, RENAME_CHAIN};
+use anyhow::Result;
+use clap::{App, Shell};
+use std::io::Write;
+
+/// Generate the completion script for `shell`.
+///
+/// clap only knows the static parts of the command line, so the script is
+/// extended to complete chain names and dates by calling the hidden
+/// `__complete` command.
+pub fn generate(app: App<'static, 'static>, shell: Shell, out: &mut dyn Write) -> Result<()> {
+    // The bash script lists the positional arguments of every subcommand,
+    // which the other shells need to know which commands take chains and
+    // dates.
+    let mut bash: Vec<u8> = Vec::new();
+    app.clone().gen_completions_to(NAME, Shell::Bash, &mut bash);
+    let bash = String::from_utf8(bash)?;
+    let commands = positionals(&bash);
+
+    let script = match shell {
+        Shell::Bash => extend_bash(&bash),
+        Shell::Zsh => extend_zsh(&generate_script(app, shell)?),
+        Shell::Fish => extend_fish(&generate_script(app, shell)?, &commands),
+        Shell::PowerShell => extend_powershell(&generate_script(app, shell)?, &commands),
+        Shell::Elvish => generate_script(app, shell)?,
+    };
+
+    out.write_all(script.as_bytes())?;
+
+    Ok(())
+}
+
+fn generate_script(mut app: App<'static, 'static>, shell: Shell) -> Result<String> {
+    let mut script: Vec<u8> = Vec::new();
+    app.gen_completions_to(NAME, shell, &mut script);
+
+    Ok(String::from_utf8(script)?)
+}
+
+/// What the value of a positional argument is completed with, `None` when
+/// it can't be completed.
+fn completion_for(command: &str, arg: &str) -> Option<&'static str> {
+    match (command, arg) {
+        // A new chain needs a new name.
+        (ADD_CHAIN, _) => None,
+        (MV, arg) if arg == CURRENT || arg == NEW => Some(DATES),
+        (RENAME_CHAIN, arg) if arg == CURRENT => Some(CHAINS),
+        (_, "CHAIN") => Some(CHAINS),
+        (_, "DATE") => Some(DATES),
+        _ => None,
+    }
+}
+
+fn complete_command(kind: &str) -> String {
+    format!("{} {} {}", NAME, COMPLETE, kind)
+}
+
+/// The subcommand a block of clap's bash script completes, e.g. `add-chain`
+/// for `c__add__chain)`.
+fn bash_command(line: &str) -> Option<String> {
+    let label = line.trim().strip_suffix(')')?;
+    let command = label.strip_prefix(&format!("{}__", NAME))?;
+
+    Some(command.replace("__", "-"))
+}
+
+/// The positional arguments of every subcommand, read from the bash script.
+fn positionals(bash: &str) -> Vec<(String, Vec<String>)> {
+    let mut commands: Vec<(String, Vec<String>)> = Vec::new();
+    let mut command: Option<String> = None;
+
+    for line in bash.lines() {
+        if let Some(name) = bash_command(line) {
+            command = Some(name);
+        } else if let (Some(name), Some(opts)) = (&command, line.trim().strip_prefix("opts=")) {
+            let args = opts
+                .split_whitespace()
+                .filter_map(|word| word.strip_prefix('<'))
+                .filter_map(|word| word.split('>').next())
+                .map(|arg| arg.to_string())
+                .collect();
+
+            commands.push((name.to_string(), args));
+            command = None;
+        }
+    }
+
+    commands
+}
+
+/// The subcommands with a positional argument completed with `kind`.
+fn commands_completing<'a>(commands: &'a [(String, Vec<String>)], kind: &str) -> Vec<&'a str> {
+    commands
+        .iter()
+        .filter(|(command, args)| args.iter().any(|arg| completion_for(command, arg) == Some(kind)))
+        .map(|(command, _)| command.as_str())
+        .collect()
+}
+
+/// Replace the `<ARG>` placeholders clap offers literally with the chain
+/// names and dates, placeholders which can't be completed are dropped.
+fn extend_bash(script: &str) -> String {
+    let mut lines: Vec<String> = Vec::new();
+    let mut command = String::new();
+
+    for line in script.lines() {
+        if let Some(name) = bash_command(line) {
+            command = name;
+        }
+
+        if !line.trim().starts_with("opts=") || command.is_empty() {
+            lines.push(line.to_string());
+            continue;
+        }
+
+        let words: Vec<String> = line
+            .split(' ')
+            .map(|word| match word.strip_prefix('<').and_then(|word| word.split('>').next()) {
+                Some(arg) => match completion_for(&command, arg) {
+                    Some(kind) => format!("$({} 2>/dev/null)", complete_command(kind)),
+                    None => String::new(),
+                },
+                None => word.to_string(),
+            })
+            .collect();
+
+        lines.push(words.join(" "));
+    }
+
+    lines.join("\n") + "\n"
+}
+
+/// Complete positional arguments with `_c_chains` and `_c_dates` instead of
+/// file names.
+fn extend_zsh(script: &str) -> String {
+    let mut lines: Vec<String> = Vec::new();
+    let mut command = String::new();
+
+    for line in script.lines() {
+        let trimmed = line.trim();
+
+        if let Some(name) = trimmed.strip_prefix('(').and_then(|name| name.strip_suffix(')')) {
+            command = name.to_string();
+        }
+
+        let arg = trimmed
+            .trim_start_matches('\'')
+            .trim_start_matches(':')
+            .split(" -- ")
+            .next()
+            .unwrap_or_default();
+
+        match completion_for(&command, arg) {
+            Some(kind) if trimmed.starts_with("':") && trimmed.contains(":_files'") => {
+                lines.push(line.replace(":_files'", &format!(":_{}_{}'", NAME, kind)));
+            }
+            _ => lines.push(line.to_string()),
+        }
+    }
+
+    let mut script = lines.join("\n") + "\n";
+
+    // The helpers have to be defined before the completion function runs.
+    let mut helpers = String::new();
+    for kind in [CHAINS, DATES] {
+        helpers.push_str(&format!(
+            "_{name}_{kind}() {{\n    local -a values\n    values=(${{(f)\"$({command} 2>/dev/null)\"}})\n    compadd -a values\n}}\n\n",
+            name = NAME,
+            kind = kind,
+            command = complete_command(kind)
+        ));
+    }
+
+    let call = format!("_{} \"$@\"", NAME);
+    if let Some(index) = script.rfind(&call) {
+        script.insert_str(index, &helpers);
+    }
+
+    script
+}
+
+fn extend_fish(script: &str, commands: &[(String, Vec<String>)]) -> String {
+    let mut script = script.to_string();
+
+    for kind in [CHAINS, DATES] {
+        let completing = commands_completing(commands, kind);
+
+        // Without any commands the condition would hold everywhere.
+        if completing.is_empty() {
+            continue;
+        }
+
+        script.push_str(&format!(
+            "complete -c {} -n \"__fish_seen_subcommand_from {}\" -f -a \"({})\"\n",
+            NAME,
+            completing.join(" "),
+            complete_command(kind)
+        ));
+    }
+
+    script
+}
+
+fn extend_powershell(script: &str, commands: &[(String, Vec<String>)]) -> String {
+    let mut lines: Vec<String> = Vec::new();
+    let mut command = String::new();
+
+    for line in script.lines() {
+        let trimmed = line.trim();
+
+        if let Some(name) = trimmed
+            .strip_prefix(&format!("'{};", NAME))
+            .and_then(|name| name.strip_suffix("' {"))
+        {
+            command = name.to_string();
+        }
+
+        if trimmed == "break" {
+            for kind in [CHAINS, DATES] {
+                if commands_completing(commands, kind).contains(&command.as_str()) {
+                    lines.push(format!(
+                        "            {} | ForEach-Object {{ [CompletionResult]::new($_, $_, [CompletionResultType]::ParameterValue, $_) }}",
+                        complete_command(kind)
+                    ));
+                }
+            }
+
+            command = String::new();
+        }
+
+        lines.push(line.to_string());
+    }
+
+    lines.join("\n") + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn script(shell: Shell) -> String {
+        let mut script: Vec<u8> = Vec::new();
+        generate(crate::build_app(), shell, &mut script).unwrap();
+
+        String::from_utf8(script).unwrap()
+    }
+
+    fn calls(script: &str, kind: &str) -> usize {
+        script.matches(&complete_command(kind)).count()
+    }
+
+    #[test]
+    fn bash_completes_chains_and_dates() {
+        let script = script(Shell::Bash);
+
+        assert!(script.contains(&format!("$({} 2>/dev/null)", complete_command(CHAINS))));
+        assert!(script.contains(&format!("$({} 2>/dev/null)", complete_command(DATES))));
+        assert!(!script.contains("<CHAIN>"));
+    }
+
+    #[test]
+    fn zsh_completes_chains_and_dates() {
+        let script = script(Shell::Zsh);
+
+        assert!(script.contains(&format!(":_{}_{}'", NAME, CHAINS)));
+        assert_eq!(calls(&script, CHAINS), 1);
+        assert_eq!(calls(&script, DATES), 1);
+        // The helpers are defined before they are used.
+        assert!(script.find(&complete_command(CHAINS)) < script.rfind(&format!("_{} \"$@\"", NAME)));
+    }
+
+    #[test]
+    fn fish_completes_chains_and_dates() {
+        let script = script(Shell::Fish);
+
+        assert_eq!(calls(&script, CHAINS), 1);
+        assert_eq!(calls(&script, DATES), 1);
+        // A new chain needs a new name.
+        let chains = script.lines().find(|line| line.contains(&complete_command(CHAINS))).unwrap();
+        assert!(chains.contains(" rename-chain "));
+        assert!(!chains.contains(&format!(" {} ", ADD_CHAIN)));
+    }
+
+    #[test]
+    fn fish_skips_kinds_no_command_completes() {
+        let commands = vec![("ls".to_string(), Vec::new()), ("mv".to_string(), vec![CURRENT.to_string()])];
+        let script = extend_fish("", &commands);
+
+        assert_eq!(calls(&script, CHAINS), 0);
+        assert_eq!(
+            script,
+            format!(
+                "complete -c {} -n \"__fish_seen_subcommand_from mv\" -f -a \"({})\"\n",
+                NAME,
+                complete_command(DATES)
+            )
+        );
+    }
+
+    #[test]
+    fn powershell_completes_chains_and_dates() {
+        let script = script(Shell::PowerShell);
+
+        assert!(calls(&script, CHAINS) > 1);
+        assert!(calls(&script, DATES) > 1);
+    }
+
+    #[test]
+    fn elvish_scripts_are_generated() {
+        assert!(script(Shell::Elvish).contains(&format!("edit:completion:arg-completer[{}]", NAME)));
+    }
+}
@@ -0,0 +1,361 @@
+use super::chain_error::ChainError;
+use super::logic;
+use super::store;
+use anyhow::{anyhow, Result};
+use chrono::{NaiveDate, Weekday};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const DATABASE: &str = "database";
+pub const BACKEND: &str = "backend";
+pub const DATE_FORMAT: &str = "date_format";
+pub const WEEK_START: &str = "week_start";
+pub const DAYS: &str = "days";
+pub const THEME: &str = "theme";
+pub const DAY_START: &str = "day_start";
+pub const OUTPUT: &str = "output";
+pub const PROFILE: &str = "profile";
+pub const ICS_FEED: &str = "ics_feed";
+pub const SYNC_DIR: &str = "sync_dir";
+
+pub const KEYS: &[&str] = &[
+    DATABASE,
+    BACKEND,
+    PROFILE,
+    DATE_FORMAT,
+    WEEK_START,
+    DAYS,
+    THEME,
+    DAY_START,
+    OUTPUT,
+    ICS_FEED,
+    SYNC_DIR,
+];
+
+/// The profile using the default database.
+pub const DEFAULT_PROFILE: &str = "default";
+
+const THEMES: &[&str] = &["default", "plain"];
+const OUTPUTS: &[&str] = &["text", "machine"];
+
+/// The settings read from `config.toml`, every setting is optional in the
+/// file and can be overridden on the command line.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The database file, defaults to `$XDG_DATA_HOME/chain/chain.db`, or
+    /// the directory of the text backend, `$XDG_DATA_HOME/chain/chains`.
+    pub database: Option<PathBuf>,
+    /// How chains are stored: `sqlite` or `text`.
+    pub backend: String,
+    /// The profile in use, each profile has its own database. A profile
+    /// other than `default` takes precedence over `database`.
+    pub profile: Option<String>,
+    /// The format dates are displayed in.
+    pub date_format: String,
+    /// The first day of the week, weeks are separated in the day strip.
+    pub week_start: Option<String>,
+    /// The number of days in the day strip.
+    pub days: usize,
+    /// How days and chains are colored: `default` or `plain`.
+    pub theme: String,
+    /// The hour a new day starts at, so late nights count as the day before.
+    pub day_start: u32,
+    /// The default output format: `text` or `machine`.
+    pub output: String,
+    /// An iCalendar file rewritten after every change, for calendar apps to
+    /// subscribe to.
+    pub ics_feed: Option<PathBuf>,
+    /// A folder shared between devices, e.g. with Syncthing, which `c sync
+    /// dir` merges from and publishes to.
+    pub sync_dir: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            database: None,
+            backend: store::SQLITE.to_string(),
+            profile: None,
+            date_format: "%Y-%m-%d".to_string(),
+            week_start: None,
+            days: 10,
+            theme: "default".to_string(),
+            day_start: 0,
+            output: "text".to_string(),
+            ics_feed: None,
+            sync_dir: None,
+        }
+    }
+}
+
+impl Config {
+    /// The current day, which starts at `day_start`.
+    pub fn today(&self) -> NaiveDate {
+        logic::today(self.day_start)
+    }
+
+    /// Get a setting as text, unset settings are empty.
+    pub fn get(&self, key: &str) -> Result<String> {
+        let value = match key {
+            DATABASE => self
+                .database
+                .as_ref()
+                .map(|path| path.display().to_string())
+                .unwrap_or_default(),
+            BACKEND => self.backend.to_string(),
+            PROFILE => self.profile().to_string(),
+            DATE_FORMAT => self.date_format.to_string(),
+            WEEK_START => self.week_start.clone().unwrap_or_default(),
+            DAYS => self.days.to_string(),
+            THEME => self.theme.to_string(),
+            DAY_START => self.day_start.to_string(),
+            OUTPUT => self.output.to_string(),
+            ICS_FEED => self
+                .ics_feed
+                .as_ref()
+                .map(|path| path.display().to_string())
+                .unwrap_or_default(),
+            SYNC_DIR => self
+                .sync_dir
+                .as_ref()
+                .map(|path| path.display().to_string())
+                .unwrap_or_default(),
+            _ => return Err(unknown_key(key)),
+        };
+
+        Ok(value)
+    }
+
+    /// Change a setting, an empty value unsets optional settings.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let optional = |value: &str| {
+            if value.is_empty() {
+                None
+            } else {
+                Some(value.to_string())
+            }
+        };
+
+        match key {
+            DATABASE => self.database = optional(value).map(PathBuf::from),
+            BACKEND => self.backend = value.to_string(),
+            PROFILE => self.profile = optional(value).filter(|profile| profile != DEFAULT_PROFILE),
+            DATE_FORMAT => self.date_format = value.to_string(),
+            WEEK_START => self.week_start = optional(value),
+            DAYS => self.days = value.parse()?,
+            THEME => self.theme = value.to_string(),
+            DAY_START => self.day_start = value.parse()?,
+            OUTPUT => self.output = value.to_string(),
+            ICS_FEED => self.ics_feed = optional(value).map(PathBuf::from),
+            SYNC_DIR => self.sync_dir = optional(value).map(PathBuf::from),
+            _ => return Err(unknown_key(key)),
+        }
+
+        self.validate()
+    }
+
+    /// Every setting with its value.
+    pub fn list(&self) -> Vec<(&'static str, String)> {
+        KEYS.iter()
+            .map(|key| (*key, self.get(key).unwrap_or_default()))
+            .collect()
+    }
+
+    /// The name of the profile in use.
+    pub fn profile(&self) -> &str {
+        self.profile.as_deref().unwrap_or(DEFAULT_PROFILE)
+    }
+
+    /// The first day of the week, if weeks should be separated.
+    pub fn week_start(&self) -> Option<Weekday> {
+        self.week_start.as_ref().and_then(|day| day.parse().ok())
+    }
+
+    /// Whether the chosen theme uses colors.
+    pub fn is_colored(&self) -> bool {
+        self.theme != "plain"
+    }
+
+    pub fn is_machine(&self) -> bool {
+        self.output == "machine"
+    }
+
+    pub fn format_date(&self, date: NaiveDate) -> String {
+        date.format(&self.date_format).to_string()
+    }
+
+    fn validate(&self) -> Result<()> {
+        if let Some(profile) = &self.profile {
+            validate_profile(profile)?;
+        }
+
+        if !store::BACKENDS.contains(&self.backend.as_str()) {
+            return Err(invalid(BACKEND, &self.backend));
+        }
+
+        if let Some(day) = &self.week_start {
+            if day.parse::<Weekday>().is_err() {
+                return Err(invalid(WEEK_START, day));
+            }
+        }
+
+        if self.days == 0 {
+            return Err(invalid(DAYS, "0"));
+        }
+
+        if !THEMES.contains(&self.theme.as_str()) {
+            return Err(invalid(THEME, &self.theme));
+        }
+
+        if self.day_start > 23 {
+            return Err(invalid(DAY_START, &self.day_start.to_string()));
+        }
+
+        if !OUTPUTS.contains(&self.output.as_str()) {
+            return Err(invalid(OUTPUT, &self.output));
+        }
+
+        // chrono only reports invalid format strings while formatting.
+        let mut formatted = String::new();
+        let date = NaiveDate::from_ymd_opt(2000, 1, 1).unwrap();
+        if std::fmt::write(&mut formatted, format_args!("{}", date.format(&self.date_format))).is_err() {
+            return Err(invalid(DATE_FORMAT, &self.date_format));
+        }
+
+        Ok(())
+    }
+}
+
+fn unknown_key(key: &str) -> anyhow::Error {
+    ChainError::new(&format!(
+        "Unknown setting \"{}\", the settings are {}",
+        key,
+        KEYS.join(", ")
+    ))
+    .into()
+}
+
+fn invalid(key: &str, value: &str) -> anyhow::Error {
+    ChainError::new(&format!("Invalid {} \"{}\"", key, value)).into()
+}
+
+fn home_dir() -> Result<PathBuf> {
+    dirs::home_dir().ok_or(anyhow!("Failed to locate the users home directory"))
+}
+
+/// The directory named by the XDG environment variable `var`, or `fallback`
+/// in the home directory if it is unset.
+fn xdg_dir(var: &str, fallback: &str) -> Result<PathBuf> {
+    match std::env::var_os(var) {
+        Some(dir) if !dir.is_empty() => Ok(PathBuf::from(dir)),
+        _ => Ok(home_dir()?.join(fallback)),
+    }
+}
+
+/// The path of the config file, `$XDG_CONFIG_HOME/chain/config.toml`.
+pub fn config_path() -> Result<PathBuf> {
+    Ok(xdg_dir("XDG_CONFIG_HOME", ".config")?
+        .join("chain")
+        .join("config.toml"))
+}
+
+/// The default database file, `$XDG_DATA_HOME/chain/chain.db`.
+pub fn database_path() -> Result<PathBuf> {
+    Ok(xdg_dir("XDG_DATA_HOME", ".local/share")?
+        .join("chain")
+        .join("chain.db"))
+}
+
+/// Profile names become file names, so only letters, digits, `-` and `_`
+/// are allowed.
+pub fn validate_profile(profile: &str) -> Result<()> {
+    let is_valid = !profile.is_empty()
+        && profile.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+    if !is_valid {
+        return Err(invalid(PROFILE, profile));
+    }
+
+    Ok(())
+}
+
+/// The default directory of the text backend, `$XDG_DATA_HOME/chain/chains`.
+pub fn text_path() -> Result<PathBuf> {
+    Ok(database_path()?.with_file_name("chains"))
+}
+
+/// The database file of a profile, the default profile uses the default
+/// database and the others `$XDG_DATA_HOME/chain/profiles/NAME.db`. With
+/// the text backend profiles are directories without the extension.
+pub fn profile_path(profile: &str, backend: &str) -> Result<PathBuf> {
+    match (profile, backend) {
+        (DEFAULT_PROFILE, store::TEXT) => return text_path(),
+        (DEFAULT_PROFILE, _) => return database_path(),
+        _ => validate_profile(profile)?,
+    }
+
+    match backend {
+        store::TEXT => Ok(profiles_dir()?.join(profile)),
+        _ => Ok(profiles_dir()?.join(format!("{}.db", profile))),
+    }
+}
+
+fn profiles_dir() -> Result<PathBuf> {
+    Ok(database_path()?.with_file_name("profiles"))
+}
+
+/// The names of every profile, starting with the default profile.
+pub fn profiles() -> Result<Vec<String>> {
+    let mut profiles: Vec<String> = Vec::new();
+
+    if let Ok(entries) = fs::read_dir(profiles_dir()?) {
+        for entry in entries {
+            let path = entry?.path();
+
+            let is_profile = path.is_dir() || path.extension().is_some_and(|extension| extension == "db");
+
+            if is_profile {
+                if let Some(name) = path.file_stem().and_then(|name| name.to_str()) {
+                    if !profiles.iter().any(|profile| profile == name) {
+                        profiles.push(name.to_string());
+                    }
+                }
+            }
+        }
+    }
+
+    profiles.sort();
+    profiles.insert(0, DEFAULT_PROFILE.to_string());
+
+    Ok(profiles)
+}
+
+/// Where the database was kept before it moved to the XDG data directory.
+pub fn legacy_database_path() -> Result<PathBuf> {
+    Ok(home_dir()?.join(".c").join("c.db"))
+}
+
+/// Load the config file, a missing file leaves every setting at its default.
+pub fn load(path: &Path) -> Result<Config> {
+    if !path.exists() {
+        return Ok(Config::default());
+    }
+
+    let config: Config = toml::from_str(&fs::read_to_string(path)?)
+        .map_err(|err| anyhow!("Failed to read {}: {}", path.display(), err))?;
+    config.validate()?;
+
+    Ok(config)
+}
+
+pub fn save(path: &Path, config: &Config) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    fs::write(path, toml::to_string(config)?)?;
+
+    Ok(())
+}
@@ -1,10 +1,170 @@
+use super::chain_error::ChainError;
+use super::history;
+use super::logic;
+use super::oplog;
+use super::store::Store;
+use super::sync;
 use super::Chain;
+use super::DatabaseInfo;
+use super::Kind;
 use super::Link;
+use super::Note;
+use super::Rule;
+use super::State;
 use anyhow::Result;
-use chrono::NaiveDate;
-use rusqlite::{params, Connection};
+use chrono::{NaiveDate, NaiveTime};
+use rusqlite::types::Value as SqlValue;
+use rusqlite::{params, params_from_iter, Connection, Row};
+use serde_json::{Map, Number, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
 
-use super::FORMAT;
+use super::{FORMAT, TIME_FORMAT};
+
+// Schema changes applied on top of the original tables, in order.
+// The index of the last applied migration is stored in `user_version`.
+const MIGRATIONS: &[&str] = &[
+    "ALTER TABLE chains ADD COLUMN unit TEXT;
+    ALTER TABLE chains ADD COLUMN target REAL;
+    ALTER TABLE links ADD COLUMN value REAL;",
+    "CREATE TABLE notes (
+        id              INTEGER PRIMARY KEY,
+        chain_id        INTEGER NOT NULL,
+        date            TEXT NOT NULL,
+        text            TEXT NOT NULL,
+        FOREIGN KEY (chain_id) REFERENCES chains(id)
+    );",
+    "CREATE TABLE links_new (
+        id              INTEGER PRIMARY KEY,
+        chain_id        INTEGER NOT NULL,
+        date            TEXT NOT NULL,
+        time            TEXT,
+        value           REAL,
+        FOREIGN KEY (chain_id) REFERENCES chains(id)
+    );
+    INSERT INTO links_new (chain_id, date, value) SELECT chain_id, date, value FROM links;
+    DROP TABLE links;
+    ALTER TABLE links_new RENAME TO links;
+    CREATE INDEX links_chain_id_date ON links (chain_id, date);
+    ALTER TABLE chains ADD COLUMN quota INTEGER;",
+    "ALTER TABLE chains ADD COLUMN kind TEXT NOT NULL DEFAULT 'positive';",
+    "ALTER TABLE links ADD COLUMN state TEXT NOT NULL DEFAULT 'done';
+    ALTER TABLE links ADD COLUMN reason TEXT;",
+    "ALTER TABLE chains ADD COLUMN description TEXT;
+    ALTER TABLE chains ADD COLUMN created TEXT;
+    ALTER TABLE chains ADD COLUMN start TEXT;
+    ALTER TABLE chains ADD COLUMN color TEXT;
+    ALTER TABLE chains ADD COLUMN icon TEXT;
+    ALTER TABLE chains ADD COLUMN goal TEXT;",
+    "ALTER TABLE chains ADD COLUMN end_date TEXT;
+    ALTER TABLE chains ADD COLUMN archived INTEGER NOT NULL DEFAULT 0;",
+    "CREATE TABLE tags (
+        id              INTEGER PRIMARY KEY,
+        chain_id        INTEGER NOT NULL,
+        tag             TEXT NOT NULL,
+        UNIQUE (chain_id, tag),
+        FOREIGN KEY (chain_id) REFERENCES chains(id)
+    );",
+    "ALTER TABLE chains ADD COLUMN rule TEXT;
+    CREATE TABLE components (
+        id              INTEGER PRIMARY KEY,
+        chain_id        INTEGER NOT NULL,
+        child_id        INTEGER NOT NULL,
+        UNIQUE (chain_id, child_id),
+        FOREIGN KEY (chain_id) REFERENCES chains(id),
+        FOREIGN KEY (child_id) REFERENCES chains(id)
+    );",
+    "CREATE TABLE aliases (
+        id              INTEGER PRIMARY KEY,
+        chain_id        INTEGER NOT NULL,
+        alias           TEXT NOT NULL UNIQUE,
+        FOREIGN KEY (chain_id) REFERENCES chains(id)
+    );",
+    "ALTER TABLE chains ADD COLUMN uuid TEXT;
+    ALTER TABLE chains ADD COLUMN updated TEXT;
+    ALTER TABLE links ADD COLUMN uuid TEXT;
+    ALTER TABLE links ADD COLUMN updated TEXT;
+    ALTER TABLE notes ADD COLUMN uuid TEXT;
+    ALTER TABLE notes ADD COLUMN updated TEXT;
+    UPDATE chains SET uuid = lower(hex(randomblob(16)));
+    UPDATE links SET uuid = lower(hex(randomblob(16)));
+    UPDATE notes SET uuid = lower(hex(randomblob(16)));
+    CREATE UNIQUE INDEX chains_uuid ON chains (uuid);
+    CREATE UNIQUE INDEX links_uuid ON links (uuid);
+    CREATE UNIQUE INDEX notes_uuid ON notes (uuid);
+    CREATE TABLE tombstones (
+        uuid            TEXT PRIMARY KEY,
+        deleted         TEXT NOT NULL
+    );",
+    "CREATE TABLE replica (
+        device          TEXT NOT NULL,
+        physical        INTEGER NOT NULL,
+        counter         INTEGER NOT NULL
+    );
+    INSERT INTO replica (device, physical, counter) VALUES (lower(hex(randomblob(8))), 0, 0);
+    CREATE TABLE ops (
+        hlc             TEXT PRIMARY KEY,
+        device          TEXT NOT NULL,
+        tbl             TEXT NOT NULL,
+        uuid            TEXT NOT NULL,
+        row             TEXT
+    );
+    CREATE INDEX ops_uuid ON ops (uuid, hlc);
+    CREATE TABLE pending_ops (
+        uuid            TEXT PRIMARY KEY,
+        tbl             TEXT NOT NULL
+    );
+    INSERT INTO pending_ops (uuid, tbl) SELECT uuid, 'chains' FROM chains;
+    INSERT INTO pending_ops (uuid, tbl) SELECT uuid, 'links' FROM links;
+    INSERT INTO pending_ops (uuid, tbl) SELECT uuid, 'notes' FROM notes;",
+    "ALTER TABLE replica ADD COLUMN location TEXT;",
+    "CREATE TABLE devices (
+        device          TEXT PRIMARY KEY
+    );
+    INSERT INTO devices (device) SELECT device FROM replica;",
+    // Removing a chain used to leave the notes of days without links.
+    "DELETE FROM notes WHERE chain_id NOT IN (SELECT id FROM chains);",
+];
+
+const CHAIN_COLUMNS: &str =
+    "id, name, unit, target, quota, kind, description, created, start, color, icon, goal, end_date, archived,
+    (SELECT group_concat(tag, ',') FROM tags WHERE tags.chain_id = chains.id),
+    rule,
+    (SELECT group_concat(child_id, ',') FROM components WHERE components.chain_id = chains.id)";
+
+/// The default store, a SQLite database file.
+pub struct SqliteStore {
+    path: PathBuf,
+}
+
+impl SqliteStore {
+    pub fn new(path: &Path) -> SqliteStore {
+        SqliteStore {
+            path: path.to_path_buf(),
+        }
+    }
+}
+
+impl Store for SqliteStore {
+    fn open(&self) -> Result<Connection> {
+        if let Some(dir) = self.path.parent() {
+            if !dir.as_os_str().is_empty() && !dir.exists() {
+                fs::create_dir_all(dir)?;
+            }
+        }
+
+        let conn = Connection::open(&self.path)?;
+
+        setup_tables(&conn)?;
+
+        Ok(conn)
+    }
+
+    // The changes are saved when the transaction is committed.
+    fn save(&self, _conn: &Connection) -> Result<()> {
+        Ok(())
+    }
+}
 
 pub fn setup_tables(conn: &Connection) -> Result<()> {
     conn.execute(
@@ -25,98 +185,401 @@ pub fn setup_tables(conn: &Connection) -> Result<()> {
         params![],
     )?;
 
+    migrate(conn)?;
+
+    history::setup_tables(conn)?;
+    sync::setup_triggers(conn)?;
+    oplog::setup_triggers(conn)?;
+
     Ok(())
 }
 
+fn migrate(conn: &Connection) -> Result<()> {
+    let version: usize =
+        conn.query_row("PRAGMA user_version;", params![], |row| row.get::<_, i64>(0))? as usize;
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        conn.execute_batch(migration)?;
+        conn.pragma_update(None, "user_version", &((index + 1) as i64))?;
+    }
+
+    Ok(())
+}
+
+fn date_from_row(row: &Row, index: usize) -> rusqlite::Result<Option<NaiveDate>> {
+    let date_str: Option<String> = row.get(index)?;
+
+    Ok(date_str.map(|date| NaiveDate::parse_from_str(&date, FORMAT).unwrap()))
+}
+
+fn chain_from_row(row: &Row) -> rusqlite::Result<Chain> {
+    Ok(Chain {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        unit: row.get(2)?,
+        target: row.get(3)?,
+        quota: row.get(4)?,
+        kind: Kind::parse(&row.get::<_, String>(5)?),
+        description: row.get(6)?,
+        created: date_from_row(row, 7)?,
+        start: date_from_row(row, 8)?,
+        color: row.get(9)?,
+        icon: row.get(10)?,
+        goal: row.get(11)?,
+        end: date_from_row(row, 12)?,
+        archived: row.get(13)?,
+        tags: row
+            .get::<_, Option<String>>(14)?
+            .map(|tags| tags.split(',').map(|tag| tag.to_string()).collect())
+            .unwrap_or_default(),
+        rule: row
+            .get::<_, Option<String>>(15)?
+            .and_then(|rule| Rule::parse(&rule)),
+        children: row
+            .get::<_, Option<String>>(16)?
+            .map(|children| children.split(',').filter_map(|id| id.parse().ok()).collect())
+            .unwrap_or_default(),
+    })
+}
+
 pub fn add_chain(conn: &Connection, chain: &Chain) -> Result<()> {
     conn.execute(
-        "INSERT OR IGNORE INTO chains (name)
-                VALUES (?1)",
-        params![chain.name,],
+        "INSERT OR IGNORE INTO chains (
+                    name, unit, target, quota, kind, description, created, start, color, icon, goal,
+                    end_date, archived, rule
+                )
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
+        params![
+            chain.name,
+            chain.unit,
+            chain.target,
+            chain.quota,
+            chain.kind.as_str(),
+            chain.description,
+            chain.created.map(|date| date.format(FORMAT).to_string()),
+            chain.start.map(|date| date.format(FORMAT).to_string()),
+            chain.color,
+            chain.icon,
+            chain.goal,
+            chain.end.map(|date| date.format(FORMAT).to_string()),
+            chain.archived,
+            chain.rule.map(|rule| rule.as_str().to_string())
+        ],
+    )?;
+
+    Ok(())
+}
+
+/// Update the metadata of a chain, the name and kind are left untouched.
+pub fn edit_chain(conn: &Connection, chain: &Chain) -> Result<()> {
+    conn.execute(
+        "UPDATE chains
+            SET
+                unit = ?2,
+                target = ?3,
+                quota = ?4,
+                description = ?5,
+                start = ?6,
+                color = ?7,
+                icon = ?8,
+                goal = ?9,
+                end_date = ?10,
+                archived = ?11,
+                rule = ?12
+            WHERE
+                id = ?1;",
+        params![
+            chain.id,
+            chain.unit,
+            chain.target,
+            chain.quota,
+            chain.description,
+            chain.start.map(|date| date.format(FORMAT).to_string()),
+            chain.color,
+            chain.icon,
+            chain.goal,
+            chain.end.map(|date| date.format(FORMAT).to_string()),
+            chain.archived,
+            chain.rule.map(|rule| rule.as_str().to_string())
+        ],
     )?;
 
     Ok(())
 }
 
+/// Replace the chains a composite chain is made of.
+pub fn set_children(conn: &Connection, chain_id: i64, children: &[i64]) -> Result<()> {
+    conn.execute(
+        "DELETE FROM components WHERE chain_id=?1;",
+        params![chain_id],
+    )?;
+
+    for child_id in children.iter() {
+        conn.execute(
+            "INSERT OR IGNORE INTO components (chain_id, child_id)
+                VALUES (?1, ?2);",
+            params![chain_id, child_id],
+        )?;
+    }
+
+    Ok(())
+}
+
 pub fn delete_chain_for_name(conn: &Connection, chain_name: &str) -> Result<()> {
+    conn.execute(
+        "DELETE FROM tags WHERE chain_id IN (SELECT id FROM chains WHERE name=?1)",
+        params![chain_name],
+    )?;
+    conn.execute(
+        "DELETE FROM components
+            WHERE chain_id IN (SELECT id FROM chains WHERE name=?1)
+            OR child_id IN (SELECT id FROM chains WHERE name=?1)",
+        params![chain_name],
+    )?;
+    conn.execute(
+        "DELETE FROM aliases WHERE chain_id IN (SELECT id FROM chains WHERE name=?1)",
+        params![chain_name],
+    )?;
+    conn.execute(
+        "DELETE FROM notes WHERE chain_id IN (SELECT id FROM chains WHERE name=?1)",
+        params![chain_name],
+    )?;
     conn.execute("DELETE FROM chains WHERE name=?1", params![chain_name])?;
 
     Ok(())
 }
 
+/// Delete every chain with its links, notes, tags, components and aliases.
+pub fn delete_chains(conn: &Connection) -> Result<()> {
+    for table in ["links", "notes", "tags", "components", "aliases", "chains"] {
+        conn.execute(&format!("DELETE FROM {};", table), params![])?;
+    }
+
+    Ok(())
+}
+
 pub fn edit_chain_for_name(conn: &Connection, chain: &Chain, name: &str) -> Result<()> {
     conn.execute(
-        "UPDATE chains 
-            SET 
-                name = ?2,
-            WHERE 
+        "UPDATE chains
+            SET
+                name = ?2
+            WHERE
                 name = ?1;",
-        params![name, chain.name],
+        params![chain.name, name],
     )?;
 
     Ok(())
 }
 
 pub fn get_chains(conn: &Connection) -> Result<Vec<Chain>> {
-    let mut statement = conn.prepare(
-        "SELECT 
-                id, 
-                name
+    let mut statement = conn.prepare(&format!(
+        "SELECT {}
             FROM chains
             ORDER BY name ASC;",
-    )?;
-    let chain_iter = statement.query_map([], |row| {
-        Ok(Chain {
-            id: row.get(0)?,
-            name: row.get(1)?,
-        })
-    })?;
+        CHAIN_COLUMNS
+    ))?;
+    let chain_iter = statement.query_map([], chain_from_row)?;
 
     Ok(chain_iter.filter_map(Result::ok).collect())
 }
 
+/// Get the id of the chain `chain_name` refers to, see `get_chain_for_name`.
 pub fn get_chain_id_for_name(conn: &Connection, chain_name: &str) -> Result<i32> {
-    Ok(conn.query_row_and_then(
-        "SELECT id FROM chains WHERE name=?;",
-        params![chain_name],
-        |row| row.get(0),
-    )?)
+    Ok(get_chain_for_name(conn, chain_name)?.id as i32)
 }
 
 pub fn get_chain_for_id(conn: &Connection, chain_id: i32) -> Result<Chain> {
     let chain = conn.query_row(
-        "SELECT id, name FROM chains WHERE id=?1;",
+        &format!("SELECT {} FROM chains WHERE id=?1;", CHAIN_COLUMNS),
         params![chain_id],
-        |row| {
-            Ok(Chain {
-                id: row.get(0)?,
-                name: row.get(1)?,
-            })
-        },
+        chain_from_row,
     )?;
 
     Ok(chain)
 }
 
+fn find_chain(conn: &Connection, condition: &str, value: &str) -> Result<Option<Chain>> {
+    let mut statement = conn.prepare(&format!(
+        "SELECT {} FROM chains WHERE {};",
+        CHAIN_COLUMNS, condition
+    ))?;
+    let mut chain_iter = statement.query_map(params![value], chain_from_row)?;
+
+    Ok(chain_iter.next().transpose()?)
+}
+
+/// Get the chain named exactly `chain_name`, without the lookups of
+/// `get_chain_for_name`.
+pub fn find_chain_for_name(conn: &Connection, chain_name: &str) -> Result<Option<Chain>> {
+    find_chain(conn, "name=?1", chain_name)
+}
+
+/// Get the chain `chain_name` refers to.
+///
+/// The name is matched in order against the exact chain names, the aliases,
+/// the chain ids, the names ignoring case and finally a unique prefix of the
+/// names. When nothing matches, the error suggests the closest names.
 pub fn get_chain_for_name(conn: &Connection, chain_name: &str) -> Result<Chain> {
-    let chain = conn.query_row(
-        "SELECT id, name FROM chains WHERE name=?1;",
-        params![chain_name],
-        |row| {
-            Ok(Chain {
-                id: row.get(0)?,
-                name: row.get(1)?,
-            })
-        },
+    let lookups = [
+        "name=?1",
+        "id IN (SELECT chain_id FROM aliases WHERE alias=?1)",
+        "CAST(id AS TEXT)=?1",
+        "name=?1 COLLATE NOCASE",
+    ];
+
+    for condition in lookups.iter() {
+        if let Some(chain) = find_chain(conn, condition, chain_name)? {
+            return Ok(chain);
+        }
+    }
+
+    let query = chain_name.to_lowercase();
+    let mut chains: Vec<Chain> = get_chains(conn)?
+        .into_iter()
+        .filter(|chain| chain.name.to_lowercase().starts_with(&query))
+        .collect();
+
+    match chains.len() {
+        1 => return Ok(chains.remove(0)),
+        0 => (),
+        _ => {
+            let names: Vec<String> = chains
+                .iter()
+                .map(|chain| format!("\"{}\"", chain.name))
+                .collect();
+
+            return Err(ChainError::new(&format!(
+                "\"{}\" is ambiguous, it matches {}",
+                chain_name,
+                names.join(", ")
+            ))
+            .into());
+        }
+    }
+
+    let mut names: Vec<String> = get_chains(conn)?
+        .into_iter()
+        .map(|chain| chain.name)
+        .collect();
+    names.extend(get_aliases(conn)?.into_iter().map(|(alias, _)| alias));
+
+    let message = match logic::suggest(chain_name, &names).as_slice() {
+        [] => format!("No chain named \"{}\"", chain_name),
+        suggestions => format!(
+            "No chain named \"{}\", did you mean {}?",
+            chain_name,
+            suggestions
+                .iter()
+                .map(|name| format!("\"{}\"", name))
+                .collect::<Vec<String>>()
+                .join(" or ")
+        ),
+    };
+
+    Err(ChainError::new(&message).into())
+}
+
+pub fn add_alias(conn: &Connection, chain: &Chain, alias: &str) -> Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO aliases (chain_id, alias)
+                VALUES (?1, ?2);",
+        params![chain.id, alias],
     )?;
 
-    Ok(chain)
+    Ok(())
+}
+
+/// Delete an alias, returning whether it existed.
+pub fn delete_alias(conn: &Connection, alias: &str) -> Result<bool> {
+    let deleted = conn.execute("DELETE FROM aliases WHERE alias=?1;", params![alias])?;
+
+    Ok(deleted > 0)
+}
+
+/// Get every alias with the name of the chain it refers to.
+pub fn get_aliases(conn: &Connection) -> Result<Vec<(String, String)>> {
+    let mut statement = conn.prepare(
+        "SELECT alias, name
+            FROM aliases
+            JOIN chains ON chains.id = aliases.chain_id
+            ORDER BY alias ASC;",
+    )?;
+    let alias_iter = statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+    Ok(alias_iter.filter_map(Result::ok).collect())
 }
 
+/// Add a link to a chain, merging it with an existing link on the same day.
+///
+/// Adding a value to a day which already has a link adds the value to the
+/// day's total instead of being ignored.
 pub fn add_link(conn: &Connection, link: &Link) -> Result<()> {
+    let updated = conn.execute(
+        "UPDATE links
+            SET value = CASE
+                WHEN ?3 IS NULL THEN value
+                ELSE COALESCE(value, 0) + ?3
+            END
+            WHERE chain_id = ?1 AND date = ?2 AND state = 'done';",
+        params![
+            link.chain_id,
+            link.date.format(FORMAT).to_string(),
+            link.value
+        ],
+    )?;
+
+    if updated == 0 {
+        insert_link(conn, link)?;
+    }
+
+    Ok(())
+}
+
+/// Add a link to a chain as a separate check-in, even if the day already
+/// has a link.
+pub fn insert_link(conn: &Connection, link: &Link) -> Result<()> {
     conn.execute(
-        "INSERT OR IGNORE INTO links (chain_id, date)
-                VALUES (?1, ?2);",
+        "INSERT INTO links (chain_id, date, time, value, state, reason)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6);",
+        params![
+            link.chain_id,
+            link.date.format(FORMAT).to_string(),
+            link.time.map(|time| time.format(TIME_FORMAT).to_string()),
+            link.value,
+            link.state.as_str(),
+            link.reason
+        ],
+    )?;
+
+    Ok(())
+}
+
+/// Mark a day on a chain as failed, skipped or excused, replacing any
+/// previous mark for the day. A day which is already done can't be marked.
+pub fn set_mark(conn: &Connection, link: &Link) -> Result<()> {
+    let done: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM links WHERE chain_id=?1 AND date=?2 AND state = 'done';",
+        params![link.chain_id, link.date.format(FORMAT).to_string()],
+        |row| row.get(0),
+    )?;
+
+    if done > 0 {
+        return Err(ChainError::new(&format!(
+            "{} already has {} link(s), remove them before marking the day",
+            link.date.format(FORMAT),
+            done
+        ))
+        .into());
+    }
+
+    delete_marks(conn, link)?;
+    insert_link(conn, link)
+}
+
+/// Delete the failed, skipped or excused mark of the day of `link`.
+pub fn delete_marks(conn: &Connection, link: &Link) -> Result<()> {
+    conn.execute(
+        "DELETE FROM links WHERE chain_id=?1 AND date=?2 AND state != 'done';",
         params![link.chain_id, link.date.format(FORMAT).to_string()],
     )?;
 
@@ -129,6 +592,18 @@ pub fn delete_link(conn: &Connection, link: &Link) -> Result<()> {
         params![link.chain_id, link.date.format(FORMAT).to_string()],
     )?;
 
+    conn.execute(
+        "DELETE FROM notes WHERE chain_id=?1 AND date=?2;",
+        params![link.chain_id, link.date.format(FORMAT).to_string()],
+    )?;
+
+    Ok(())
+}
+
+/// Delete a single check-in from a chain.
+pub fn delete_link_for_id(conn: &Connection, link_id: i64) -> Result<()> {
+    conn.execute("DELETE FROM links WHERE id=?1;", params![link_id])?;
+
     Ok(())
 }
 
@@ -142,27 +617,329 @@ pub fn update(conn: &Connection, current: &Link, new: &Link) -> Result<()> {
         ],
     )?;
 
+    conn.execute(
+        "UPDATE notes SET date = ?1 WHERE chain_id = ?2 AND date = ?3",
+        params![
+            new.date.format(FORMAT).to_string(),
+            current.chain_id,
+            current.date.format(FORMAT).to_string()
+        ],
+    )?;
+
     Ok(())
 }
 
 pub fn get_links_for_chain_id(conn: &Connection, chain_id: i32) -> Result<Vec<Link>> {
     let mut statement = conn.prepare(
-        "SELECT chain_id, date 
-            FROM links 
-            WHERE chain_id = ? 
-            ORDER BY date ASC;",
+        "SELECT id, chain_id, date, time, value, state, reason
+            FROM links
+            WHERE chain_id = ?
+            ORDER BY date ASC, time ASC, id ASC;",
     )?;
 
     let link_iter = statement.query_map(params![chain_id], |row| {
-        let chain_id: i32 = row.get(0)?;
-        let date_str: String = row.get::<usize, String>(1)?.to_string();
+        let date_str: String = row.get(2)?;
         let date = NaiveDate::parse_from_str(&date_str, FORMAT).unwrap();
+        let time_str: Option<String> = row.get(3)?;
+        let time = time_str.map(|time| NaiveTime::parse_from_str(&time, TIME_FORMAT).unwrap());
 
         Ok(Link {
-            chain_id: chain_id,
-            date: date,
+            id: row.get(0)?,
+            chain_id: row.get(1)?,
+            date,
+            time,
+            value: row.get(4)?,
+            state: State::parse(&row.get::<_, String>(5)?),
+            reason: row.get(6)?,
         })
     })?;
 
     Ok(link_iter.filter_map(Result::ok).collect())
 }
+
+pub fn has_link(conn: &Connection, link: &Link) -> Result<bool> {
+    let count: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM links WHERE chain_id=?1 AND date=?2;",
+        params![link.chain_id, link.date.format(FORMAT).to_string()],
+        |row| row.get(0),
+    )?;
+
+    Ok(count > 0)
+}
+
+pub fn add_note(conn: &Connection, note: &Note) -> Result<()> {
+    conn.execute(
+        "INSERT INTO notes (chain_id, date, text)
+                VALUES (?1, ?2, ?3);",
+        params![note.chain_id, note.date.format(FORMAT).to_string(), note.text],
+    )?;
+
+    Ok(())
+}
+
+fn note_from_row(row: &Row) -> rusqlite::Result<Note> {
+    let date_str: String = row.get(2)?;
+    let date = NaiveDate::parse_from_str(&date_str, FORMAT).unwrap();
+
+    Ok(Note {
+        id: row.get(0)?,
+        chain_id: row.get(1)?,
+        date,
+        text: row.get(3)?,
+    })
+}
+
+pub fn get_notes_for_chain_id(conn: &Connection, chain_id: i32) -> Result<Vec<Note>> {
+    let mut statement = conn.prepare(
+        "SELECT id, chain_id, date, text
+            FROM notes
+            WHERE chain_id = ?
+            ORDER BY date ASC, id ASC;",
+    )?;
+
+    let note_iter = statement.query_map(params![chain_id], note_from_row)?;
+
+    Ok(note_iter.filter_map(Result::ok).collect())
+}
+
+/// Find every note containing `text`, ignoring case.
+pub fn search_notes(conn: &Connection, text: &str) -> Result<Vec<Note>> {
+    let mut statement = conn.prepare(
+        "SELECT id, chain_id, date, text
+            FROM notes
+            WHERE text LIKE '%' || ?1 || '%'
+            ORDER BY date ASC, id ASC;",
+    )?;
+
+    let note_iter = statement.query_map(params![text], note_from_row)?;
+
+    Ok(note_iter.filter_map(Result::ok).collect())
+}
+
+pub fn add_tag(conn: &Connection, chain: &Chain, tag: &str) -> Result<()> {
+    conn.execute(
+        "INSERT OR IGNORE INTO tags (chain_id, tag)
+                VALUES (?1, ?2);",
+        params![chain.id, tag],
+    )?;
+
+    Ok(())
+}
+
+pub fn delete_tag(conn: &Connection, chain: &Chain, tag: &str) -> Result<()> {
+    conn.execute(
+        "DELETE FROM tags WHERE chain_id=?1 AND tag=?2;",
+        params![chain.id, tag],
+    )?;
+
+    Ok(())
+}
+
+/// Get every tag with the number of chains it is assigned to.
+pub fn get_tags(conn: &Connection) -> Result<Vec<(String, i64)>> {
+    let mut statement = conn.prepare(
+        "SELECT tag, COUNT(*)
+            FROM tags
+            GROUP BY tag
+            ORDER BY tag ASC;",
+    )?;
+    let tag_iter = statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+    Ok(tag_iter.filter_map(Result::ok).collect())
+}
+
+fn count(conn: &Connection, table: &str) -> Result<i64> {
+    Ok(conn.query_row(&format!("SELECT COUNT(*) FROM {};", table), params![], |row| row.get(0))?)
+}
+
+pub fn get_info(conn: &Connection, path: &Path) -> Result<DatabaseInfo> {
+    Ok(DatabaseInfo {
+        path: path.to_path_buf(),
+        size: fs::metadata(path).map(|metadata| metadata.len()).unwrap_or(0),
+        version: conn.query_row("PRAGMA user_version;", params![], |row| row.get(0))?,
+        chains: count(conn, "chains")?,
+        links: count(conn, "links")?,
+        notes: count(conn, "notes")?,
+        operations: count(conn, "operations")?,
+    })
+}
+
+/// The tables of a database, in the order they were created.
+fn tables(conn: &Connection) -> Result<Vec<String>> {
+    let mut statement =
+        conn.prepare("SELECT name FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%' ORDER BY rowid;")?;
+    let table_iter = statement.query_map([], |row| row.get(0))?;
+
+    Ok(table_iter.filter_map(Result::ok).collect())
+}
+
+/// Every row of every table with its table, the chains with their ids and
+/// uuids as well as the history and the operation log, see `load`.
+pub fn dump(conn: &Connection) -> Result<Vec<(String, Map<String, Value>)>> {
+    let mut rows: Vec<(String, Map<String, Value>)> = Vec::new();
+
+    for table in tables(conn)? {
+        let mut statement = conn.prepare(&format!("SELECT * FROM {} ORDER BY rowid;", table))?;
+        let columns: Vec<String> = statement.column_names().iter().map(|name| name.to_string()).collect();
+        let mut row_iter = statement.query([])?;
+
+        while let Some(row) = row_iter.next()? {
+            let mut values = Map::new();
+
+            for (index, column) in columns.iter().enumerate() {
+                let value = match row.get::<_, SqlValue>(index)? {
+                    SqlValue::Null => Value::Null,
+                    SqlValue::Integer(integer) => Value::from(integer),
+                    SqlValue::Real(real) => Number::from_f64(real).map_or(Value::Null, Value::Number),
+                    SqlValue::Text(text) => Value::String(text),
+                    SqlValue::Blob(_) => {
+                        return Err(ChainError::new(&format!("{}.{} holds binary data", table, column)).into())
+                    }
+                };
+
+                values.insert(column.clone(), value);
+            }
+
+            rows.push((table.clone(), values));
+        }
+    }
+
+    Ok(rows)
+}
+
+/// Replace every row of the database with `rows` from `dump`, keeping the
+/// ids, uuids, history and operation log they had.
+pub fn load(conn: &Connection, rows: &[(String, Map<String, Value>)]) -> Result<()> {
+    let tables = tables(conn)?;
+
+    // A new database already has an id of its own.
+    for table in tables.iter() {
+        conn.execute(&format!("DELETE FROM {};", table), params![])?;
+    }
+
+    let (pending, rows): (Vec<_>, Vec<_>) = rows.iter().partition(|(table, _)| table == "pending_ops");
+
+    for (table, values) in rows {
+        insert_row(conn, &tables, table, values)?;
+    }
+
+    // Inserting the rows queued them as changes, only the changes which
+    // were queued when they were dumped are.
+    conn.execute("DELETE FROM pending_ops;", params![])?;
+
+    for (table, values) in pending {
+        insert_row(conn, &tables, table, values)?;
+    }
+
+    Ok(())
+}
+
+fn insert_row(conn: &Connection, tables: &[String], table: &str, values: &Map<String, Value>) -> Result<()> {
+    if !tables.iter().any(|existing| existing == table) {
+        return Err(ChainError::new(&format!("Unknown table {}", table)).into());
+    }
+
+    let columns: Vec<String> = values.keys().map(|column| format!("\"{}\"", column)).collect();
+    let placeholders: Vec<String> = (1..=columns.len()).map(|index| format!("?{}", index)).collect();
+    let values = values.values().map(|value| match value {
+        Value::Number(number) => match number.as_i64() {
+            Some(integer) => SqlValue::Integer(integer),
+            None => SqlValue::Real(number.as_f64().unwrap_or_default()),
+        },
+        Value::String(text) => SqlValue::Text(text.clone()),
+        _ => SqlValue::Null,
+    });
+
+    conn.execute(
+        &format!(
+            "INSERT INTO {} ({}) VALUES ({});",
+            table,
+            columns.join(", "),
+            placeholders.join(", ")
+        ),
+        params_from_iter(values),
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn database(names: &[&str]) -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        setup_tables(&conn).unwrap();
+
+        for name in names.iter() {
+            add_chain(
+                &conn,
+                &Chain {
+                    name: name.to_string(),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        }
+
+        conn
+    }
+
+    fn resolve(conn: &Connection, name: &str) -> String {
+        match get_chain_for_name(conn, name) {
+            Ok(chain) => chain.name,
+            Err(err) => err.to_string(),
+        }
+    }
+
+    #[test]
+    fn names_are_resolved_in_order() {
+        let conn = database(&["read", "reading", "Run", "2"]);
+        let run = find_chain_for_name(&conn, "Run").unwrap().unwrap();
+        add_alias(&conn, &run, "jog").unwrap();
+
+        assert_eq!(resolve(&conn, "read"), "read");
+        assert_eq!(resolve(&conn, "jog"), "Run");
+        // Names win over ids.
+        assert_eq!(resolve(&conn, "2"), "2");
+        assert_eq!(resolve(&conn, "3"), "Run");
+        assert_eq!(resolve(&conn, "run"), "Run");
+        assert_eq!(resolve(&conn, "READI"), "reading");
+    }
+
+    #[test]
+    fn ambiguous_and_unknown_names_are_explained() {
+        let conn = database(&["read", "reading", "run"]);
+
+        assert_eq!(
+            resolve(&conn, "rea"),
+            "\"rea\" is ambiguous, it matches \"read\", \"reading\""
+        );
+        assert_eq!(resolve(&conn, "raed"), "No chain named \"raed\", did you mean \"read\"?");
+        assert_eq!(resolve(&conn, "swim"), "No chain named \"swim\"");
+    }
+
+    #[test]
+    fn removed_chains_take_their_notes_along() {
+        let conn = database(&["read", "run"]);
+
+        for (chain_id, text) in [(1, "hello"), (2, "hello again")] {
+            add_note(
+                &conn,
+                &Note {
+                    id: 0,
+                    chain_id,
+                    date: NaiveDate::from_ymd_opt(2024, 1, 1).unwrap(),
+                    text: text.to_string(),
+                },
+            )
+            .unwrap();
+        }
+
+        delete_chain_for_name(&conn, "read").unwrap();
+
+        let notes = search_notes(&conn, "hello").unwrap();
+        assert_eq!(notes.len(), 1);
+        assert_eq!(notes[0].chain_id, 2);
+    }
+}
@@ -0,0 +1,615 @@
+use super::backup::{Backup, BackupChain, BackupLink, BackupNote, VERSION};
+use super::chain_error::ChainError;
+use super::structs::{Kind, State};
+use anyhow::Result;
+use chrono::{DateTime, Local, NaiveDate, TimeZone, Utc};
+use rusqlite::{Connection, OpenFlags};
+use serde_json::Value;
+use std::io::Read;
+use std::path::Path;
+
+// The values Loop Habit Tracker stores for a day. Days are filled in
+// automatically when a habit which isn't daily was done often enough.
+const LOOP_YES_AUTO: i64 = 1;
+const LOOP_YES_MANUAL: i64 = 2;
+const LOOP_SKIP: i64 = 3;
+// Loop stores numerical values multiplied by 1000.
+const LOOP_SCALE: f64 = 1000.0;
+const LOOP_NUMERICAL: i64 = 1;
+const LOOP_AT_MOST: i64 = 1;
+
+/// A habit as Loop Habit Tracker defines it, read from either of its
+/// backups.
+struct LoopHabit {
+    name: String,
+    description: Option<String>,
+    frequency: (i64, i64),
+    numerical: bool,
+    at_most: bool,
+    target: Option<f64>,
+    unit: Option<String>,
+    archived: bool,
+}
+
+fn new_chain(name: &str) -> BackupChain {
+    BackupChain {
+        name: name.to_string(),
+        ..Default::default()
+    }
+}
+
+fn non_empty(text: Option<String>) -> Option<String> {
+    text.map(|text| text.trim().to_string()).filter(|text| !text.is_empty())
+}
+
+fn done(date: NaiveDate, value: Option<f64>) -> BackupLink {
+    BackupLink {
+        date,
+        time: None,
+        value,
+        state: State::Done.as_str().to_string(),
+        reason: None,
+    }
+}
+
+/// Describe a frequency the chains can't enforce, chains are done daily.
+pub fn frequency_goal(times: i64, days: i64) -> Option<String> {
+    match (times, days) {
+        (1, 1) => None,
+        (1, 7) => Some("once a week".to_string()),
+        (times, 7) => Some(format!("{} times a week", times)),
+        (1, days) => Some(format!("once every {} days", days)),
+        (times, days) => Some(format!("{} times every {} days", times, days)),
+    }
+}
+
+impl LoopHabit {
+    fn to_chain(&self) -> BackupChain {
+        let mut chain = new_chain(&self.name);
+
+        chain.description = self.description.clone();
+        chain.archived = self.archived;
+        chain.unit = self.unit.clone();
+
+        let (times, days) = self.frequency;
+
+        // Loop's numerical targets apply to the whole interval, only daily
+        // minimums map to the target of a chain.
+        if self.numerical {
+            let target = self.target.unwrap_or(0.0);
+            let unit = self.unit.clone().unwrap_or_default();
+            let bound = if self.at_most { "at most" } else { "at least" };
+
+            if days == 1 && !self.at_most {
+                chain.target = self.target;
+            } else if days == 1 {
+                chain.goal = Some(format!("{} {} {} a day", bound, target, unit));
+            } else {
+                chain.goal = Some(format!("{} {} {} every {} days", bound, target, unit, days));
+            }
+        } else {
+            chain.goal = frequency_goal(times, days);
+        }
+
+        chain
+    }
+
+    /// The link for a day, `None` when the habit wasn't done.
+    fn to_link(&self, date: NaiveDate, value: i64) -> Option<BackupLink> {
+        if self.numerical {
+            return match value {
+                value if value > 0 => Some(done(date, Some(value as f64 / LOOP_SCALE))),
+                _ => None,
+            };
+        }
+
+        match value {
+            LOOP_YES_AUTO | LOOP_YES_MANUAL => Some(done(date, None)),
+            LOOP_SKIP => Some(BackupLink {
+                state: State::Skipped.as_str().to_string(),
+                ..done(date, None)
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Read a Loop Habit Tracker backup, either the SQLite database or the
+/// directory of an unzipped CSV export.
+pub fn read_loop(path: &Path) -> Result<Backup> {
+    let chains = if path.is_dir() {
+        read_loop_csv(path)?
+    } else {
+        read_loop_database(path)?
+    };
+
+    Ok(Backup {
+        version: VERSION,
+        chains,
+    })
+}
+
+fn read_loop_database(path: &Path) -> Result<Vec<BackupChain>> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+
+    let mut statement = conn.prepare(
+        "SELECT id, name, description, freq_num, freq_den, type, target_type, target_value, unit, archived
+            FROM Habits
+            ORDER BY position ASC;",
+    )?;
+    let habit_iter = statement.query_map([], |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            LoopHabit {
+                name: row.get(1)?,
+                description: non_empty(row.get(2)?),
+                frequency: (row.get(3)?, row.get(4)?),
+                numerical: row.get::<_, Option<i64>>(5)? == Some(LOOP_NUMERICAL),
+                at_most: row.get::<_, Option<i64>>(6)? == Some(LOOP_AT_MOST),
+                target: row.get(7)?,
+                unit: non_empty(row.get(8)?),
+                archived: row.get::<_, Option<i64>>(9)?.unwrap_or(0) != 0,
+            },
+        ))
+    })?;
+    let habits: Vec<(i64, LoopHabit)> = habit_iter.collect::<rusqlite::Result<_>>()?;
+
+    let mut chains: Vec<BackupChain> = Vec::new();
+
+    for (id, habit) in habits.iter() {
+        let mut chain = habit.to_chain();
+
+        let mut statement = conn.prepare(
+            "SELECT timestamp, value
+                FROM Repetitions
+                WHERE habit = ?1
+                ORDER BY timestamp ASC;",
+        )?;
+        let repetition_iter = statement.query_map([id], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?)))?;
+
+        for repetition in repetition_iter {
+            let (timestamp, value) = repetition?;
+
+            // Loop stores days as midnight UTC.
+            let date = match Utc.timestamp_millis_opt(timestamp).single() {
+                Some(time) => time.date_naive(),
+                None => continue,
+            };
+
+            chain.links.extend(habit.to_link(date, value));
+        }
+
+        chains.push(chain);
+    }
+
+    Ok(chains)
+}
+
+fn read_loop_csv(dir: &Path) -> Result<Vec<BackupChain>> {
+    let mut habits: Vec<LoopHabit> = Vec::new();
+
+    let mut reader = csv::Reader::from_path(dir.join("Habits.csv"))?;
+    let header = reader.headers()?.clone();
+    let column = |names: &[&str]| header.iter().position(|column| names.contains(&column.trim()));
+
+    let name = column(&["Name"]).ok_or_else(|| ChainError::new("Habits.csv has no Name column"))?;
+    let description = column(&["Description", "Question"]);
+    let times = column(&["FrequencyNumerator", "NumRepetitions"]);
+    let days = column(&["FrequencyDenominator", "Interval"]);
+    let kind = column(&["Type"]);
+    let target_type = column(&["Target Type"]);
+    let target = column(&["Target Value"]);
+    let unit = column(&["Unit"]);
+    let archived = column(&["Archived?"]);
+
+    for record in reader.records() {
+        let record = record?;
+        let cell = |index: Option<usize>| index.and_then(|index| record.get(index)).map(|value| value.trim().to_string());
+        let number = |index: Option<usize>| cell(index).and_then(|value| value.parse::<i64>().ok());
+
+        habits.push(LoopHabit {
+            name: cell(Some(name)).unwrap_or_default(),
+            description: non_empty(cell(description)),
+            frequency: (number(times).unwrap_or(1), number(days).unwrap_or(1)),
+            numerical: cell(kind).is_some_and(|kind| kind.eq_ignore_ascii_case("numerical"))
+                || number(kind) == Some(LOOP_NUMERICAL),
+            at_most: cell(target_type).is_some_and(|kind| kind.eq_ignore_ascii_case("at most"))
+                || number(target_type) == Some(LOOP_AT_MOST),
+            target: cell(target).and_then(|value| value.parse().ok()),
+            unit: non_empty(cell(unit)),
+            archived: cell(archived).is_some_and(|value| value.eq_ignore_ascii_case("true") || value == "1"),
+        });
+    }
+
+    let mut chains: Vec<BackupChain> = habits.iter().map(|habit| habit.to_chain()).collect();
+
+    // Checkmarks.csv has a row per day with a column per habit.
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .from_path(dir.join("Checkmarks.csv"))?;
+    let header = reader.headers()?.clone();
+
+    for record in reader.records() {
+        let record = record?;
+
+        let date = match record.get(0).and_then(|date| NaiveDate::parse_from_str(date.trim(), "%Y-%m-%d").ok()) {
+            Some(date) => date,
+            None => continue,
+        };
+
+        for (index, value) in record.iter().enumerate().skip(1) {
+            let name = header.get(index).unwrap_or_default().trim();
+            let value = match value.trim().parse::<i64>() {
+                Ok(value) => value,
+                Err(_) => continue,
+            };
+
+            if let Some(position) = habits.iter().position(|habit| habit.name == name) {
+                chains[position].links.extend(habits[position].to_link(date, value));
+            }
+        }
+    }
+
+    Ok(chains)
+}
+
+/// Habitica exports dates as milliseconds or as ISO 8601 text.
+fn habitica_date(value: &Value) -> Option<NaiveDate> {
+    match value {
+        Value::Number(millis) => Local
+            .timestamp_millis_opt(millis.as_f64()? as i64)
+            .single()
+            .map(|time| time.date_naive()),
+        Value::String(text) => DateTime::parse_from_rfc3339(text)
+            .ok()
+            .map(|time| time.with_timezone(&Local).date_naive()),
+        _ => None,
+    }
+}
+
+fn habitica_text(task: &Value, key: &str) -> Option<String> {
+    non_empty(task.get(key).and_then(|text| text.as_str()).map(|text| text.to_string()))
+}
+
+fn habitica_history(task: &Value) -> Vec<&Value> {
+    task.get("history")
+        .and_then(|history| history.as_array())
+        .map(|history| history.iter().collect())
+        .unwrap_or_default()
+}
+
+/// Read the user data export of Habitica. Dailies become chains with a
+/// link for every completed day, habits become chains with a link for
+/// every day they were scored, negative habits become negative chains.
+pub fn read_habitica(json: &str) -> Result<Backup> {
+    let data: Value = serde_json::from_str(json)?;
+    let tasks = data
+        .get("tasks")
+        .ok_or_else(|| ChainError::new("The file is not a Habitica user data export"))?;
+
+    let mut chains: Vec<BackupChain> = Vec::new();
+
+    let empty = Vec::new();
+    let dailies = tasks.get("dailys").and_then(|tasks| tasks.as_array()).unwrap_or(&empty);
+    let habits = tasks.get("habits").and_then(|tasks| tasks.as_array()).unwrap_or(&empty);
+
+    for daily in dailies.iter() {
+        let mut chain = new_chain(&habitica_text(daily, "text").unwrap_or_default());
+        chain.description = habitica_text(daily, "notes");
+
+        let every = daily.get("everyX").and_then(|every| every.as_i64()).unwrap_or(1);
+        chain.goal = match daily.get("frequency").and_then(|frequency| frequency.as_str()) {
+            Some("weekly") => {
+                let days: Vec<&str> = ["m", "t", "w", "th", "f", "s", "su"]
+                    .iter()
+                    .zip(["mon", "tue", "wed", "thu", "fri", "sat", "sun"].iter())
+                    .filter(|(key, _)| daily["repeat"][**key].as_bool().unwrap_or(true))
+                    .map(|(_, day)| *day)
+                    .collect();
+
+                match days.len() {
+                    7 => None,
+                    _ => Some(format!("on {}", days.join(", "))),
+                }
+            }
+            Some("monthly") => Some(format!("every {} month(s)", every)),
+            Some("yearly") => Some(format!("every {} year(s)", every)),
+            _ => frequency_goal(1, every),
+        };
+
+        for entry in habitica_history(daily) {
+            // Older exports only have the task value, which rises when the
+            // daily was completed.
+            let completed = entry.get("completed").and_then(|completed| completed.as_bool());
+
+            if completed == Some(true) {
+                if let Some(date) = habitica_date(&entry["date"]) {
+                    chain.links.push(done(date, None));
+                }
+            }
+        }
+
+        chains.push(chain);
+    }
+
+    for habit in habits.iter() {
+        let mut chain = new_chain(&habitica_text(habit, "text").unwrap_or_default());
+        chain.description = habitica_text(habit, "notes");
+
+        let up = habit.get("up").and_then(|up| up.as_bool()).unwrap_or(true);
+        let down = habit.get("down").and_then(|down| down.as_bool()).unwrap_or(false);
+
+        // Habits which can only be scored down track something to avoid.
+        let negative = down && !up;
+        if negative {
+            chain.kind = Kind::Negative.as_str().to_string();
+        }
+
+        for entry in habitica_history(habit) {
+            let date = match habitica_date(&entry["date"]) {
+                Some(date) => date,
+                None => continue,
+            };
+            let key = if negative { "scoredDown" } else { "scoredUp" };
+            let count = entry.get(key).and_then(|count| count.as_i64()).unwrap_or(0);
+
+            if count > 0 && !chain.links.iter().any(|link| link.date == date) {
+                chain.links.push(done(date, None));
+            }
+        }
+
+        chains.push(chain);
+    }
+
+    chains.retain(|chain| !chain.name.is_empty());
+
+    Ok(Backup {
+        version: VERSION,
+        chains,
+    })
+}
+
+/// Read the CSV export of HabitBull, which has a row per habit and day
+/// with the value of the day and a comment.
+pub fn read_habitbull(input: &mut dyn Read) -> Result<Backup> {
+    let mut reader = csv::Reader::from_reader(input);
+    let header = reader.headers()?.clone();
+    let column = |name: &str| header.iter().position(|column| column.trim() == name);
+
+    let (name, date, value) = match (column("HabitName"), column("CalendarDate"), column("Value")) {
+        (Some(name), Some(date), Some(value)) => (name, date, value),
+        _ => return Err(ChainError::new("The file is not a HabitBull export").into()),
+    };
+    let description = column("HabitDescription");
+    let category = column("HabitCategory");
+    let comment = column("CommentText");
+
+    let mut chains: Vec<BackupChain> = Vec::new();
+
+    for record in reader.records() {
+        let record = record?;
+        let cell = |index: Option<usize>| {
+            non_empty(index.and_then(|index| record.get(index)).map(|value| value.to_string()))
+        };
+
+        let habit = match cell(Some(name)) {
+            Some(habit) => habit,
+            None => continue,
+        };
+        // Dates may carry a time, only the day matters.
+        let day = match cell(Some(date)).and_then(|date| NaiveDate::parse_from_str(date.get(..10)?, "%Y-%m-%d").ok()) {
+            Some(day) => day,
+            None => continue,
+        };
+
+        let position = match chains.iter().position(|chain| chain.name == habit) {
+            Some(position) => position,
+            None => {
+                let mut chain = new_chain(&habit);
+                chain.description = cell(description);
+                chain.tags = cell(category).into_iter().collect();
+                chains.push(chain);
+
+                chains.len() - 1
+            }
+        };
+        let chain = &mut chains[position];
+
+        if let Some(text) = cell(comment) {
+            chain.notes.push(BackupNote { date: day, text });
+        }
+
+        match cell(Some(value)).and_then(|value| value.parse::<f64>().ok()) {
+            Some(value) if value > 0.0 => chain.links.push(done(day, Some(value))),
+            _ => (),
+        }
+    }
+
+    // Boolean habits have a value of 1 on every day they were done, numeric
+    // habits keep their values even on days the value was 1.
+    for chain in chains.iter_mut() {
+        if chain.links.iter().all(|link| link.value == Some(1.0)) {
+            for link in chain.links.iter_mut() {
+                link.value = None;
+            }
+        }
+    }
+
+    Ok(Backup {
+        version: VERSION,
+        chains,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn date(text: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(text, "%Y-%m-%d").unwrap()
+    }
+
+    fn chain<'a>(backup: &'a Backup, name: &str) -> &'a BackupChain {
+        backup.chains.iter().find(|chain| chain.name == name).unwrap()
+    }
+
+    fn links(chain: &BackupChain) -> Vec<(NaiveDate, Option<f64>, &str)> {
+        chain
+            .links
+            .iter()
+            .map(|link| (link.date, link.value, link.state.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn loop_csv_exports_are_read() {
+        let dir = std::env::temp_dir().join(format!("c-loop-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        fs::write(
+            dir.join("Habits.csv"),
+            "Position,Name,Question,Description,NumRepetitions,Interval,Color\n\
+             001,Gym,Did you go?,,3,7,#000000\n\
+             002,Read,,Books,1,1,#000000\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("Checkmarks.csv"),
+            "Date,Gym,Read,\n\
+             2024-01-03,1,0,\n\
+             2024-01-02,2,3,\n\
+             2024-01-01,0,2,\n",
+        )
+        .unwrap();
+
+        let backup = read_loop(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let gym = chain(&backup, "Gym");
+        assert_eq!(gym.goal.as_deref(), Some("3 times a week"));
+        assert_eq!(gym.description.as_deref(), Some("Did you go?"));
+        // Days Loop filled in count as done.
+        assert_eq!(
+            links(gym),
+            vec![(date("2024-01-03"), None, "done"), (date("2024-01-02"), None, "done")]
+        );
+
+        let read = chain(&backup, "Read");
+        assert_eq!(read.goal, None);
+        assert_eq!(
+            links(read),
+            vec![(date("2024-01-02"), None, "skipped"), (date("2024-01-01"), None, "done")]
+        );
+    }
+
+    #[test]
+    fn loop_databases_are_read() {
+        let path = std::env::temp_dir().join(format!("c-loop-{}.db", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE Habits (id INTEGER PRIMARY KEY, name TEXT, description TEXT, freq_num INTEGER,
+                freq_den INTEGER, type INTEGER, target_type INTEGER, target_value REAL, unit TEXT,
+                archived INTEGER, position INTEGER);
+            CREATE TABLE Repetitions (habit INTEGER, timestamp INTEGER, value INTEGER);
+            INSERT INTO Habits VALUES (1, 'Run', '', 1, 1, 1, 0, 5.0, 'km', 0, 0);
+            INSERT INTO Habits VALUES (2, 'Floss', '', 1, 1, 0, 0, 0, '', 1, 1);
+            INSERT INTO Repetitions VALUES (1, 1704067200000, 5500);
+            INSERT INTO Repetitions VALUES (1, 1704153600000, 0);
+            INSERT INTO Repetitions VALUES (2, 1704067200000, 1);
+            INSERT INTO Repetitions VALUES (2, 1704153600000, 2);",
+        )
+        .unwrap();
+        drop(conn);
+
+        let backup = read_loop(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let run = chain(&backup, "Run");
+        assert_eq!(run.target, Some(5.0));
+        assert_eq!(run.unit.as_deref(), Some("km"));
+        assert_eq!(links(run), vec![(date("2024-01-01"), Some(5.5), "done")]);
+
+        let floss = chain(&backup, "Floss");
+        assert!(floss.archived);
+        assert_eq!(
+            links(floss),
+            vec![(date("2024-01-01"), None, "done"), (date("2024-01-02"), None, "done")]
+        );
+    }
+
+    #[test]
+    fn habitbull_values_are_kept_for_numeric_habits() {
+        let csv = "HabitName,HabitDescription,HabitCategory,CalendarDate,Value,CommentText\n\
+                   Floss,,Health,2024-01-01T00:00:00,1,\n\
+                   Floss,,Health,2024-01-02T00:00:00,0,Forgot\n\
+                   Water,Glasses,,2024-01-01,1,\n\
+                   Water,Glasses,,2024-01-02,3,\n";
+
+        let backup = read_habitbull(&mut csv.as_bytes()).unwrap();
+
+        let floss = chain(&backup, "Floss");
+        assert_eq!(floss.tags, vec!["Health".to_string()]);
+        assert_eq!(links(floss), vec![(date("2024-01-01"), None, "done")]);
+        assert_eq!(floss.notes[0].text, "Forgot");
+
+        let water = chain(&backup, "Water");
+        assert_eq!(water.description.as_deref(), Some("Glasses"));
+        assert_eq!(
+            links(water),
+            vec![(date("2024-01-01"), Some(1.0), "done"), (date("2024-01-02"), Some(3.0), "done")]
+        );
+    }
+
+    #[test]
+    fn other_files_are_not_habitbull_exports() {
+        let err = read_habitbull(&mut "Date,Chain\n".as_bytes()).unwrap_err();
+
+        assert_eq!(err.to_string(), "The file is not a HabitBull export");
+    }
+
+    #[test]
+    fn habitica_dailies_and_habits_become_chains() {
+        let json = r#"{
+            "tasks": {
+                "dailys": [{
+                    "text": "Stretch",
+                    "frequency": "weekly",
+                    "repeat": {"m": true, "t": false, "w": true, "th": false, "f": true, "s": false, "su": false},
+                    "history": [
+                        {"date": "2024-01-01T12:00:00Z", "completed": true},
+                        {"date": "2024-01-02T12:00:00Z", "completed": false}
+                    ]
+                }],
+                "habits": [
+                    {"text": "Walk", "up": true, "down": false, "history": [
+                        {"date": 1704110400000, "scoredUp": 2, "scoredDown": 0}
+                    ]},
+                    {"text": "Snack", "up": false, "down": true, "history": [
+                        {"date": 1704110400000, "scoredUp": 0, "scoredDown": 1},
+                        {"date": 1704196800000, "scoredUp": 0, "scoredDown": 0}
+                    ]},
+                    {"text": "", "history": []}
+                ]
+            }
+        }"#;
+
+        let backup = read_habitica(json).unwrap();
+        assert_eq!(backup.chains.len(), 3);
+
+        let stretch = chain(&backup, "Stretch");
+        assert_eq!(stretch.goal.as_deref(), Some("on mon, wed, fri"));
+        assert_eq!(stretch.links.len(), 1);
+
+        assert_eq!(chain(&backup, "Walk").links.len(), 1);
+
+        let snack = chain(&backup, "Snack");
+        assert_eq!(snack.kind, Kind::Negative.as_str());
+        assert_eq!(snack.links.len(), 1);
+
+        assert!(read_habitica("{}").is_err());
+    }
+}
@@ -0,0 +1,283 @@
+use super::Operation;
+use anyhow::Result;
+use chrono::{Local, NaiveDateTime};
+use rusqlite::{params, Connection};
+
+const DATETIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+// Tables whose changes are recorded so they can be undone.
+const TRACKED_TABLES: &[&str] = &["chains", "links", "notes", "tags", "components", "aliases"];
+
+// Operations move from recording to applied when their command finishes.
+// Undoing an operation marks it as undone, and recording a new operation
+// abandons every undone operation, which can then no longer be redone.
+const RECORDING: &str = "recording";
+const APPLIED: &str = "applied";
+const UNDONE: &str = "undone";
+const ABANDONED: &str = "abandoned";
+
+pub fn setup_tables(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS operations (
+                    id              INTEGER PRIMARY KEY,
+                    time            TEXT NOT NULL,
+                    user            TEXT NOT NULL,
+                    command         TEXT NOT NULL,
+                    state           TEXT NOT NULL
+                );
+        CREATE TABLE IF NOT EXISTS changes (
+                    id              INTEGER PRIMARY KEY,
+                    operation_id    INTEGER NOT NULL,
+                    undo            TEXT NOT NULL,
+                    redo            TEXT NOT NULL,
+                    FOREIGN KEY (operation_id) REFERENCES operations(id)
+                );",
+    )?;
+
+    for table in TRACKED_TABLES.iter() {
+        setup_triggers(conn, table)?;
+    }
+
+    Ok(())
+}
+
+/// Create the triggers which record the SQL needed to undo and redo every
+/// change to `table` while an operation is recording.
+///
+/// The triggers are recreated every time so they pick up columns added by
+/// later migrations.
+fn setup_triggers(conn: &Connection, table: &str) -> Result<()> {
+    let mut statement = conn.prepare(&format!("PRAGMA table_info({});", table))?;
+    let columns: Vec<String> = statement
+        .query_map([], |row| row.get(1))?
+        .filter_map(Result::ok)
+        .collect();
+
+    let values = |row: &str| {
+        columns
+            .iter()
+            .map(|column| format!("quote({}.{})", row, column))
+            .collect::<Vec<String>>()
+            .join(" || ', ' || ")
+    };
+    let assignments = |row: &str| {
+        columns
+            .iter()
+            .map(|column| format!("'{} = ' || quote({}.{})", column, row, column))
+            .collect::<Vec<String>>()
+            .join(" || ', ' || ")
+    };
+    let insert = |row: &str| {
+        format!(
+            "'INSERT INTO {} ({}) VALUES (' || {} || ');'",
+            table,
+            columns.join(", "),
+            values(row)
+        )
+    };
+    let delete = |row: &str| format!("'DELETE FROM {} WHERE rowid = ' || {}.rowid || ';'", table, row);
+    let update = |row: &str| {
+        format!(
+            "'UPDATE {} SET ' || {} || ' WHERE rowid = ' || {}.rowid || ';'",
+            table,
+            assignments(row),
+            row
+        )
+    };
+
+    let record = |event: &str, undo: String, redo: String| {
+        format!(
+            "DROP TRIGGER IF EXISTS {table}_{event}_history;
+            CREATE TRIGGER {table}_{event}_history AFTER {event} ON {table}
+            WHEN EXISTS (SELECT 1 FROM operations WHERE state = '{recording}')
+            BEGIN
+                INSERT INTO changes (operation_id, undo, redo)
+                VALUES (
+                    (SELECT id FROM operations WHERE state = '{recording}'),
+                    {undo},
+                    {redo}
+                );
+            END;",
+            table = table,
+            event = event,
+            recording = RECORDING,
+            undo = undo,
+            redo = redo
+        )
+    };
+
+    conn.execute_batch(&record("INSERT", delete("NEW"), insert("NEW")))?;
+    conn.execute_batch(&record("UPDATE", update("OLD"), update("NEW")))?;
+    conn.execute_batch(&record("DELETE", insert("OLD"), delete("OLD")))?;
+
+    Ok(())
+}
+
+fn user() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+fn add_operation(conn: &Connection, command: &str, state: &str) -> Result<()> {
+    conn.execute(
+        "INSERT INTO operations (time, user, command, state)
+                VALUES (?1, ?2, ?3, ?4);",
+        params![
+            Local::now().format(DATETIME_FORMAT).to_string(),
+            user(),
+            command,
+            state
+        ],
+    )?;
+
+    Ok(())
+}
+
+/// Start recording the changes made by `command`.
+pub fn begin_operation(conn: &Connection, command: &str) -> Result<()> {
+    add_operation(conn, command, RECORDING)
+}
+
+/// Stop recording changes. Operations which did not change anything are
+/// dropped from the log.
+pub fn end_operation(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "DELETE FROM operations
+            WHERE state = ?1
+            AND NOT EXISTS (SELECT 1 FROM changes WHERE operation_id = operations.id);",
+        params![RECORDING],
+    )?;
+
+    let recorded = conn.execute(
+        "UPDATE operations SET state = ?1 WHERE state = ?2;",
+        params![APPLIED, RECORDING],
+    )?;
+
+    if recorded > 0 {
+        conn.execute(
+            "UPDATE operations SET state = ?1 WHERE state = ?2;",
+            params![ABANDONED, UNDONE],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Get the changes recorded by the operation which is recording, as the SQL
+/// statements which would redo them.
+pub fn get_recorded_changes(conn: &Connection) -> Result<Vec<String>> {
+    let mut statement = conn.prepare(
+        "SELECT redo FROM changes
+            WHERE operation_id IN (SELECT id FROM operations WHERE state = ?1)
+            ORDER BY id ASC;",
+    )?;
+    let change_iter = statement.query_map(params![RECORDING], |row| row.get(0))?;
+
+    Ok(change_iter.filter_map(Result::ok).collect())
+}
+
+fn get_changes(conn: &Connection, operation_id: i64, column: &str) -> Result<Vec<String>> {
+    let mut statement = conn.prepare(&format!(
+        "SELECT {} FROM changes WHERE operation_id = ?1 ORDER BY id ASC;",
+        column
+    ))?;
+    let change_iter = statement.query_map(params![operation_id], |row| row.get(0))?;
+
+    Ok(change_iter.filter_map(Result::ok).collect())
+}
+
+fn get_operation_ids(conn: &Connection, state: &str, order: &str, count: usize) -> Result<Vec<i64>> {
+    let mut statement = conn.prepare(&format!(
+        "SELECT id FROM operations
+            WHERE state = ?1
+            AND EXISTS (SELECT 1 FROM changes WHERE operation_id = operations.id)
+            ORDER BY id {}
+            LIMIT ?2;",
+        order
+    ))?;
+    let id_iter = statement.query_map(params![state, count as i64], |row| row.get(0))?;
+
+    Ok(id_iter.filter_map(Result::ok).collect())
+}
+
+/// Undo the last `count` applied operations, returning the undone
+/// operations.
+pub fn undo(conn: &Connection, count: usize) -> Result<Vec<Operation>> {
+    let mut operations: Vec<Operation> = Vec::new();
+
+    for id in get_operation_ids(conn, APPLIED, "DESC", count)? {
+        for change in get_changes(conn, id, "undo")?.iter().rev() {
+            conn.execute_batch(change)?;
+        }
+
+        conn.execute(
+            "UPDATE operations SET state = ?1 WHERE id = ?2;",
+            params![UNDONE, id],
+        )?;
+
+        operations.push(get_operation(conn, id)?);
+    }
+
+    if !operations.is_empty() {
+        add_operation(conn, &format!("undo {}", operations.len()), APPLIED)?;
+    }
+
+    Ok(operations)
+}
+
+/// Redo the last `count` undone operations, returning the redone operations.
+pub fn redo(conn: &Connection, count: usize) -> Result<Vec<Operation>> {
+    let mut operations: Vec<Operation> = Vec::new();
+
+    // The most recently undone operation has the lowest id.
+    for id in get_operation_ids(conn, UNDONE, "ASC", count)? {
+        for change in get_changes(conn, id, "redo")?.iter() {
+            conn.execute_batch(change)?;
+        }
+
+        conn.execute(
+            "UPDATE operations SET state = ?1 WHERE id = ?2;",
+            params![APPLIED, id],
+        )?;
+
+        operations.push(get_operation(conn, id)?);
+    }
+
+    if !operations.is_empty() {
+        add_operation(conn, &format!("redo {}", operations.len()), APPLIED)?;
+    }
+
+    Ok(operations)
+}
+
+fn operation_from_row(row: &rusqlite::Row) -> rusqlite::Result<Operation> {
+    let time_str: String = row.get(1)?;
+
+    Ok(Operation {
+        id: row.get(0)?,
+        time: NaiveDateTime::parse_from_str(&time_str, DATETIME_FORMAT).unwrap(),
+        user: row.get(2)?,
+        command: row.get(3)?,
+        state: row.get(4)?,
+    })
+}
+
+fn get_operation(conn: &Connection, id: i64) -> Result<Operation> {
+    Ok(conn.query_row(
+        "SELECT id, time, user, command, state FROM operations WHERE id = ?1;",
+        params![id],
+        operation_from_row,
+    )?)
+}
+
+pub fn get_operations(conn: &Connection) -> Result<Vec<Operation>> {
+    let mut statement = conn.prepare(
+        "SELECT id, time, user, command, state
+            FROM operations
+            ORDER BY id ASC;",
+    )?;
+    let operation_iter = statement.query_map([], operation_from_row)?;
+
+    Ok(operation_iter.filter_map(Result::ok).collect())
+}
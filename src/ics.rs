@@ -0,0 +1,299 @@
+use super::backup::{self, Backup, BackupChain};
+use super::logic;
+use super::printer;
+use super::structs::{Kind, Link, State};
+use chrono::{Duration, NaiveDate, Utc};
+
+use super::NAME;
+
+/// Lines longer than this many bytes are folded.
+const LINE_LENGTH: usize = 75;
+
+/// Escape text for a property value.
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+/// Fold a content line, continuation lines start with a space.
+fn fold(line: &str) -> String {
+    let mut folded = String::new();
+    let mut length = 0;
+
+    for c in line.chars() {
+        if length + c.len_utf8() > LINE_LENGTH {
+            folded.push_str("\r\n ");
+            length = 1;
+        }
+
+        folded.push(c);
+        length += c.len_utf8();
+    }
+
+    folded + "\r\n"
+}
+
+fn date(date: NaiveDate) -> String {
+    date.format("%Y%m%d").to_string()
+}
+
+/// A stable id for an event, so calendars update events instead of
+/// duplicating them when the feed is refreshed.
+///
+/// Every byte of the name other than a letter or digit is percent-encoded,
+/// so chains like "a b" and "a-b" get different ids.
+fn uid(chain: &str, date: NaiveDate, kind: &str) -> String {
+    let chain: String = chain
+        .bytes()
+        .map(|byte| match byte {
+            byte if byte.is_ascii_alphanumeric() => (byte as char).to_string(),
+            byte => format!("%{:02X}", byte),
+        })
+        .collect();
+
+    format!("{}-{}-{}@{}", kind, date.format("%Y%m%d"), chain, NAME)
+}
+
+/// The days each chain was done on with the total value of the day. Days
+/// short of the target or quota of the chain aren't done, and negative
+/// chains have no done days, their links are relapses.
+fn done_days(chain: &BackupChain) -> Vec<(NaiveDate, Option<f64>)> {
+    let settings = backup::to_chain(chain);
+
+    if settings.kind == Kind::Negative {
+        return Vec::new();
+    }
+
+    let mut links: Vec<Link> = chain
+        .links
+        .iter()
+        .map(|link| Link {
+            id: 0,
+            chain_id: 0,
+            date: link.date,
+            time: link.time,
+            value: link.value,
+            state: State::parse(&link.state),
+            reason: None,
+        })
+        .collect();
+    links.sort_by_key(|link| link.date);
+
+    logic::daily_totals(&links)
+        .iter()
+        .filter(|total| logic::day_state(&settings, total) == State::Done)
+        .map(|total| (total.date, total.value))
+        .collect()
+}
+
+/// Merge consecutive days into runs of their first and last day.
+fn runs(days: &[(NaiveDate, Option<f64>)]) -> Vec<(NaiveDate, NaiveDate)> {
+    let mut runs: Vec<(NaiveDate, NaiveDate)> = Vec::new();
+
+    for (date, _) in days.iter() {
+        match runs.last_mut() {
+            Some((_, end)) if *end + Duration::days(1) == *date => *end = *date,
+            _ => runs.push((*date, *date)),
+        }
+    }
+
+    runs
+}
+
+fn event(lines: &mut Vec<String>, uid: String, start: NaiveDate, end: NaiveDate, summary: String) {
+    lines.push("BEGIN:VEVENT".to_string());
+    lines.push(format!("UID:{}", uid));
+    lines.push(format!("DTSTAMP:{}", Utc::now().format("%Y%m%dT%H%M%SZ")));
+    lines.push(format!("DTSTART;VALUE=DATE:{}", date(start)));
+    // The end of all-day events is exclusive.
+    lines.push(format!("DTEND;VALUE=DATE:{}", date(end + Duration::days(1))));
+    lines.push(format!("SUMMARY:{}", escape(&summary)));
+    lines.push("TRANSP:TRANSPARENT".to_string());
+    lines.push("END:VEVENT".to_string());
+}
+
+/// Write an iCalendar file with an all-day event for every day a chain was
+/// done, or with `by_run` for every run of consecutive days, and a to-do
+/// for each chain in `due`.
+pub fn write(backup: &Backup, due: &[String], by_run: bool, today: NaiveDate) -> String {
+    let mut lines: Vec<String> = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        format!("PRODID:-//{}//chains//EN", NAME),
+        "CALSCALE:GREGORIAN".to_string(),
+        format!("X-WR-CALNAME:{}", NAME),
+    ];
+
+    for chain in backup.chains.iter() {
+        let days = done_days(chain);
+
+        if by_run {
+            for (start, end) in runs(&days) {
+                let length = (end - start).num_days() + 1;
+                let summary = format!("{} ({} day(s))", chain.name, length);
+
+                event(&mut lines, uid(&chain.name, start, "run"), start, end, summary);
+            }
+        } else {
+            for (day, value) in days.iter() {
+                let summary = match (value, &chain.unit) {
+                    (Some(value), Some(unit)) => format!("{} {} {}", chain.name, printer::format_value(*value), unit),
+                    (Some(value), None) => format!("{} {}", chain.name, printer::format_value(*value)),
+                    (None, _) => chain.name.clone(),
+                };
+
+                event(&mut lines, uid(&chain.name, *day, "done"), *day, *day, summary);
+            }
+        }
+    }
+
+    for name in due.iter() {
+        lines.push("BEGIN:VTODO".to_string());
+        lines.push(format!("UID:{}", uid(name, today, "due")));
+        lines.push(format!("DTSTAMP:{}", Utc::now().format("%Y%m%dT%H%M%SZ")));
+        lines.push(format!("DUE;VALUE=DATE:{}", date(today)));
+        lines.push(format!("SUMMARY:{}", escape(name)));
+        lines.push("STATUS:NEEDS-ACTION".to_string());
+        lines.push("END:VTODO".to_string());
+    }
+
+    lines.push("END:VCALENDAR".to_string());
+
+    lines.iter().map(|line| fold(line)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup::BackupLink;
+
+    fn date(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 1, day).unwrap()
+    }
+
+    fn link(day: u32, value: Option<f64>, state: State) -> BackupLink {
+        BackupLink {
+            date: date(day),
+            time: None,
+            value,
+            state: state.as_str().to_string(),
+            reason: None,
+        }
+    }
+
+    fn chain(name: &str, links: Vec<BackupLink>) -> BackupChain {
+        BackupChain {
+            name: name.to_string(),
+            kind: Kind::Positive.as_str().to_string(),
+            links,
+            ..Default::default()
+        }
+    }
+
+    fn write_chains(chains: Vec<BackupChain>, due: &[String], by_run: bool) -> String {
+        write(&Backup { version: 1, chains }, due, by_run, date(10))
+    }
+
+    fn events(ics: &str) -> Vec<&str> {
+        ics.lines().filter(|line| line.starts_with("DTSTART")).collect()
+    }
+
+    #[test]
+    fn done_days_are_events() {
+        let ics = write_chains(
+            vec![chain(
+                "read",
+                vec![link(1, None, State::Done), link(2, None, State::Skipped), link(3, None, State::Done)],
+            )],
+            &[],
+            false,
+        );
+
+        assert!(ics.starts_with("BEGIN:VCALENDAR\r\n"));
+        assert!(ics.ends_with("END:VCALENDAR\r\n"));
+        assert_eq!(events(&ics), vec!["DTSTART;VALUE=DATE:20240101", "DTSTART;VALUE=DATE:20240103"]);
+        assert!(ics.contains("DTEND;VALUE=DATE:20240102\r\n"));
+        assert!(ics.contains("UID:done-20240101-read@"));
+    }
+
+    #[test]
+    fn consecutive_days_are_runs() {
+        let ics = write_chains(
+            vec![chain(
+                "read",
+                vec![link(1, None, State::Done), link(2, None, State::Done), link(4, None, State::Done)],
+            )],
+            &[],
+            true,
+        );
+
+        assert_eq!(events(&ics).len(), 2);
+        assert!(ics.contains("SUMMARY:read (2 day(s))\r\n"));
+        assert!(ics.contains("DTEND;VALUE=DATE:20240103\r\n"));
+    }
+
+    #[test]
+    fn values_are_summed_and_short_days_left_out() {
+        let mut run = chain(
+            "run",
+            vec![link(1, Some(3.0), State::Done), link(1, Some(2.0), State::Done), link(2, Some(1.0), State::Done)],
+        );
+        run.unit = Some("km".to_string());
+        run.target = Some(5.0);
+
+        let ics = write_chains(vec![run], &[], false);
+
+        assert_eq!(events(&ics), vec!["DTSTART;VALUE=DATE:20240101"]);
+        assert!(ics.contains("SUMMARY:run 5 km\r\n"));
+    }
+
+    #[test]
+    fn days_short_of_the_quota_are_left_out() {
+        let mut water = chain(
+            "water",
+            vec![link(1, None, State::Done), link(1, None, State::Done), link(2, None, State::Done)],
+        );
+        water.quota = Some(2);
+
+        let ics = write_chains(vec![water], &[], false);
+
+        assert_eq!(events(&ics), vec!["DTSTART;VALUE=DATE:20240101"]);
+    }
+
+    #[test]
+    fn relapses_are_not_completions() {
+        let mut smoke = chain("smoke", vec![link(1, None, State::Done)]);
+        smoke.kind = Kind::Negative.as_str().to_string();
+
+        let ics = write_chains(vec![smoke], &[], false);
+
+        assert!(events(&ics).is_empty());
+    }
+
+    #[test]
+    fn names_differing_in_punctuation_get_their_own_ids() {
+        assert_ne!(uid("a b", date(1), "done"), uid("a-b", date(1), "done"));
+        assert_ne!(uid("a%20b", date(1), "done"), uid("a b", date(1), "done"));
+        assert_eq!(uid("a b", date(1), "done"), format!("done-20240101-a%20b@{}", NAME));
+    }
+
+    #[test]
+    fn due_chains_are_to_dos() {
+        let ics = write_chains(vec![chain("read", Vec::new())], &["read".to_string()], false);
+
+        assert!(ics.contains("BEGIN:VTODO\r\n"));
+        assert!(ics.contains("DUE;VALUE=DATE:20240110\r\n"));
+        assert!(ics.contains("SUMMARY:read\r\n"));
+    }
+
+    #[test]
+    fn long_lines_are_folded_and_text_escaped() {
+        let name = format!("{}, really", "x".repeat(80));
+        let ics = write_chains(vec![chain(&name, vec![link(1, None, State::Done)])], &[], false);
+
+        assert!(ics.lines().all(|line| line.len() <= LINE_LENGTH + 1));
+        assert!(ics.contains("\\, really"));
+    }
+}
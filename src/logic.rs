@@ -1,114 +1,649 @@
-use super::{Chain, Day, Link, Streak};
-use chrono::{Datelike, Local, NaiveDate, Duration};
+use super::{Chain, Day, DayTotal, Kind, Link, Rule, State, Streak};
+use chrono::{Datelike, Duration, Local, NaiveDate};
+use std::collections::HashMap;
 
-use super::FORMAT;
+/// The current day when new days start at `day_start` instead of midnight,
+/// so a link added after midnight but before `day_start` still counts for
+/// the day before.
+pub fn today(day_start: u32) -> NaiveDate {
+    (Local::now() - Duration::hours(day_start as i64)).date_naive()
+}
+
+/// The first day tracked by a chain, either the day it was started or the
+/// day of its first link if that is earlier.
+pub fn first_day(chain: &Chain, links: &[Link]) -> Option<NaiveDate> {
+    let start = chain.start.or(chain.created);
+    let first_link = links.iter().map(|link| link.date).min();
+
+    match (start, first_link) {
+        (Some(start), Some(first_link)) => Some(start.min(first_link)),
+        (start, first_link) => start.or(first_link),
+    }
+}
+
+/// Check whether a chain has passed its end date.
+pub fn has_ended(chain: &Chain, today: NaiveDate) -> bool {
+    chain.end.is_some_and(|end| end < today)
+}
+
+/// Group the links of a chain by date, summing the values logged on each day.
+///
+/// The returned totals are sorted by date. A day without any values has a
+/// total of `None`. Only done links are counted, a day which was marked as
+/// failed, skipped or excused takes the state of the mark.
+pub fn daily_totals(links: &[Link]) -> Vec<DayTotal> {
+    let mut totals: Vec<DayTotal> = Vec::new();
+
+    for link in links.iter() {
+        let is_new_day = match totals.last() {
+            Some(total) => total.date != link.date,
+            None => true,
+        };
+
+        if is_new_day {
+            totals.push(DayTotal {
+                date: link.date,
+                value: None,
+                count: 0,
+                state: State::Done,
+            });
+        }
+
+        let total = totals.last_mut().unwrap();
+
+        if link.state == State::Done {
+            if let Some(value) = link.value {
+                total.value = Some(total.value.unwrap_or(0.0) + value);
+            }
+            total.count += 1;
+        } else {
+            total.state = link.state;
+        }
+    }
+
+    totals
+}
+
+/// Check whether a day counts towards the chain.
+///
+/// Chains without a target or quota only need a link on the day.
+pub fn is_done(chain: &Chain, total: &DayTotal) -> bool {
+    let meets_target = match chain.target {
+        Some(target) => total.value.unwrap_or(0.0) >= target,
+        None => true,
+    };
+    let meets_quota = match chain.quota {
+        Some(quota) => total.count >= quota,
+        None => true,
+    };
+
+    total.state == State::Done && total.count > 0 && meets_target && meets_quota
+}
+
+/// The state of a day with links, a day which falls short of its target or
+/// quota is missed.
+pub fn day_state(chain: &Chain, total: &DayTotal) -> State {
+    match total.state {
+        State::Done if is_done(chain, total) => State::Done,
+        State::Done => State::Missed,
+        state => state,
+    }
+}
+
+fn calculate_totals(totals: &[DayTotal]) -> (Option<f64>, Option<f64>) {
+    let values: Vec<f64> = totals.iter().filter_map(|total| total.value).collect();
+
+    if values.is_empty() {
+        (None, None)
+    } else {
+        let total: f64 = values.iter().sum();
+
+        (Some(total), Some(total / values.len() as f64))
+    }
+}
+
+pub fn calculate_streak(chain: &Chain, links: &[Link], today: NaiveDate) -> Streak {
+    if chain.kind == Kind::Negative {
+        return calculate_clean_streak(chain, links, today);
+    }
 
-pub fn calculate_streak(chain: &Chain, links: &Vec<Link>) -> Streak {
     let name = chain.name.to_string();
+    let totals = daily_totals(links);
+    let states: HashMap<NaiveDate, State> = totals
+        .iter()
+        .map(|total| (total.date, day_state(chain, total)))
+        .collect();
 
     let mut date: Option<NaiveDate> = None;
-    let mut prev_date: Option<NaiveDate>;
     let mut streak = 0;
     let mut longest_streak = 0;
 
-    for link in links.iter() {
-        prev_date = date;
-        date = Some(link.date);
+    for total in totals.iter() {
+        match states[&total.date] {
+            State::Done => {
+                // Skipped and excused days bridge the gap between two links,
+                // any other day breaks the streak.
+                if let Some(prev_date) = date {
+                    let is_bridged = prev_date
+                        .iter_days()
+                        .skip(1)
+                        .take_while(|day| *day < total.date)
+                        .all(|day| {
+                            matches!(states.get(&day), Some(State::Skipped | State::Excused))
+                        });
 
-        if date.is_some() && prev_date.is_some() {
-            let between = date
-                .unwrap()
-                .signed_duration_since(prev_date.unwrap())
-                .num_days()
-                - 1;
+                    if !is_bridged {
+                        if streak > longest_streak {
+                            longest_streak = streak;
+                        }
+                        streak = 0;
+                    }
+                }
 
-            if between > 0 {
+                date = Some(total.date);
+                streak += 1;
+            }
+            State::Missed | State::Failed => {
                 if streak > longest_streak {
                     longest_streak = streak;
                 }
+                date = None;
                 streak = 0;
             }
+            State::Skipped | State::Excused | State::Untracked => (),
         }
-
-        streak += 1;
     }
 
     if streak > longest_streak {
         longest_streak = streak;
     }
 
+    let (total, average) = calculate_totals(&totals);
+
+    let count = |state: State| states.values().filter(|s| **s == state).count() as i32;
+
+    // Days since the chain was started without any links are missed, today
+    // is not over yet.
+    let missed_days = match first_day(chain, links) {
+        Some(first_day) => first_day
+            .iter_days()
+            .take_while(|day| *day < today && chain.end.is_none_or(|end| *day <= end))
+            .filter(|day| matches!(states.get(day), None | Some(State::Missed)))
+            .count() as i32,
+        None => 0,
+    };
+
     Streak {
         name,
         streak,
         longest_streak,
+        total,
+        average,
+        unit: chain.unit.clone(),
+        kind: chain.kind,
+        description: chain.description.clone(),
+        goal: chain.goal.clone(),
+        created: chain.created,
+        start: chain.start,
+        color: chain.color.clone(),
+        icon: chain.icon.clone(),
+        done_days: count(State::Done),
+        missed_days,
+        failed_days: count(State::Failed),
+        skipped_days: count(State::Skipped),
+        excused_days: count(State::Excused),
+        children: Vec::new(),
     }
 }
 
-pub fn create_days(links: &Vec<Link>) -> Vec<Day> {
-    let mut days: Vec<Day> = Vec::new();
+/// Calculate the streak of a negative chain, where every link is a relapse.
+///
+/// The current streak is the number of days since the last relapse and the
+/// longest streak is the longest gap between two relapses.
+fn calculate_clean_streak(chain: &Chain, links: &[Link], today: NaiveDate) -> Streak {
+    let totals: Vec<DayTotal> = daily_totals(links)
+        .into_iter()
+        .filter(|total| total.count > 0)
+        .collect();
 
-    if links.len() == 0 {
-        return create_dummy_days()
-    }
+    // The chain was clean from its first day until the first relapse.
+    let mut longest_streak = match (first_day(chain, links), totals.first()) {
+        (Some(first_day), Some(relapse)) => relapse.date.signed_duration_since(first_day).num_days() as i32,
+        _ => 0,
+    };
 
-    let start_date = links[0].date;
-    let end_date = Local::today().naive_local(); 
+    for pair in totals.windows(2) {
+        let between = pair[1].date.signed_duration_since(pair[0].date).num_days() as i32 - 1;
 
-    let difference = end_date.signed_duration_since(start_date).num_days();
-    let start_date = if difference < 10 {
-        start_date - Duration::days(9 - difference)
-    } else {
-        start_date + Duration::days(difference - 9) 
+        if between > longest_streak {
+            longest_streak = between;
+        }
+    }
+
+    // Without any relapses the chain has been clean since it was started.
+    let streak = match totals.last().map(|total| total.date).or_else(|| first_day(chain, links)) {
+        Some(date) => today.signed_duration_since(date).num_days().max(0) as i32,
+        None => 0,
     };
 
-    let links = if links.len() > 10 {
-        links.get(links.len() - 10..).unwrap()
+    if streak > longest_streak {
+        longest_streak = streak;
+    }
+
+    let (total, average) = calculate_totals(&totals);
+
+    Streak {
+        name: chain.name.to_string(),
+        streak,
+        longest_streak,
+        total,
+        average,
+        unit: chain.unit.clone(),
+        kind: chain.kind,
+        description: chain.description.clone(),
+        goal: chain.goal.clone(),
+        created: chain.created,
+        start: chain.start,
+        color: chain.color.clone(),
+        icon: chain.icon.clone(),
+        done_days: 0,
+        missed_days: 0,
+        failed_days: 0,
+        skipped_days: 0,
+        excused_days: 0,
+        children: Vec::new(),
+    }
+}
+
+/// Create the last `length` days of a chain, ending today.
+pub fn create_days(chain: &Chain, links: &[Link], length: usize, today: NaiveDate) -> Vec<Day> {
+    let mut days: Vec<Day> = Vec::new();
+
+    if links.is_empty() {
+        return create_dummy_days(chain, length, today);
+    }
+
+    let end_date = today;
+    let start_date = end_date - Duration::days(length as i64 - 1);
+
+    let totals = daily_totals(links);
+    let totals = if totals.len() > length {
+        totals.get(totals.len() - length..).unwrap()
     } else {
-        links.get(..).unwrap()
+        totals.get(..).unwrap()
     };
 
-    assert!(start_date.iter_days().take(10).last().unwrap().format(FORMAT).to_string() == end_date.format(FORMAT).to_string());
+    let first_day = first_day(chain, links);
 
-    let mut is_done = false;
+    for date in start_date.iter_days().take(length) {
+        // Days without a relapse are the done days of a negative chain.
+        let mut state = match chain.kind {
+            _ if first_day.is_some_and(|first_day| date < first_day) => State::Untracked,
+            _ if chain.end.is_some_and(|end| date > end) => State::Untracked,
+            Kind::Positive => State::Missed,
+            Kind::Negative => State::Done,
+        };
+        let mut value = None;
 
-    for date in start_date.iter_days().take(10) {
-        for link in links {
-            if link.date.signed_duration_since(date).num_days() == 0 {
-                is_done = true;
+        for total in totals {
+            if total.date.signed_duration_since(date).num_days() == 0 {
+                state = match chain.kind {
+                    Kind::Positive => day_state(chain, total),
+                    Kind::Negative if total.count > 0 => State::Missed,
+                    Kind::Negative => State::Done,
+                };
+                // Show the number of check-ins for chains with a quota.
+                value = match (total.value, chain.quota) {
+                    (None, Some(_)) => Some(total.count as f64),
+                    (value, _) => value,
+                };
             }
         }
 
         let day = Day {
             day: date.day() as i32,
-            is_done: is_done
+            weekday: date.weekday(),
+            state,
+            value,
         };
 
         days.push(day);
-        
-        is_done = false;
     }
 
-    assert!(days.len() == 10);
+    assert!(days.len() == length);
 
     days
 }
 
-pub fn create_dummy_days() -> Vec<Day> {
+pub fn create_dummy_days(chain: &Chain, length: usize, today: NaiveDate) -> Vec<Day> {
     let mut days: Vec<Day> = Vec::new();
 
-    let today = Local::today().naive_local(); 
-    let start_date = today - Duration::days(9);
+    let start_date = today - Duration::days(length as i64 - 1);
 
-    for date in start_date.iter_days().take(10) {
+    let first_day = first_day(chain, &[]);
+
+    for date in start_date.iter_days().take(length) {
         let day = Day {
             day: date.day() as i32,
-            is_done: false
+            weekday: date.weekday(),
+            state: match chain.kind {
+                _ if first_day.is_some_and(|first_day| date < first_day) => State::Untracked,
+                _ if chain.end.is_some_and(|end| date > end) => State::Untracked,
+                Kind::Positive => State::Missed,
+                Kind::Negative => State::Done,
+            },
+            value: None,
         };
 
         days.push(day);
     }
 
-    assert!(days.len() == 10);
+    assert!(days.len() == length);
 
     days
 }
+
+/// Derive the links of a group of chains, with a link on every day on which
+/// all or at least one of the chains was done, depending on the rule.
+///
+/// Days a chain was skipped or excused don't count against the rule, a day
+/// on which every chain was skipped or excused is skipped.
+///
+/// Negative chains are left out, every day without a relapse would count.
+pub fn derived_links(rule: Rule, chains: &[(Chain, Vec<Link>)]) -> Vec<Link> {
+    let states: Vec<HashMap<NaiveDate, State>> = chains
+        .iter()
+        .filter(|(chain, _)| chain.kind == Kind::Positive)
+        .map(|(chain, links)| {
+            daily_totals(links)
+                .iter()
+                .map(|total| (total.date, day_state(chain, total)))
+                .collect()
+        })
+        .collect();
+
+    let mut dates: Vec<NaiveDate> = states.iter().flat_map(|states| states.keys()).copied().collect();
+
+    dates.sort();
+    dates.dedup();
+
+    let mut links: Vec<Link> = Vec::new();
+
+    for date in dates {
+        let day: Vec<Option<State>> = states.iter().map(|states| states.get(&date).copied()).collect();
+
+        let is_done = |state: &Option<State>| *state == Some(State::Done);
+        let is_neutral = |state: &Option<State>| matches!(state, Some(State::Skipped | State::Excused));
+
+        let is_met = match rule {
+            Rule::All => day.iter().all(|state| is_done(state) || is_neutral(state)),
+            Rule::Any => day.iter().any(is_done),
+        };
+
+        let state = if day.iter().all(is_neutral) {
+            State::Skipped
+        } else if is_met {
+            State::Done
+        } else {
+            continue;
+        };
+
+        links.push(Link {
+            id: -1,
+            chain_id: -1,
+            date,
+            time: None,
+            value: None,
+            state,
+            reason: None,
+        });
+    }
+
+    links
+}
+
+/// The number of single character insertions, deletions and substitutions
+/// needed to turn `a` into `b`.
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, a) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+
+        for (j, b) in b.iter().enumerate() {
+            let current = row[j + 1];
+
+            row[j + 1] = if a == *b {
+                previous
+            } else {
+                previous.min(row[j]).min(row[j + 1]) + 1
+            };
+            previous = current;
+        }
+    }
+
+    row[b.len()]
+}
+
+/// Find the names closest to a misspelled name, at most three and only if
+/// they are reasonably close.
+pub fn suggest<'a>(name: &str, names: &'a [String]) -> Vec<&'a str> {
+    let name = name.to_lowercase();
+    let max_distance = (name.chars().count() / 3).max(2);
+
+    let mut suggestions: Vec<(usize, &str)> = names
+        .iter()
+        .map(|candidate| (edit_distance(&name, &candidate.to_lowercase()), candidate.as_str()))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .collect();
+
+    suggestions.sort();
+    suggestions.dedup();
+
+    suggestions.into_iter().take(3).map(|(_, name)| name).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 1, day).unwrap()
+    }
+
+    fn link(day: u32, value: Option<f64>) -> Link {
+        Link {
+            id: 0,
+            chain_id: 1,
+            date: date(day),
+            time: None,
+            value,
+            state: State::Done,
+            reason: None,
+        }
+    }
+
+    fn chain() -> Chain {
+        Chain {
+            name: "read".to_string(),
+            created: Some(date(1)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn values_are_summed_per_day() {
+        let links = [link(1, Some(2.0)), link(1, Some(0.5)), link(2, None), link(3, Some(4.0))];
+
+        let totals = daily_totals(&links);
+        let values: Vec<Option<f64>> = totals.iter().map(|total| total.value).collect();
+        assert_eq!(values, vec![Some(2.5), None, Some(4.0)]);
+
+        let streak = calculate_streak(&chain(), &links, date(4));
+        assert_eq!(streak.streak, 3);
+        assert_eq!(streak.total, Some(6.5));
+        // Days without a value don't count towards the average.
+        assert_eq!(streak.average, Some(3.25));
+    }
+
+    #[test]
+    fn days_short_of_the_target_are_missed() {
+        let chain = Chain {
+            target: Some(10.0),
+            ..chain()
+        };
+        let links = [link(1, Some(10.0)), link(2, Some(4.0)), link(3, Some(6.0)), link(3, Some(5.0)), link(4, None)];
+
+        let streak = calculate_streak(&chain, &links, date(5));
+        assert_eq!(streak.streak, 0);
+        assert_eq!(streak.longest_streak, 1);
+        assert_eq!(streak.done_days, 2);
+        assert_eq!(streak.missed_days, 2);
+    }
+
+    #[test]
+    fn days_short_of_the_quota_are_missed() {
+        let chain = Chain {
+            quota: Some(2),
+            ..chain()
+        };
+        let links = [link(1, None), link(1, None), link(2, None), link(3, None), link(3, None), link(3, None)];
+
+        let totals = daily_totals(&links);
+        let counts: Vec<i32> = totals.iter().map(|total| total.count).collect();
+        assert_eq!(counts, vec![2, 1, 3]);
+
+        let streak = calculate_streak(&chain, &links, date(4));
+        assert_eq!(streak.streak, 1);
+        assert_eq!(streak.longest_streak, 1);
+        assert_eq!(streak.done_days, 2);
+        assert_eq!(streak.missed_days, 1);
+    }
+
+    #[test]
+    fn negative_chains_count_days_since_the_last_relapse() {
+        let chain = Chain {
+            kind: Kind::Negative,
+            ..chain()
+        };
+
+        let streak = calculate_streak(&chain, &[], date(8));
+        assert_eq!((streak.streak, streak.longest_streak), (7, 7));
+
+        let links = [link(3, None), link(4, None), link(4, None), link(10, None)];
+
+        let streak = calculate_streak(&chain, &links, date(12));
+        assert_eq!(streak.streak, 2);
+        assert_eq!(streak.longest_streak, 5);
+        assert_eq!(streak.missed_days, 0);
+    }
+
+    #[test]
+    fn negative_chains_are_clean_until_their_first_relapse() {
+        let chain = Chain {
+            kind: Kind::Negative,
+            start: Some(NaiveDate::from_ymd_opt(2020, 1, 1).unwrap()),
+            ..chain()
+        };
+        let relapse = |date: NaiveDate| Link { date, ..link(1, None) };
+        let links = [
+            relapse(date(1)),
+            relapse(date(3)),
+            relapse(NaiveDate::from_ymd_opt(2026, 10, 18).unwrap()),
+        ];
+
+        let streak = calculate_streak(&chain, &links, NaiveDate::from_ymd_opt(2026, 10, 19).unwrap());
+        assert_eq!(streak.streak, 1);
+        assert_eq!(streak.longest_streak, 1461);
+    }
+
+    #[test]
+    fn skipped_and_excused_days_bridge_streaks_and_failed_days_break_them() {
+        let mark = |day: u32, state: State| Link {
+            state,
+            ..link(day, None)
+        };
+        let links = [
+            link(1, None),
+            mark(2, State::Skipped),
+            mark(3, State::Excused),
+            link(4, None),
+            mark(5, State::Failed),
+            link(6, None),
+        ];
+
+        let streak = calculate_streak(&chain(), &links, date(8));
+        assert_eq!(streak.streak, 1);
+        assert_eq!(streak.longest_streak, 2);
+        assert_eq!(streak.done_days, 3);
+        assert_eq!(streak.failed_days, 1);
+        assert_eq!(streak.skipped_days, 1);
+        assert_eq!(streak.excused_days, 1);
+        // Yesterday had no links, today isn't over yet.
+        assert_eq!(streak.missed_days, 1);
+
+        // A mark takes over the day it was made on.
+        let totals = daily_totals(&[link(1, Some(3.0)), mark(1, State::Failed)]);
+        assert_eq!(day_state(&chain(), &totals[0]), State::Failed);
+    }
+
+    #[test]
+    fn edit_distances_count_single_character_edits() {
+        assert_eq!(edit_distance("read", "read"), 0);
+        assert_eq!(edit_distance("raed", "read"), 2);
+        assert_eq!(edit_distance("red", "read"), 1);
+        assert_eq!(edit_distance("", "gym"), 3);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn only_close_names_are_suggested() {
+        let names: Vec<String> = ["read", "Reading", "run", "meditate", "bread"]
+            .iter()
+            .map(|name| name.to_string())
+            .collect();
+
+        assert_eq!(suggest("reed", &names), vec!["read", "bread"]);
+        assert_eq!(suggest("MEDITATES", &names), vec!["meditate"]);
+        assert!(suggest("swim", &names).is_empty());
+    }
+
+    #[test]
+    fn skipped_and_excused_chains_dont_break_composite_chains() {
+        let mark = |day: u32, state: State| Link {
+            state,
+            ..link(day, None)
+        };
+        let chains = vec![
+            (chain(), vec![link(1, None), mark(2, State::Skipped), mark(3, State::Excused), link(4, None)]),
+            (chain(), vec![link(1, None), link(2, None), mark(3, State::Skipped), link(5, None)]),
+        ];
+
+        let states = |rule: Rule| -> Vec<(u32, State)> {
+            derived_links(rule, &chains)
+                .iter()
+                .map(|link| (link.date.day(), link.state))
+                .collect()
+        };
+
+        // The first chain wasn't done on the fifth and the second on the
+        // fourth.
+        assert_eq!(
+            states(Rule::All),
+            vec![(1, State::Done), (2, State::Done), (3, State::Skipped)]
+        );
+        assert_eq!(
+            states(Rule::Any),
+            vec![(1, State::Done), (2, State::Done), (3, State::Skipped), (4, State::Done), (5, State::Done)]
+        );
+
+        let all = Chain {
+            rule: Some(Rule::All),
+            ..chain()
+        };
+        let streak = calculate_streak(&all, &derived_links(Rule::All, &chains), date(4));
+        assert_eq!(streak.streak, 2);
+    }
+}
@@ -1,139 +1,774 @@
 use anyhow::{anyhow, Result};
-use chrono::{Local, NaiveDate};
-use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
-use dirs;
-use rusqlite::Connection;
+use chrono::{Duration, Local, NaiveDate, NaiveTime, Timelike};
+use clap::{App, AppSettings, Arg, ArgMatches, Shell, SubCommand};
+use rusqlite::{Connection, Transaction};
 use std::fs;
+use std::path::{Path, PathBuf};
+use std::io::{self, BufRead, IsTerminal, Write};
 
-pub use structs::{Chain, Day, Link, Streak};
+use chain_error::ChainError;
+use config::Config;
+use store::Store;
 
+pub use structs::{
+    Chain, DatabaseInfo, Day, DayTotal, Kind, Link, Note, Operation, Rule, State, Streak,
+};
+
+pub mod backup;
 pub mod chain_error;
+pub mod completions;
+pub mod config;
 pub mod database;
+pub mod habit_apps;
+pub mod history;
+pub mod ics;
 pub mod logic;
+pub mod oplog;
+pub mod org;
 pub mod printer;
+pub mod server;
+pub mod spreadsheet;
+pub mod store;
 pub mod structs;
+pub mod sync;
+pub mod text_store;
 
 // Cargo Information
-const AUTHORS: &'static str = env!("CARGO_PKG_AUTHORS");
-const DESCRIPTION: &'static str = env!("CARGO_PKG_DESCRIPTION");
-const NAME: &'static str = env!("CARGO_PKG_NAME");
-const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+const AUTHORS: &str = env!("CARGO_PKG_AUTHORS");
+const DESCRIPTION: &str = env!("CARGO_PKG_DESCRIPTION");
+const NAME: &str = env!("CARGO_PKG_NAME");
+const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 // Link Manipulation Commands
-const ADD: &'static str = "add";
-const MV: &'static str = "mv";
-const RM: &'static str = "rm";
+const ADD: &str = "add";
+const MV: &str = "mv";
+const RM: &str = "rm";
+const NOTE: &str = "note";
+const FAIL: &str = "fail";
+const SKIP: &str = "skip";
+const EXCUSE: &str = "excuse";
 
 // Chain Manipulation Commands
-const ADD_CHAIN: &'static str = "add-chain";
-const RENAME_CHAIN: &'static str = "rename-chain";
-const RM_CHAIN: &'static str = "rm-chain";
+const ADD_CHAIN: &str = "add-chain";
+const RENAME_CHAIN: &str = "rename-chain";
+const RM_CHAIN: &str = "rm-chain";
+const EDIT_CHAIN: &str = "edit-chain";
+const ARCHIVE: &str = "archive";
+const UNARCHIVE: &str = "unarchive";
+const TAG: &str = "tag";
+const UNTAG: &str = "untag";
 
 // Chain Information Commands
-const DUE: &'static str = "due";
-const LS: &'static str = "ls";
-const STATUS: &'static str = "status";
+const DUE: &str = "due";
+const LS: &str = "ls";
+const STATUS: &str = "status";
+const TAGS: &str = "tags";
+const ALIAS: &str = "alias";
+const UNALIAS: &str = "unalias";
+const LOG: &str = "log";
+const SEARCH: &str = "search";
+
+// History Commands
+const UNDO: &str = "undo";
+const REDO: &str = "redo";
+
+// Database Commands
+const DB: &str = "db";
+const INFO: &str = "info";
+const CONVERT: &str = "convert";
+const EXPORT: &str = "export";
+const IMPORT: &str = "import";
+
+// Sync Commands
+const SYNC: &str = "sync";
+const MERGE: &str = "merge";
+const DIR: &str = "dir";
+const PEER: &str = "peer";
+const SERVE: &str = "serve";
+
+// Profile Commands
+const PROFILE: &str = "profile";
+const CREATE: &str = "create";
+const USE: &str = "use";
+
+// Configuration Commands
+const CONFIG: &str = "config";
+const LIST: &str = "list";
+const GET: &str = "get";
+const SET: &str = "set";
+
+// Shell Completion Commands
+const COMPLETIONS: &str = "completions";
+const COMPLETE: &str = "__complete";
 
 // Argument Names
-const CHAIN: &'static str = "CHAIN";
-const MACHINE: &'static str = "machine";
-const CURRENT: &'static str = "CURRENT";
-const NEW: &'static str = "NEW";
-const DATE: &'static str = "DATE";
+const CHAIN: &str = "CHAIN";
+const MACHINE: &str = "machine";
+const CURRENT: &str = "CURRENT";
+const NEW: &str = "NEW";
+const DATE: &str = "DATE";
+const TARGET: &str = "target";
+const UNIT: &str = "unit";
+const VALUE: &str = "value";
+const TEXT: &str = "TEXT";
+const TIME: &str = "time";
+const QUOTA: &str = "quota";
+const NEGATIVE: &str = "negative";
+const REASON: &str = "reason";
+const DESCRIPTION_ARG: &str = "desc";
+const COLOR: &str = "color";
+const ICON: &str = "icon";
+const GOAL: &str = "goal";
+const START: &str = "start";
+const END: &str = "end";
+const ARCHIVED: &str = "archived";
+const OPS: &str = "ops";
+const COUNT: &str = "COUNT";
+const YES: &str = "yes";
+const DRY_RUN: &str = "dry-run";
+const VERBOSE: &str = "verbose";
+const TAG_ARG: &str = "TAG";
+const GROUP: &str = "group";
+const ALL: &str = "all";
+const ANY: &str = "any";
+const ALIAS_ARG: &str = "ALIAS";
+const SHELL: &str = "SHELL";
+const KIND: &str = "KIND";
+const CHAINS: &str = "chains";
+const DATES: &str = "dates";
+const KEY: &str = "KEY";
+const BACKEND: &str = "BACKEND";
+const PATH: &str = "PATH";
+const PROFILE_ARG: &str = "PROFILE";
+const ALL_PROFILES: &str = "all-profiles";
+const SETTING: &str = "VALUE";
+const FORMAT_ARG: &str = "format";
+const FILE: &str = "FILE";
+const REPLACE: &str = "replace";
+const MAP: &str = "map";
+const INPUT_DATE_FORMAT: &str = "input-date-format";
+const CHAIN_OPTION: &str = "chain";
+const RUNS: &str = "runs";
+const FILE_OPTION: &str = "file";
+const ADDRESS: &str = "ADDRESS";
+const LISTEN: &str = "listen";
+const TOKEN: &str = "token";
+
+// The formats chains are exported to and imported from.
+const EXPORT_FORMATS: &[&str] = &["json", "csv", "org", "ics"];
+const IMPORT_FORMATS: &[&str] = &["json", "csv", "org", "loop", "habitica", "habitbull"];
+const CONFIG_FILE: &str = "config-file";
+
+// Global flags overriding the settings in the config file.
+const OVERRIDES: &[(&str, &str)] = &[
+    ("database", config::DATABASE),
+    ("backend", config::BACKEND),
+    ("profile", config::PROFILE),
+    ("date-format", config::DATE_FORMAT),
+    ("week-start", config::WEEK_START),
+    ("days", config::DAYS),
+    ("theme", config::THEME),
+    ("day-start", config::DAY_START),
+    ("output", config::OUTPUT),
+];
+
+const FORMAT: &str = "%Y-%m-%d";
+const TIME_FORMAT: &str = "%H:%M:%S";
+
+/// Parse a date given on the command line, either in `FORMAT` or as `today`
+/// or `yesterday`.
+fn parse_date(date: &str, config: &Config) -> Result<NaiveDate> {
+    match date {
+        "today" => Ok(config.today()),
+        "yesterday" => Ok(config.today() - Duration::days(1)),
+        _ => Ok(NaiveDate::parse_from_str(date, FORMAT)?),
+    }
+}
+
+/// Add a link to a chain with an optional note for the day, used by `add`
+/// and the API server.
+fn add_link(
+    conn: &Connection,
+    chain: &Chain,
+    date: NaiveDate,
+    time: Option<NaiveTime>,
+    value: Option<f64>,
+    note: Option<&str>,
+    config: &Config,
+) -> Result<Link> {
+    check_not_composite(chain)?;
+
+    // Record the time of the check-in, defaulting to now for links added today.
+    let time = match time {
+        Some(time) => Some(time),
+        None if date == config.today() => Some(Local::now().time()),
+        None => None,
+    };
+
+    let link = Link {
+        id: -1,
+        chain_id: chain.id as i32,
+        date,
+        time,
+        value,
+        state: State::Done,
+        reason: None,
+    };
 
-const FORMAT: &'static str = "%Y-%m-%d";
+    // A link replaces a mark of the day.
+    database::delete_marks(conn, &link)?;
+
+    // Chains with a quota keep every check-in, the others have one link per day.
+    if chain.quota.is_some() {
+        database::insert_link(conn, &link)?;
+    } else {
+        database::add_link(conn, &link)?;
+    }
+
+    if let Some(text) = note {
+        let note = Note {
+            id: -1,
+            chain_id: chain.id as i32,
+            date,
+            text: text.to_string(),
+        };
+
+        database::add_note(conn, &note)?;
+    }
+
+    Ok(link)
+}
 
-fn add(conn: &Connection, m: &ArgMatches) -> Result<()> {
+fn add(conn: &Connection, m: &ArgMatches, config: &Config) -> Result<()> {
     let name = m.value_of(CHAIN).unwrap();
 
     let date = if m.is_present(DATE) {
-        NaiveDate::parse_from_str(m.value_of(DATE).unwrap(), FORMAT)?
+        parse_date(m.value_of(DATE).unwrap(), config)?
     } else {
-        Local::today().naive_local()
+        config.today()
     };
 
-    let id = database::get_chain_id_for_name(&conn, &name)?;
-    let chain = database::get_chain_for_id(&conn, id)?;
+    let id = database::get_chain_id_for_name(conn, name)?;
+    let chain = database::get_chain_for_id(conn, id)?;
 
-    let link = Link { chain_id: id, date };
+    let value = match m.value_of(VALUE) {
+        Some(value) => Some(value.parse::<f64>()?),
+        None => None,
+    };
+    let time = match m.value_of(TIME) {
+        Some(time) => Some(NaiveTime::parse_from_str(time, "%H:%M")?),
+        None => None,
+    };
 
-    database::add_link(&conn, &link)?;
+    let link = add_link(conn, &chain, date, time, value, m.value_of(NOTE), config)?;
 
-    let links = database::get_links_for_chain_id(&conn, id)?;
+    let links = database::get_links_for_chain_id(conn, id)?;
 
-    let streak = logic::calculate_streak(&chain, &links);
-    let days = logic::create_days(&links);
+    let streak = logic::calculate_streak(&chain, &links, config.today());
+    let days = logic::create_days(&chain, &links, config.days, config.today());
 
-    printer::print_add(&chain, &link);
-    printer::print_streak(&streak, &days);
+    printer::print_add(&chain, &link, config);
+    printer::print_streak(&streak, &days, config);
 
     Ok(())
 }
 
-fn mv(conn: &Connection, m: &ArgMatches) -> Result<()> {
+fn mv(conn: &Connection, m: &ArgMatches, config: &Config) -> Result<()> {
     let name = m.value_of(CHAIN).unwrap();
 
-    let current_date = NaiveDate::parse_from_str(m.value_of(CURRENT).unwrap(), FORMAT)?;
-    let new_date = NaiveDate::parse_from_str(m.value_of(NEW).unwrap(), FORMAT)?;
+    let current_date = parse_date(m.value_of(CURRENT).unwrap(), config)?;
+    let new_date = parse_date(m.value_of(NEW).unwrap(), config)?;
 
-    let id = database::get_chain_id_for_name(&conn, &name)?;
-    let chain = database::get_chain_for_name(&conn, &name)?;
+    let id = database::get_chain_id_for_name(conn, name)?;
+    let chain = database::get_chain_for_name(conn, name)?;
 
     let current = Link {
+        id: -1,
         chain_id: id,
         date: current_date,
+        time: None,
+        value: None,
+        state: State::Done,
+        reason: None,
     };
     let new = Link {
+        id: -1,
         chain_id: id,
         date: new_date,
+        time: None,
+        value: None,
+        state: State::Done,
+        reason: None,
     };
 
     database::update(conn, &current, &new)?;
-    printer::print_mv(&chain, &current, &new);
+    printer::print_mv(&chain, &current, &new, config);
+
+    Ok(())
+}
+
+/// Ask the user to confirm a destructive command.
+///
+/// Commands run without asking when `--yes` is given or stdin is not a
+/// terminal, and never ask during a dry run since nothing is saved.
+fn confirm(m: &ArgMatches, message: &str) -> Result<bool> {
+    if m.is_present(YES) || m.is_present(DRY_RUN) || !io::stdin().is_terminal() {
+        return Ok(true);
+    }
+
+    let is_confirmed = ask(message, false)?;
+
+    if !is_confirmed {
+        printer::print_aborted();
+    }
+
+    Ok(is_confirmed)
+}
+
+/// Ask the user a yes or no question, an empty answer picks the default.
+fn ask(message: &str, default: bool) -> Result<bool> {
+    print!("{} {} ", message, if default { "[Y/n]" } else { "[y/N]" });
+    io::stdout().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+
+    Ok(match answer.trim().to_lowercase().as_str() {
+        "" => default,
+        answer => matches!(answer, "y" | "yes"),
+    })
+}
+
+fn rm(conn: &Connection, m: &ArgMatches, config: &Config) -> Result<()> {
+    let date = parse_date(m.value_of(DATE).unwrap(), config)?;
+    let name = m.value_of(CHAIN).unwrap();
+
+    let id = database::get_chain_id_for_name(conn, name)?;
+    let chain = database::get_chain_for_name(conn, name)?;
+
+    let link = Link {
+        id: -1,
+        chain_id: id,
+        date,
+        time: None,
+        value: None,
+        state: State::Done,
+        reason: None,
+    };
+
+    // Delete a single check-in if a time is given, otherwise the whole day.
+    if let Some(time) = m.value_of(TIME) {
+        let time = NaiveTime::parse_from_str(time, "%H:%M")?;
+        let links = database::get_links_for_chain_id(conn, id)?;
+        let check_in = links
+            .iter()
+            .filter(|link| link.date == date)
+            .find(|link| {
+                link.time
+                    .is_some_and(|t| t.hour() == time.hour() && t.minute() == time.minute())
+            })
+            .ok_or_else(|| {
+                ChainError::new(&format!(
+                    "\"{}\" has no check-in at \"{} {}\"",
+                    chain.name,
+                    date.format(FORMAT),
+                    time.format("%H:%M")
+                ))
+            })?;
+
+        let message = format!(
+            "Delete the check-in at \"{} {}\" from \"{}\"?",
+            date.format(FORMAT),
+            time.format("%H:%M"),
+            chain.name
+        );
+
+        if !confirm(m, &message)? {
+            return Ok(());
+        }
+
+        database::delete_link_for_id(conn, check_in.id)?;
+    } else {
+        let links = database::get_links_for_chain_id(conn, id)?;
+        let count = links.iter().filter(|link| link.date == date).count();
+
+        let message = format!(
+            "Delete {} link(s) for \"{}\" from \"{}\"?",
+            count,
+            date.format(FORMAT),
+            chain.name
+        );
+
+        if !confirm(m, &message)? {
+            return Ok(());
+        }
+
+        database::delete_link(conn, &link)?;
+    }
+
+    printer::print_rm(&chain, &link, config);
+
+    Ok(())
+}
+
+fn mark(conn: &Connection, m: &ArgMatches, state: State, config: &Config) -> Result<()> {
+    let name = m.value_of(CHAIN).unwrap();
+
+    let date = if m.is_present(DATE) {
+        parse_date(m.value_of(DATE).unwrap(), config)?
+    } else {
+        config.today()
+    };
+
+    let id = database::get_chain_id_for_name(conn, name)?;
+    let chain = database::get_chain_for_id(conn, id)?;
+
+    check_not_composite(&chain)?;
+
+    let link = Link {
+        id: -1,
+        chain_id: id,
+        date,
+        time: None,
+        value: None,
+        state,
+        reason: m.value_of(REASON).map(|reason| reason.to_string()),
+    };
+
+    database::set_mark(conn, &link)?;
+
+    let links = database::get_links_for_chain_id(conn, id)?;
+
+    let streak = logic::calculate_streak(&chain, &links, config.today());
+    let days = logic::create_days(&chain, &links, config.days, config.today());
+
+    printer::print_mark(&chain, &link, config);
+    printer::print_streak(&streak, &days, config);
 
     Ok(())
 }
 
-fn rm(conn: &Connection, m: &ArgMatches) -> Result<()> {
-    let date = NaiveDate::parse_from_str(m.value_of(DATE).unwrap(), FORMAT)?;
+fn note(conn: &Connection, m: &ArgMatches, config: &Config) -> Result<()> {
     let name = m.value_of(CHAIN).unwrap();
+    let date = parse_date(m.value_of(DATE).unwrap(), config)?;
+    let text = m.value_of(TEXT).unwrap();
+
+    let id = database::get_chain_id_for_name(conn, name)?;
+    let chain = database::get_chain_for_id(conn, id)?;
+
+    let link = Link {
+        id: -1,
+        chain_id: id,
+        date,
+        time: None,
+        value: None,
+        state: State::Done,
+        reason: None,
+    };
+
+    if !database::has_link(conn, &link)? {
+        return Err(ChainError::new(&format!(
+            "\"{}\" has no link for \"{}\"",
+            chain.name,
+            date.format(FORMAT)
+        ))
+        .into());
+    }
+
+    let note = Note {
+        id: -1,
+        chain_id: id,
+        date,
+        text: text.to_string(),
+    };
+
+    database::add_note(conn, &note)?;
+    printer::print_note(&chain, &note, config);
+
+    Ok(())
+}
+
+/// Read an optional setting, an empty value clears it.
+fn optional_value(m: &ArgMatches, name: &str) -> Option<Option<String>> {
+    m.value_of(name).map(|value| {
+        if value.is_empty() {
+            None
+        } else {
+            Some(value.to_string())
+        }
+    })
+}
+
+/// Apply the metadata options shared by `add-chain` and `edit-chain`.
+fn apply_chain_options(chain: &mut Chain, m: &ArgMatches, config: &Config) -> Result<()> {
+    if let Some(unit) = optional_value(m, UNIT) {
+        chain.unit = unit;
+    }
+
+    if let Some(target) = optional_value(m, TARGET) {
+        chain.target = match target {
+            Some(target) => Some(target.parse::<f64>()?),
+            None => None,
+        };
+    }
+
+    if let Some(quota) = optional_value(m, QUOTA) {
+        chain.quota = match quota {
+            Some(quota) => Some(quota.parse::<i32>()?),
+            None => None,
+        };
+    }
+
+    if let Some(description) = optional_value(m, DESCRIPTION_ARG) {
+        chain.description = description;
+    }
+
+    if let Some(color) = optional_value(m, COLOR) {
+        if let Some(color) = &color {
+            if printer::color_code(color).is_none() {
+                return Err(ChainError::new(&format!("Unknown color \"{}\"", color)).into());
+            }
+        }
+
+        chain.color = color;
+    }
+
+    if let Some(icon) = optional_value(m, ICON) {
+        chain.icon = icon;
+    }
+
+    if let Some(goal) = optional_value(m, GOAL) {
+        chain.goal = goal;
+    }
+
+    if let Some(start) = optional_value(m, START) {
+        chain.start = match start {
+            Some(start) => Some(parse_date(&start, config)?),
+            None => None,
+        };
+    }
+
+    if let Some(end) = optional_value(m, END) {
+        chain.end = match end {
+            Some(end) => Some(parse_date(&end, config)?),
+            None => None,
+        };
+    }
+
+    Ok(())
+}
+
+/// Links of composite chains are derived from their chains and can't be
+/// added by hand.
+fn check_not_composite(chain: &Chain) -> Result<()> {
+    if chain.rule.is_some() {
+        return Err(ChainError::new(&format!(
+            "The links of \"{}\" are derived from its chains",
+            chain.name
+        ))
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Check whether `chain` is the chain with `id` or is made of it.
+fn contains_chain(conn: &Connection, chain: &Chain, id: i64) -> Result<bool> {
+    if chain.id == id {
+        return Ok(true);
+    }
+
+    for child_id in chain.children.iter() {
+        let child = database::get_chain_for_id(conn, *child_id as i32)?;
+
+        if contains_chain(conn, &child, id)? {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Apply `--all` or `--any`, which turn a chain into a composite of other
+/// chains.
+fn apply_composite_options(conn: &Connection, chain: &mut Chain, m: &ArgMatches) -> Result<()> {
+    for rule in [Rule::All, Rule::Any] {
+        let names = match m.values_of(rule.as_str()) {
+            Some(names) => names,
+            None => continue,
+        };
+
+        let mut children: Vec<i64> = Vec::new();
+
+        for name in names {
+            let child = database::get_chain_for_name(conn, name)?;
+
+            // A chain made of itself would never finish deriving its links.
+            if contains_chain(conn, &child, chain.id)? {
+                return Err(ChainError::new(&format!(
+                    "\"{}\" can't be made of \"{}\"",
+                    chain.name, child.name
+                ))
+                .into());
+            }
+
+            children.push(child.id);
+        }
 
-    let id = database::get_chain_id_for_name(&conn, &name)?;
-    let chain = database::get_chain_for_name(&conn, &name)?;
+        chain.rule = Some(rule);
+        chain.children = children;
+    }
 
-    let link = Link { chain_id: id, date };
+    if chain.rule.is_some() {
+        if chain.kind == Kind::Negative {
+            return Err(ChainError::new("Composite chains can't be negative").into());
+        }
 
-    database::delete_link(&conn, &link)?;
-    printer::print_rm(&chain, &link);
+        if chain.target.is_some() || chain.quota.is_some() {
+            return Err(ChainError::new("Composite chains can't have a target or quota").into());
+        }
+    }
 
     Ok(())
 }
 
-fn add_chain(conn: &Connection, m: &ArgMatches) -> Result<()> {
+fn add_chain(conn: &Connection, m: &ArgMatches, config: &Config) -> Result<()> {
     let name = m.value_of(CHAIN).unwrap();
 
-    let chain = Chain {
+    if database::find_chain_for_name(conn, name)?.is_some() {
+        return Err(ChainError::new(&format!("A chain named \"{}\" already exists", name)).into());
+    }
+
+    let mut chain = Chain {
         id: -1,
         name: name.to_string(),
+        kind: if m.is_present(NEGATIVE) {
+            Kind::Negative
+        } else {
+            Kind::Positive
+        },
+        created: Some(config.today()),
+        ..Default::default()
     };
 
-    database::add_chain(&conn, &chain)?;
+    apply_chain_options(&mut chain, m, config)?;
+    apply_composite_options(conn, &mut chain, m)?;
+
+    database::add_chain(conn, &chain)?;
+
+    if chain.rule.is_some() {
+        let id = database::get_chain_id_for_name(conn, name)?;
+
+        database::set_children(conn, id as i64, &chain.children)?;
+    }
+
     printer::print_add_chain(&chain);
 
     Ok(())
 }
 
+fn edit_chain(conn: &Connection, m: &ArgMatches, config: &Config) -> Result<()> {
+    let name = m.value_of(CHAIN).unwrap();
+
+    let mut chain = database::get_chain_for_name(conn, name)?;
+
+    apply_chain_options(&mut chain, m, config)?;
+    apply_composite_options(conn, &mut chain, m)?;
+
+    database::edit_chain(conn, &chain)?;
+
+    if chain.rule.is_some() {
+        database::set_children(conn, chain.id, &chain.children)?;
+    }
+
+    printer::print_edit_chain(&chain);
+
+    Ok(())
+}
+
+fn archive(conn: &Connection, m: &ArgMatches, archived: bool) -> Result<()> {
+    let name = m.value_of(CHAIN).unwrap();
+
+    let mut chain = database::get_chain_for_name(conn, name)?;
+    chain.archived = archived;
+
+    database::edit_chain(conn, &chain)?;
+    printer::print_archive(&chain);
+
+    Ok(())
+}
+
+fn tag(conn: &Connection, m: &ArgMatches, is_tagged: bool) -> Result<()> {
+    let name = m.value_of(CHAIN).unwrap();
+
+    let chain = database::get_chain_for_name(conn, name)?;
+    let tags: Vec<&str> = m.values_of(TAG_ARG).unwrap().collect();
+
+    for tag in tags.iter() {
+        if tag.is_empty() || tag.contains(',') || tag.contains(char::is_whitespace) {
+            return Err(ChainError::new(&format!("Invalid tag \"{}\"", tag)).into());
+        }
+
+        if is_tagged {
+            database::add_tag(conn, &chain, tag)?;
+        } else {
+            database::delete_tag(conn, &chain, tag)?;
+        }
+    }
+
+    printer::print_tag(&chain, &tags, is_tagged);
+
+    Ok(())
+}
+
+fn alias(conn: &Connection, m: &ArgMatches) -> Result<()> {
+    let alias = match m.value_of(ALIAS_ARG) {
+        Some(alias) => alias,
+        None => {
+            printer::print_aliases(&database::get_aliases(conn)?);
+
+            return Ok(());
+        }
+    };
+
+    // Exact chain names are matched before aliases, an alias with the name
+    // of a chain could never be used.
+    if database::get_chains(conn)?.iter().any(|chain| chain.name == alias) {
+        return Err(ChainError::new(&format!("\"{}\" is already the name of a chain", alias)).into());
+    }
+
+    let name = match m.value_of(CHAIN) {
+        Some(name) => name,
+        None => return Err(ChainError::new("The name of the chain is required").into()),
+    };
+    let chain = database::get_chain_for_name(conn, name)?;
+
+    database::add_alias(conn, &chain, alias)?;
+    printer::print_alias(&chain, alias);
+
+    Ok(())
+}
+
+fn unalias(conn: &Connection, m: &ArgMatches) -> Result<()> {
+    let alias = m.value_of(ALIAS_ARG).unwrap();
+
+    if !database::delete_alias(conn, alias)? {
+        return Err(ChainError::new(&format!("No alias named \"{}\"", alias)).into());
+    }
+
+    printer::print_unalias(alias);
+
+    Ok(())
+}
+
 fn rename_chain(conn: &Connection, m: &ArgMatches) -> Result<()> {
     let current = m.value_of(CURRENT).unwrap();
     let new = m.value_of(NEW).unwrap();
 
-    let chain = Chain {
-        id: -1,
-        name: current.to_string(),
-    };
+    let chain = database::get_chain_for_name(conn, current)?;
 
-    database::edit_chain_for_name(&conn, &chain, &new)?;
-    printer::print_rename_chain(&chain, &new);
+    database::edit_chain_for_name(conn, &chain, new)?;
+    printer::print_rename_chain(&chain, new);
 
     Ok(())
 }
@@ -141,127 +776,1046 @@ fn rename_chain(conn: &Connection, m: &ArgMatches) -> Result<()> {
 fn rm_chain(conn: &Connection, m: &ArgMatches) -> Result<()> {
     let name = m.value_of(CHAIN).unwrap();
 
-    let id = database::get_chain_id_for_name(&conn, &name)?;
-    let chain = database::get_chain_for_name(&conn, &name)?;
+    let id = database::get_chain_id_for_name(conn, name)?;
+    let chain = database::get_chain_for_name(conn, name)?;
 
-    let links = database::get_links_for_chain_id(&conn, id)?;
+    let links = database::get_links_for_chain_id(conn, id)?;
+
+    let message = format!(
+        "Delete \"{}\" and its {} link(s)?",
+        chain.name,
+        links.len()
+    );
+
+    if !confirm(m, &message)? {
+        return Ok(());
+    }
 
     for link in links.iter() {
-        database::delete_link(&conn, &link)?;
+        database::delete_link(conn, link)?;
     }
 
-    database::delete_chain_for_name(&conn, &name)?;
+    database::delete_chain_for_name(conn, &chain.name)?;
     printer::print_rm_chain(&chain);
 
     Ok(())
 }
 
-fn due(conn: &Connection, m: &ArgMatches) -> Result<()> {
-    let chains = database::get_chains(&conn)?;
+/// Get the links of a chain, the links of composite chains are derived from
+/// the links of their chains.
+fn get_links(conn: &Connection, chain: &Chain) -> Result<Vec<Link>> {
+    match chain.rule {
+        Some(rule) => Ok(logic::derived_links(rule, &get_children(conn, chain)?)),
+        None => database::get_links_for_chain_id(conn, chain.id as i32),
+    }
+}
+
+/// Get the chains a composite chain is made of with their links.
+fn get_children(conn: &Connection, chain: &Chain) -> Result<Vec<(Chain, Vec<Link>)>> {
+    let mut children: Vec<(Chain, Vec<Link>)> = Vec::new();
 
-    let mut due: Vec<(Streak, Vec<Day>)> = Vec::new();
+    for child_id in chain.children.iter() {
+        let child = database::get_chain_for_id(conn, *child_id as i32)?;
+        let links = get_links(conn, &child)?;
 
-    for chain in chains.iter() {
-        let id = chain.id;
-        let links = database::get_links_for_chain_id(&conn, id as i32)?;
+        children.push((child, links));
+    }
+
+    Ok(children)
+}
+
+/// Calculate the streak and recent days of a chain, with a breakdown of the
+/// chains of a composite chain.
+fn get_streak(
+    conn: &Connection,
+    chain: &Chain,
+    links: &[Link],
+    config: &Config,
+) -> Result<(Streak, Vec<Day>)> {
+    let mut streak = logic::calculate_streak(chain, links, config.today());
+    let days = logic::create_days(chain, links, config.days, config.today());
+
+    for (child, links) in get_children(conn, chain)? {
+        let child_streak = logic::calculate_streak(&child, &links, config.today());
+        let child_days = logic::create_days(&child, &links, config.days, config.today());
+
+        streak.children.push((child_streak, child_days));
+    }
+
+    Ok((streak, days))
+}
+
+/// Get the chains listed by `ls`, `status` and `due`, archived chains are
+/// only included with `--archived`. With `--tag` only chains with at least
+/// one of the tags are listed.
+fn list_chains(conn: &Connection, m: &ArgMatches) -> Result<Vec<Chain>> {
+    let chains = database::get_chains(conn)?;
+    let tags: Vec<&str> = m.values_of(TAG).map(|tags| tags.collect()).unwrap_or_default();
+
+    Ok(chains
+        .into_iter()
+        .filter(|chain| !chain.archived || m.is_present(ARCHIVED))
+        .filter(|chain| tags.is_empty() || chain.tags.iter().any(|tag| tags.contains(&tag.as_str())))
+        .collect())
+}
+
+/// Group chains by their tags, a chain with several tags is in several
+/// groups.
+fn group_chains(chains: Vec<Chain>) -> Vec<(String, Vec<Chain>)> {
+    let mut tags: Vec<String> = chains.iter().flat_map(|chain| chain.tags.clone()).collect();
+    tags.sort();
+    tags.dedup();
 
-        let latest = links.last();
-        let today = Local::today().naive_local();
+    let mut groups: Vec<(String, Vec<Chain>)> = tags.into_iter().map(|tag| (tag, Vec::new())).collect();
+    let mut untagged: Vec<Chain> = Vec::new();
 
-        if latest.is_some() {
-            // Check if the chain has a link for today.
-            // signed_duration_since will be zero if there is a link for today.
-            if today.signed_duration_since(latest.unwrap().date).num_days() > 0 {
-                let streak = logic::calculate_streak(&chain, &links);
-                let days = logic::create_days(&links);
+    for chain in chains {
+        if chain.tags.is_empty() {
+            untagged.push(chain);
+            continue;
+        }
 
-                due.push((streak, days));
+        for (tag, members) in groups.iter_mut() {
+            if chain.tags.contains(tag) {
+                members.push(chain.clone());
             }
-        } else {
-            let streak = Streak {
-                name: chain.name.to_string(),
-                streak: 0,
-                longest_streak: 0,
-            };
+        }
+    }
+
+    if !untagged.is_empty() {
+        groups.push((String::new(), untagged));
+    }
+
+    groups
+}
+
+/// Check whether a chain still needs to be done today.
+fn is_due(chain: &Chain, links: &[Link], today: NaiveDate) -> bool {
+    // Negative chains are never due, there is nothing to do for them.
+    // Chains past their end date are finished.
+    if chain.kind == Kind::Negative || chain.archived || logic::has_ended(chain, today) {
+        return false;
+    }
+
+    // Check if the chain has a link for today which meets its target,
+    // or today was explicitly marked as failed, skipped or excused.
+    match logic::daily_totals(links).last() {
+        Some(total) => total.date < today || logic::day_state(chain, total) == State::Missed,
+        None => true,
+    }
+}
 
-            let days = logic::create_dummy_days();
+fn due(conn: &Connection, m: &ArgMatches, config: &Config) -> Result<()> {
+    let chains = list_chains(conn, m)?;
 
-            due.push((streak, days));
+    let mut due: Vec<(Streak, Vec<Day>)> = Vec::new();
+
+    for chain in chains.iter() {
+        let links = get_links(conn, chain)?;
+
+        if is_due(chain, &links, config.today()) {
+            due.push(get_streak(conn, chain, &links, config)?);
         }
     }
 
-    if m.is_present(MACHINE) {
+    if m.is_present(MACHINE) || config.is_machine() {
         printer::print_streaks_machine(&due);
     } else {
-        printer::print_streaks(&due);
+        printer::print_streaks(&due, config);
+    }
+
+    Ok(())
+}
+
+fn ls(conn: &Connection, m: &ArgMatches, config: &Config) -> Result<()> {
+    let chains = list_chains(conn, m)?;
+    let all = database::get_chains(conn)?;
+
+    if m.is_present(GROUP) {
+        printer::print_ls_grouped(&group_chains(chains), &all, config);
+    } else {
+        printer::print_ls(&chains, &all, config);
     }
 
     Ok(())
 }
 
-fn ls(conn: &Connection, _m: &ArgMatches) -> Result<()> {
-    let chains = database::get_chains(&conn)?;
+fn tags(conn: &Connection, _m: &ArgMatches) -> Result<()> {
+    let tags = database::get_tags(conn)?;
 
-    printer::print_ls(&chains);
+    printer::print_tags(&tags);
 
     Ok(())
 }
 
-fn status(conn: &Connection, m: &ArgMatches) -> Result<()> {
+fn status(conn: &Connection, m: &ArgMatches, config: &Config) -> Result<()> {
     // print the status of a single chain if the name of the chain is provided.
     if m.is_present(CHAIN) {
         let name = m.value_of(CHAIN).unwrap();
 
-        let id = database::get_chain_id_for_name(&conn, &name)?;
-        let chain = database::get_chain_for_id(&conn, id)?;
-        let links = database::get_links_for_chain_id(&conn, id)?;
+        let id = database::get_chain_id_for_name(conn, name)?;
+        let chain = database::get_chain_for_id(conn, id)?;
+        let links = get_links(conn, &chain)?;
+
+        let (streak, days) = get_streak(conn, &chain, &links, config)?;
+
+        printer::print_streak(&streak, &days, config);
+    } else if m.is_present(GROUP) {
+        // Print an aggregate streak for each tag, a day counts if at least
+        // one of the chains with the tag was done.
+        let mut streaks: Vec<(Streak, Vec<Day>)> = Vec::new();
+
+        for (tag, chains) in group_chains(list_chains(conn, m)?) {
+            if tag.is_empty() {
+                continue;
+            }
+
+            let mut members: Vec<(Chain, Vec<Link>)> = Vec::new();
+
+            for chain in chains {
+                let links = get_links(conn, &chain)?;
+
+                members.push((chain, links));
+            }
+
+            // The group starts with the earliest of its chains.
+            let group = Chain {
+                name: format!("#{}", tag),
+                start: members
+                    .iter()
+                    .filter_map(|(chain, links)| logic::first_day(chain, links))
+                    .min(),
+                ..Default::default()
+            };
+            let links = logic::derived_links(Rule::Any, &members);
 
-        let streak = logic::calculate_streak(&chain, &links);
-        let days = logic::create_days(&links);
+            let streak = logic::calculate_streak(&group, &links, config.today());
+            let days = logic::create_days(&group, &links, config.days, config.today());
 
-        printer::print_streak(&streak, &days);
+            streaks.push((streak, days));
+        }
 
+        printer::print_streaks(&streaks, config);
     } else {
-        let chains = database::get_chains(&conn)?;
+        let chains = list_chains(conn, m)?;
 
         let mut streaks: Vec<(Streak, Vec<Day>)> = Vec::new();
 
         for chain in chains.iter() {
-            let chain_id = chain.id;
-            let links = database::get_links_for_chain_id(&conn, chain_id as i32)?;
+            let links = get_links(conn, chain)?;
 
-            let streak = logic::calculate_streak(&chain, &links);
-            let days = logic::create_days(&links);
-
-            streaks.push((streak, days));
+            streaks.push(get_streak(conn, chain, &links, config)?);
         }
 
-        printer::print_streaks(&streaks);
+        printer::print_streaks(&streaks, config);
     }
 
     Ok(())
 }
 
-fn main() -> Result<()> {
-    let matches = App::new(NAME)
-        .setting(AppSettings::ArgRequiredElseHelp)
-        .version(VERSION)
-        .author(AUTHORS)
-        .about(DESCRIPTION)
-        .subcommand(
+fn log(conn: &Connection, m: &ArgMatches, config: &Config) -> Result<()> {
+    if m.is_present(OPS) {
+        let operations = history::get_operations(conn)?;
+
+        printer::print_operations(&operations);
+
+        return Ok(());
+    }
+
+    let name = m.value_of(CHAIN).unwrap();
+
+    let id = database::get_chain_id_for_name(conn, name)?;
+    let chain = database::get_chain_for_id(conn, id)?;
+    let links = get_links(conn, &chain)?;
+    let notes = database::get_notes_for_chain_id(conn, id)?;
+
+    printer::print_log(&chain, &links, &notes, config);
+
+    Ok(())
+}
+
+fn search(conn: &Connection, m: &ArgMatches, config: &Config) -> Result<()> {
+    let text = m.value_of(TEXT).unwrap();
+
+    let mut results: Vec<(Chain, Note)> = Vec::new();
+
+    for note in database::search_notes(conn, text)? {
+        let chain = database::get_chain_for_id(conn, note.chain_id)?;
+
+        results.push((chain, note));
+    }
+
+    printer::print_search(&results, config);
+
+    Ok(())
+}
+
+/// The metadata options shared by `add-chain` and `edit-chain`.
+fn chain_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name(UNIT)
+            .long("unit")
+            .takes_value(true)
+            .required(false)
+            .help("the unit of the values recorded on the chain"),
+        Arg::with_name(TARGET)
+            .long("target")
+            .takes_value(true)
+            .required(false)
+            .help("the daily total required for a day to count"),
+        Arg::with_name(QUOTA)
+            .long("quota")
+            .takes_value(true)
+            .required(false)
+            .help("the number of check-ins required for a day to count"),
+        Arg::with_name(DESCRIPTION_ARG)
+            .long("desc")
+            .takes_value(true)
+            .required(false)
+            .help("a description of the chain"),
+        Arg::with_name(COLOR)
+            .long("color")
+            .takes_value(true)
+            .required(false)
+            .help("the color used to display the chain"),
+        Arg::with_name(ICON)
+            .long("icon")
+            .takes_value(true)
+            .required(false)
+            .help("an emoji or icon displayed next to the chain"),
+        Arg::with_name(GOAL)
+            .long("goal")
+            .takes_value(true)
+            .required(false)
+            .help("a free-form goal for the chain"),
+        Arg::with_name(START)
+            .long("start")
+            .takes_value(true)
+            .required(false)
+            .help("the date the chain started"),
+        Arg::with_name(END)
+            .long("end")
+            .takes_value(true)
+            .required(false)
+            .help("the last day of the chain, it is no longer due afterwards"),
+        Arg::with_name(ALL)
+            .long("all")
+            .takes_value(true)
+            .multiple(true)
+            .min_values(1)
+            .conflicts_with(ANY)
+            .required(false)
+            .help("make the chain a composite, done on days all of the chains were done"),
+        Arg::with_name(ANY)
+            .long("any")
+            .takes_value(true)
+            .multiple(true)
+            .min_values(1)
+            .required(false)
+            .help("make the chain a composite, done on days any of the chains was done"),
+    ]
+}
+
+fn archived_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name(ARCHIVED)
+        .long("archived")
+        .required(false)
+        .help("include archived chains")
+}
+
+fn undo(conn: &Connection, m: &ArgMatches) -> Result<()> {
+    let count = m.value_of(COUNT).unwrap_or("1").parse::<usize>()?;
+
+    let operations = history::undo(conn, count)?;
+    printer::print_undo(&operations);
+
+    Ok(())
+}
+
+fn redo(conn: &Connection, m: &ArgMatches) -> Result<()> {
+    let count = m.value_of(COUNT).unwrap_or("1").parse::<usize>()?;
+
+    let operations = history::redo(conn, count)?;
+    printer::print_redo(&operations);
+
+    Ok(())
+}
+
+fn sync_token_arg<'a, 'b>(help: &'a str) -> Arg<'a, 'b> {
+    Arg::with_name(TOKEN)
+        .long(TOKEN)
+        .takes_value(true)
+        .env("C_SYNC_TOKEN")
+        .hide_env_values(true)
+        .help(help)
+}
+
+fn yes_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name(YES)
+        .long("yes")
+        .short("y")
+        .required(false)
+        .help("do not ask for confirmation")
+}
+
+fn tag_filter_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name(TAG)
+        .long("tag")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .required(false)
+        .help("only include chains with TAG, can be given several times")
+}
+
+fn format_arg<'a, 'b>(formats: &'a [&'a str]) -> Arg<'a, 'b> {
+    Arg::with_name(FORMAT_ARG)
+        .long("format")
+        .takes_value(true)
+        .possible_values(formats)
+        .default_value("json")
+        .help("the file format")
+}
+
+fn group_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name(GROUP)
+        .long("group")
+        .required(false)
+        .help("group the chains by tag")
+}
+
+/// Whether `--dry-run` was given, before or after any of the subcommands.
+/// A dry run leaves files outside the database untouched too.
+fn is_dry_run(matches: &ArgMatches) -> bool {
+    matches.is_present(DRY_RUN) || matches.subcommand().1.is_some_and(is_dry_run)
+}
+
+/// Get the value of a global flag, which may be given before or after the
+/// subcommand.
+fn global_value<'a>(matches: &'a ArgMatches, name: &str) -> Option<&'a str> {
+    matches
+        .value_of(name)
+        .or_else(|| matches.subcommand().1.and_then(|m| m.value_of(name)))
+}
+
+fn config(path: &Path, mut config: Config, m: &ArgMatches, dry_run: bool) -> Result<()> {
+    match m.subcommand() {
+        (GET, Some(m)) => printer::print_setting(&config.get(m.value_of(KEY).unwrap())?),
+        (SET, Some(m)) => {
+            let key = m.value_of(KEY).unwrap();
+            let value = m.value_of(SETTING).unwrap_or_default();
+
+            config.set(key, value)?;
+
+            if dry_run {
+                printer::print_dry_run_write(path);
+                return Ok(());
+            }
+
+            config::save(path, &config)?;
+
+            printer::print_set_setting(key, value);
+        }
+        _ => printer::print_config(&config.list()),
+    }
+
+    Ok(())
+}
+
+/// The database of a profile. The default profile uses the database set in
+/// the config, the other profiles have their own file.
+fn profile_database(config_path: &Path, config: &Config, profile: &str, dry_run: bool) -> Result<PathBuf> {
+    match (profile, &config.database) {
+        (config::DEFAULT_PROFILE, Some(database)) => Ok(database.clone()),
+        (config::DEFAULT_PROFILE, None) if config.backend == store::TEXT => config::text_path(),
+        (config::DEFAULT_PROFILE, None) => default_database(config_path, dry_run),
+        (profile, _) => config::profile_path(profile, &config.backend),
+    }
+}
+
+/// Find the default database, offering to move a database from `~/.c`,
+/// where it was kept before, to the XDG data directory.
+fn default_database(config_path: &Path, dry_run: bool) -> Result<PathBuf> {
+    let database = config::database_path()?;
+    let legacy = config::legacy_database_path()?;
+
+    if database.exists() || !legacy.exists() {
+        return Ok(database);
+    }
+
+    // Keep using the old database until someone can answer the question, a
+    // dry run neither moves it nor remembers the answer.
+    if !io::stdin().is_terminal() || dry_run {
+        return Ok(legacy);
+    }
+
+    let message = format!(
+        "Found a database at {}, move it to {}?",
+        legacy.display(),
+        database.display()
+    );
+
+    if ask(&message, true)? {
+        if let Some(dir) = database.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        // Renaming fails across file systems, copy the file instead.
+        if fs::rename(&legacy, &database).is_err() {
+            fs::copy(&legacy, &database)?;
+            fs::remove_file(&legacy)?;
+        }
+
+        if let Some(dir) = legacy.parent() {
+            fs::remove_dir(dir).ok();
+        }
+
+        printer::print_moved_database(&legacy, &database);
+
+        Ok(database)
+    } else {
+        // Remember the answer by pointing the config file at the old
+        // database, the overrides from the command line are not saved.
+        let mut config = config::load(config_path)?;
+        config.database = Some(legacy.clone());
+        config::save(config_path, &config)?;
+
+        printer::print_kept_database(&legacy, config_path);
+
+        Ok(legacy)
+    }
+}
+
+fn db(conn: &Connection, m: &ArgMatches, path: &Path, dry_run: bool) -> Result<()> {
+    match m.subcommand() {
+        (CONVERT, Some(m)) => {
+            let backend = m.value_of(BACKEND).unwrap();
+            let target = Path::new(m.value_of(PATH).unwrap());
+
+            let is_empty = match fs::read_dir(target) {
+                Ok(mut entries) => entries.next().is_none(),
+                Err(_) => !target.exists(),
+            };
+            if !is_empty {
+                return Err(ChainError::new(&format!("{} already exists", target.display())).into());
+            }
+
+            if dry_run {
+                printer::print_dry_run_write(target);
+                return Ok(());
+            }
+
+            let chains = database::get_chains(conn)?;
+
+            let store = store::open(backend, target);
+            let mut target_conn = store.open()?;
+            let transaction = target_conn.transaction()?;
+
+            // The copy keeps the uuids, the history and the operation log.
+            database::load(&transaction, &database::dump(conn)?)?;
+            store.save(&transaction)?;
+            transaction.commit()?;
+
+            printer::print_convert(chains.len(), backend, target);
+        }
+        (INFO, Some(_)) | (_, None) => {
+            let info = database::get_info(conn, path)?;
+
+            printer::print_db_info(&info);
+        }
+        _ => return Err(anyhow!("Failed to parse subcommand")),
+    }
+
+    Ok(())
+}
+
+fn export(conn: &Connection, m: &ArgMatches, config: &Config, dry_run: bool) -> Result<()> {
+    // Archived chains are part of the backup too.
+    let tags: Vec<&str> = m.values_of(TAG).map(|tags| tags.collect()).unwrap_or_default();
+    let chains: Vec<Chain> = database::get_chains(conn)?
+        .into_iter()
+        .filter(|chain| tags.is_empty() || chain.tags.iter().any(|tag| tags.contains(&tag.as_str())))
+        .collect();
+    let backup = backup::export(conn, &chains)?;
+
+    let text = match m.value_of(FORMAT_ARG) {
+        Some("csv") => {
+            let mut csv: Vec<u8> = Vec::new();
+            spreadsheet::write(&backup, &mut csv)?;
+
+            String::from_utf8(csv)?
+        }
+        Some("org") => org::write(&backup, config.today()),
+        Some("ics") => {
+            let today = config.today();
+
+            ics::write(&backup, &due_chains(conn, &chains, today)?, m.is_present(RUNS), today)
+        }
+        _ => backup::to_json(&backup)? + "\n",
+    };
+
+    match m.value_of(FILE_OPTION) {
+        Some(path) if dry_run => printer::print_dry_run_write(Path::new(path)),
+        Some(path) => write_file(Path::new(path), &text)?,
+        None => print!("{}", text),
+    }
+
+    Ok(())
+}
+
+/// The names of the chains which are due today.
+fn due_chains(conn: &Connection, chains: &[Chain], today: NaiveDate) -> Result<Vec<String>> {
+    let mut due: Vec<String> = Vec::new();
+
+    for chain in chains.iter() {
+        if is_due(chain, &get_links(conn, chain)?, today) {
+            due.push(chain.name.clone());
+        }
+    }
+
+    Ok(due)
+}
+
+/// Replace a file in one step, so a calendar reading it never sees half of
+/// it.
+fn write_file(path: &Path, text: &str) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        if !dir.as_os_str().is_empty() {
+            fs::create_dir_all(dir)?;
+        }
+    }
+
+    let temporary = path.with_extension("tmp");
+    fs::write(&temporary, text)?;
+    fs::rename(&temporary, path)?;
+
+    Ok(())
+}
+
+/// The calendar feed with every chain, calendar apps subscribed to it pick
+/// up the changes.
+fn ics_feed(conn: &Connection, config: &Config) -> Result<String> {
+    let chains: Vec<Chain> = database::get_chains(conn)?;
+    let backup = backup::export(conn, &chains)?;
+
+    let today = config.today();
+
+    Ok(ics::write(&backup, &due_chains(conn, &chains, today)?, false, today))
+}
+
+/// The number of rows changed on a connection since it was opened.
+fn total_changes(conn: &Connection) -> Result<i64> {
+    Ok(conn.query_row("SELECT total_changes();", [], |row| row.get(0))?)
+}
+
+/// Save the changes of a command and commit them. `changes` is the number of
+/// changes on the connection before the command ran, the calendar feed is
+/// only rewritten when the command changed something.
+fn commit(conn: Transaction, store: &dyn Store, config: &Config, is_recorded: bool, changes: i64) -> Result<()> {
+    let is_changed = total_changes(&conn)? != changes;
+
+    if is_recorded {
+        history::end_operation(&conn)?;
+    }
+
+    // Every change becomes an operation other devices can replay.
+    oplog::record(&conn)?;
+
+    if is_changed {
+        store.save(&conn)?;
+    }
+
+    let feed = match &config.ics_feed {
+        Some(path) if is_changed => Some((path, ics_feed(&conn, config)?)),
+        _ => None,
+    };
+
+    conn.commit()?;
+
+    // Publish the feed once the changes it shows are saved.
+    if let Some((path, feed)) = feed {
+        write_file(path, &feed)?;
+    }
+
+    Ok(())
+}
+
+fn import(conn: &Connection, m: &ArgMatches, config: &Config) -> Result<()> {
+    let file = m.value_of(FILE).unwrap();
+
+    let read = || -> Result<String> {
+        if file == "-" {
+            Ok(io::read_to_string(io::stdin())?)
+        } else {
+            Ok(fs::read_to_string(file)?)
+        }
+    };
+
+    let mut backup = match m.value_of(FORMAT_ARG) {
+        Some("loop") => habit_apps::read_loop(Path::new(file))?,
+        Some("habitica") => habit_apps::read_habitica(&read()?)?,
+        Some("habitbull") => habit_apps::read_habitbull(&mut read()?.as_bytes())?,
+        Some("org") => org::read(&read()?)?,
+        Some("csv") => {
+            let mappings: Vec<&str> = m.values_of(MAP).map(|mappings| mappings.collect()).unwrap_or_default();
+            let columns = spreadsheet::Columns::parse(&mappings)?;
+
+            spreadsheet::read(
+                &mut read()?.as_bytes(),
+                &columns,
+                m.value_of(INPUT_DATE_FORMAT).unwrap(),
+                m.value_of(CHAIN_OPTION),
+            )?
+        }
+        _ => backup::from_json(&read()?)?,
+    };
+
+    // Chains from other apps are created today, a backup keeps its dates.
+    if m.value_of(FORMAT_ARG).is_some_and(|format| format != "json") {
+        for chain in backup.chains.iter_mut() {
+            chain.created.get_or_insert(config.today());
+        }
+    }
+
+    let replace = m.is_present(REPLACE);
+
+    if replace {
+        let message = format!(
+            "Replace every chain with the {} chain(s) in {}?",
+            backup.chains.len(),
+            file
+        );
+
+        if !confirm(m, &message)? {
+            return Ok(());
+        }
+    }
+
+    let summary = backup::import(conn, &backup, replace)?;
+
+    printer::print_import(&summary);
+
+    Ok(())
+}
+
+fn sync(conn: &Connection, m: &ArgMatches, config: &Config, database: &Path) -> Result<()> {
+    match m.subcommand() {
+        (MERGE, Some(m)) => {
+            let path = Path::new(m.value_of(FILE).unwrap());
+
+            if path.canonicalize().ok() == database.canonicalize().ok() {
+                return Err(ChainError::new(&format!("{} is the database in use", path.display())).into());
+            }
+
+            let summary = sync::merge_file(conn, path)?;
+
+            printer::print_sync(path, &summary);
+        }
+        (DIR, Some(m)) => {
+            let dir = sync_dir(m, config)?;
+            // A dry run only reads the folder.
+            let (received, sent) = oplog::sync_dir(conn, &dir, !m.is_present(DRY_RUN))?;
+
+            printer::print_exchange(&dir.display().to_string(), received, sent);
+        }
+        (PEER, Some(m)) => {
+            let address = m.value_of(ADDRESS).unwrap();
+            // A dry run sends the peer nothing to save.
+            let (received, sent) = oplog::sync_peer(conn, address, m.value_of(TOKEN), !m.is_present(DRY_RUN))?;
+
+            printer::print_exchange(address, received, sent);
+        }
+        _ => return Err(anyhow!("Failed to parse subcommand")),
+    }
+
+    Ok(())
+}
+
+/// Exchange operations with every device connecting to `address` until
+/// stopped, each exchange opens the store and is saved on its own, like the
+/// requests of the API server.
+fn sync_serve(store: &dyn Store, m: &ArgMatches, config: &Config) -> Result<()> {
+    let address = m.value_of(LISTEN).unwrap();
+    let token = m.value_of(TOKEN);
+    let listener = std::net::TcpListener::bind(address)?;
+
+    printer::print_listening(&listener.local_addr()?.to_string());
+
+    // Anyone who can connect could change every chain.
+    if token.is_none() && !listener.local_addr()?.ip().is_loopback() {
+        printer::print_no_token();
+    }
+
+    for stream in listener.incoming() {
+        // A connection which failed while it was accepted doesn't stop the
+        // server.
+        let (stream, peer) = match stream.and_then(|stream| stream.peer_addr().map(|peer| (stream, peer))) {
+            Ok((stream, peer)) => (stream, peer.to_string()),
+            Err(err) => {
+                eprintln!("Failed to accept a connection: {}", err);
+                continue;
+            }
+        };
+
+        let exchange = || -> Result<(usize, usize)> {
+            let mut conn = store.open()?;
+            let transaction = conn.transaction()?;
+            let changes = total_changes(&transaction)?;
+
+            history::begin_operation(&transaction, &format!("sync serve {}", peer))?;
+            let counts = oplog::serve_peer(&transaction, stream, token)?;
+            commit(transaction, store, config, true, changes)?;
+
+            Ok(counts)
+        };
+
+        match exchange() {
+            Ok((received, sent)) => printer::print_exchange(&peer, received, sent),
+            Err(err) => eprintln!("Failed to sync with {}: {}", peer, err),
+        }
+    }
+
+    Ok(())
+}
+
+/// The sync folder given on the command line or set in the config.
+fn sync_dir(m: &ArgMatches, config: &Config) -> Result<PathBuf> {
+    match (m.value_of(PATH), &config.sync_dir) {
+        (Some(path), _) => Ok(PathBuf::from(path)),
+        (None, Some(path)) => Ok(path.clone()),
+        (None, None) => Err(ChainError::new("No sync folder given, set one with: c config set sync_dir PATH").into()),
+    }
+}
+
+fn profile(config_path: &Path, config: &Config, m: &ArgMatches, dry_run: bool) -> Result<()> {
+    match m.subcommand() {
+        (CREATE, Some(m)) => {
+            let name = m.value_of(PROFILE_ARG).unwrap();
+            let path = profile_database(config_path, config, name, dry_run)?;
+
+            if path.exists() {
+                return Err(ChainError::new(&format!("The profile \"{}\" already exists", name)).into());
+            }
+
+            if dry_run {
+                printer::print_dry_run_write(&path);
+                return Ok(());
+            }
+
+            store::open(&config.backend, &path).open()?;
+            printer::print_create_profile(name, &path);
+        }
+        (USE, Some(m)) => {
+            let name = m.value_of(PROFILE_ARG).unwrap();
+
+            if !config::profiles()?.iter().any(|profile| profile == name) {
+                return Err(ChainError::new(&format!("No profile named \"{}\"", name)).into());
+            }
+
+            if dry_run {
+                printer::print_dry_run_write(config_path);
+                return Ok(());
+            }
+
+            // Only the file is changed, not the overrides from the command line.
+            let mut file = config::load(config_path)?;
+            file.set(config::PROFILE, name)?;
+            config::save(config_path, &file)?;
+
+            printer::print_use_profile(name);
+        }
+        _ => printer::print_profiles(&config::profiles()?, config.profile()),
+    }
+
+    Ok(())
+}
+
+/// Print the status of every profile, each from its own database.
+fn status_all_profiles(config_path: &Path, m: &ArgMatches, config: &Config, dry_run: bool) -> Result<()> {
+    for profile in config::profiles()? {
+        let path = profile_database(config_path, config, &profile, dry_run)?;
+
+        if !path.exists() {
+            continue;
+        }
+
+        let conn = store::open(&config.backend, &path).open()?;
+
+        printer::print_profile_header(&profile);
+        status(&conn, m, config)?;
+    }
+
+    Ok(())
+}
+
+fn completions(m: &ArgMatches) -> Result<()> {
+    let shell = m.value_of(SHELL).unwrap().parse::<Shell>().map_err(|err| anyhow!(err))?;
+
+    completions::generate(build_app(), shell, &mut io::stdout())
+}
+
+fn complete(conn: &Connection, m: &ArgMatches) -> Result<()> {
+    let candidates: Vec<String> = match m.value_of(KIND).unwrap() {
+        CHAINS => database::get_chains(conn)?
+            .into_iter()
+            .map(|chain| chain.name)
+            .chain(database::get_aliases(conn)?.into_iter().map(|(alias, _)| alias))
+            .collect(),
+        _ => vec!["today".to_string(), "yesterday".to_string()],
+    };
+
+    printer::print_candidates(&candidates);
+
+    Ok(())
+}
+
+fn build_app() -> App<'static, 'static> {
+    let mut app = App::new(NAME)
+        .setting(AppSettings::ArgRequiredElseHelp)
+        .version(VERSION)
+        .author(AUTHORS)
+        .about(DESCRIPTION)
+        .arg(
+            Arg::with_name(CONFIG_FILE)
+                .long("config")
+                .takes_value(true)
+                .global(true)
+                .required(false)
+                .help("the config file to use instead of $XDG_CONFIG_HOME/chain/config.toml"),
+        )
+        .arg(
+            Arg::with_name(DRY_RUN)
+                .long("dry-run")
+                .global(true)
+                .required(false)
+                .help("print what would change without saving anything"),
+        )
+        .subcommand(
             SubCommand::with_name(ADD)
                 .about("add a link to CHAIN.")
                 .arg(
-                    Arg::with_name("CHAIN")
+                    Arg::with_name("CHAIN")
+                        .required(true)
+                        .index(1)
+                        .help("the name of the chain"),
+                )
+                .arg(
+                    Arg::with_name("DATE")
+                        .required(false)
+                        .index(2)
+                        .help("the date to add"),
+                )
+                .arg(
+                    Arg::with_name(VALUE)
+                        .long("value")
+                        .takes_value(true)
+                        .required(false)
+                        .help("the amount to record, e.g. pages read or minutes practised"),
+                )
+                .arg(
+                    Arg::with_name(NOTE)
+                        .long("note")
+                        .takes_value(true)
+                        .required(false)
+                        .help("a note to attach to the link"),
+                )
+                .arg(
+                    Arg::with_name(TIME)
+                        .long("time")
+                        .takes_value(true)
+                        .required(false)
+                        .help("the time of the check-in as HH:MM"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(FAIL)
+                .about("mark DATE of CHAIN as failed.")
+                .arg(
+                    Arg::with_name(CHAIN)
+                        .required(true)
+                        .index(1)
+                        .help("the name of the chain"),
+                )
+                .arg(
+                    Arg::with_name(DATE)
+                        .required(false)
+                        .index(2)
+                        .help("the date to mark"),
+                )
+                .arg(
+                    Arg::with_name(REASON)
+                        .long("reason")
+                        .takes_value(true)
+                        .required(false)
+                        .help("the reason for the mark"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(SKIP)
+                .about("mark DATE of CHAIN as skipped, without breaking the streak.")
+                .arg(
+                    Arg::with_name(CHAIN)
                         .required(true)
                         .index(1)
                         .help("the name of the chain"),
                 )
                 .arg(
-                    Arg::with_name("DATE")
+                    Arg::with_name(DATE)
                         .required(false)
                         .index(2)
-                        .help("the date to add"),
+                        .help("the date to mark"),
+                )
+                .arg(
+                    Arg::with_name(REASON)
+                        .long("reason")
+                        .takes_value(true)
+                        .required(false)
+                        .help("the reason for the mark"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(EXCUSE)
+                .about("mark DATE of CHAIN as excused, without breaking the streak.")
+                .arg(
+                    Arg::with_name(CHAIN)
+                        .required(true)
+                        .index(1)
+                        .help("the name of the chain"),
+                )
+                .arg(
+                    Arg::with_name(DATE)
+                        .required(false)
+                        .index(2)
+                        .help("the date to mark"),
+                )
+                .arg(
+                    Arg::with_name(REASON)
+                        .long("reason")
+                        .takes_value(true)
+                        .required(false)
+                        .help("the reason for the mark"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(NOTE)
+                .about("attach a note to the link on DATE of CHAIN.")
+                .arg(
+                    Arg::with_name(CHAIN)
+                        .required(true)
+                        .index(1)
+                        .help("the name of the chain"),
+                )
+                .arg(
+                    Arg::with_name(DATE)
+                        .required(true)
+                        .index(2)
+                        .help("the date of the link"),
+                )
+                .arg(
+                    Arg::with_name(TEXT)
+                        .required(true)
+                        .index(3)
+                        .help("the text of the note"),
                 ),
         )
         .subcommand(
@@ -300,7 +1854,15 @@ fn main() -> Result<()> {
                         .required(true)
                         .index(2)
                         .help("the date to delete"),
-                ),
+                )
+                .arg(
+                    Arg::with_name(TIME)
+                        .long("time")
+                        .takes_value(true)
+                        .required(false)
+                        .help("only delete the check-in at HH:MM"),
+                )
+                .arg(yes_arg()),
         )
         .subcommand(
             SubCommand::with_name(ADD_CHAIN)
@@ -310,6 +1872,104 @@ fn main() -> Result<()> {
                         .index(1)
                         .required(true)
                         .help("the name of the chain"),
+                )
+                .args(&chain_args())
+                .arg(
+                    Arg::with_name(NEGATIVE)
+                        .long("negative")
+                        .required(false)
+                        .help("track days since the last relapse, links are relapses"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(EDIT_CHAIN)
+                .about("change the metadata of CHAIN, an empty value clears a setting.")
+                .arg(
+                    Arg::with_name(CHAIN)
+                        .required(true)
+                        .index(1)
+                        .help("the name of the chain"),
+                )
+                .args(&chain_args()),
+        )
+        .subcommand(
+            SubCommand::with_name(ARCHIVE)
+                .about("hide CHAIN from ls, status and due, keeping its history.")
+                .arg(
+                    Arg::with_name(CHAIN)
+                        .required(true)
+                        .index(1)
+                        .help("the name of the chain"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(UNARCHIVE)
+                .about("restore an archived CHAIN.")
+                .arg(
+                    Arg::with_name(CHAIN)
+                        .required(true)
+                        .index(1)
+                        .help("the name of the chain"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(TAG)
+                .about("add TAGS to CHAIN.")
+                .arg(
+                    Arg::with_name(CHAIN)
+                        .required(true)
+                        .index(1)
+                        .help("the name of the chain"),
+                )
+                .arg(
+                    Arg::with_name(TAG_ARG)
+                        .required(true)
+                        .multiple(true)
+                        .index(2)
+                        .help("the tags to add"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(UNTAG)
+                .about("remove TAGS from CHAIN.")
+                .arg(
+                    Arg::with_name(CHAIN)
+                        .required(true)
+                        .index(1)
+                        .help("the name of the chain"),
+                )
+                .arg(
+                    Arg::with_name(TAG_ARG)
+                        .required(true)
+                        .multiple(true)
+                        .index(2)
+                        .help("the tags to remove"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(ALIAS)
+                .about("make ALIAS refer to CHAIN, lists the aliases without arguments.")
+                .arg(
+                    Arg::with_name(ALIAS_ARG)
+                        .required(false)
+                        .index(1)
+                        .help("the alias"),
+                )
+                .arg(
+                    Arg::with_name(CHAIN)
+                        .required(false)
+                        .index(2)
+                        .help("the name of the chain"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(UNALIAS)
+                .about("remove ALIAS.")
+                .arg(
+                    Arg::with_name(ALIAS_ARG)
+                        .required(true)
+                        .index(1)
+                        .help("the alias"),
                 ),
         )
         .subcommand(
@@ -329,12 +1989,15 @@ fn main() -> Result<()> {
                 ),
         )
         .subcommand(
-            SubCommand::with_name(RM_CHAIN).about("delete CHAIN.").arg(
-                Arg::with_name("CHAIN")
-                    .required(true)
-                    .index(1)
-                    .help("the name of the chain"),
-            ),
+            SubCommand::with_name(RM_CHAIN)
+                .about("delete CHAIN.")
+                .arg(
+                    Arg::with_name("CHAIN")
+                        .required(true)
+                        .index(1)
+                        .help("the name of the chain"),
+                )
+                .arg(yes_arg()),
         )
         .subcommand(
             SubCommand::with_name(DUE)
@@ -345,9 +2008,18 @@ fn main() -> Result<()> {
                         .short("m")
                         .required(false)
                         .help("provide output in a machine readable format"),
-                ),
+                )
+                .arg(archived_arg())
+                .arg(tag_filter_arg()),
         )
-        .subcommand(SubCommand::with_name(LS).about("list all CHAINS."))
+        .subcommand(
+            SubCommand::with_name(LS)
+                .about("list all CHAINS.")
+                .arg(archived_arg())
+                .arg(tag_filter_arg())
+                .arg(group_arg()),
+        )
+        .subcommand(SubCommand::with_name(TAGS).about("list all tags."))
         .subcommand(
             SubCommand::with_name(STATUS)
                 .about("print the status of CHAIN or all CHAINS.")
@@ -356,36 +2028,505 @@ fn main() -> Result<()> {
                         .required(false)
                         .index(1)
                         .help("the name of the chain"),
+                )
+                .arg(archived_arg())
+                .arg(tag_filter_arg())
+                .arg(
+                    Arg::with_name(GROUP)
+                        .long("group")
+                        .required(false)
+                        .help("print a streak for each tag, counting days on which any chain was done"),
+                )
+                .arg(
+                    Arg::with_name(ALL_PROFILES)
+                        .long(ALL_PROFILES)
+                        .required(false)
+                        .conflicts_with("CHAIN")
+                        .help("print the status of every profile"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(LOG)
+                .about("print the links and notes of CHAIN, or every change with --ops.")
+                .arg(
+                    Arg::with_name(CHAIN)
+                        .required_unless(OPS)
+                        .index(1)
+                        .help("the name of the chain"),
+                )
+                .arg(
+                    Arg::with_name(OPS)
+                        .long("ops")
+                        .required(false)
+                        .help("print who changed what and when"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(SEARCH)
+                .about("search the notes of all CHAINS for TEXT.")
+                .arg(
+                    Arg::with_name(TEXT)
+                        .required(true)
+                        .index(1)
+                        .help("the text to search for"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(UNDO)
+                .about("undo the last COUNT changes.")
+                .arg(
+                    Arg::with_name(COUNT)
+                        .required(false)
+                        .index(1)
+                        .help("the number of changes to undo, defaults to 1"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(REDO)
+                .about("redo the last COUNT undone changes.")
+                .arg(
+                    Arg::with_name(COUNT)
+                        .required(false)
+                        .index(1)
+                        .help("the number of changes to redo, defaults to 1"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(DB)
+                .about("manage the database.")
+                .subcommand(
+                    SubCommand::with_name(INFO).about("print the path and contents of the database."),
+                )
+                .subcommand(
+                    SubCommand::with_name(CONVERT)
+                        .about(
+                            "copy every chain with its history to a new store of another backend, e.g. text files to \
+                             keep in git.",
+                        )
+                        .arg(
+                            Arg::with_name(BACKEND)
+                                .required(true)
+                                .index(1)
+                                .possible_values(store::BACKENDS)
+                                .help("the backend to convert to"),
+                        )
+                        .arg(
+                            Arg::with_name(PATH)
+                                .required(true)
+                                .index(2)
+                                .help("the database file or directory to create"),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(EXPORT)
+                .about("export chains with their links and notes, e.g. to back them up.")
+                .arg(format_arg(EXPORT_FORMATS))
+                .arg(tag_filter_arg())
+                .arg(
+                    Arg::with_name(RUNS)
+                        .long(RUNS)
+                        .required(false)
+                        .help("export an ics event for every run of consecutive days instead of every day"),
+                )
+                .arg(
+                    Arg::with_name(FILE_OPTION)
+                        .long(FILE_OPTION)
+                        .takes_value(true)
+                        .value_name("PATH")
+                        .help("write to PATH instead of stdout, e.g. a calendar feed to subscribe to"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(SYNC)
+                .about("merge the chains of another device without losing changes on either.")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name(MERGE)
+                        .about("merge another database into this one, e.g. a copy from another device.")
+                        .arg(
+                            Arg::with_name(FILE)
+                                .required(true)
+                                .index(1)
+                                .help("the database to merge, it is left unchanged"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name(DIR)
+                        .about(
+                            "exchange changes with the other devices through a shared folder, e.g. a Syncthing or \
+                             Nextcloud folder.",
+                        )
+                        .arg(
+                            Arg::with_name(PATH)
+                                .required(false)
+                                .index(1)
+                                .help("the shared folder, defaults to the sync_dir setting"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name(SERVE)
+                        .about("exchange changes with every device running sync peer, until stopped.")
+                        .arg(
+                            Arg::with_name(LISTEN)
+                                .long(LISTEN)
+                                .takes_value(true)
+                                .default_value("127.0.0.1:7879")
+                                .help("the address to listen on, e.g. 0.0.0.0:7879 with a --token for the local network"),
+                        )
+                        .arg(sync_token_arg("a token every device has to send, set the same token on sync peer")),
+                )
+                .subcommand(
+                    SubCommand::with_name(PEER)
+                        .about("exchange changes with a device running sync serve.")
+                        .arg(
+                            Arg::with_name(ADDRESS)
+                                .required(true)
+                                .index(1)
+                                .help("the address of the device, e.g. desktop.local:7879"),
+                        )
+                        .arg(sync_token_arg("the token the device was started with")),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(SERVE)
+                .about("answer requests to a JSON API for the chains until stopped, e.g. for a web page or phone shortcuts.")
+                .arg(
+                    Arg::with_name(LISTEN)
+                        .long(LISTEN)
+                        .takes_value(true)
+                        .default_value("127.0.0.1:7878")
+                        .help("the address to listen on, e.g. 0.0.0.0:7878 for the local network"),
+                )
+                .arg(
+                    Arg::with_name(TOKEN)
+                        .long(TOKEN)
+                        .takes_value(true)
+                        .env("C_API_TOKEN")
+                        .hide_env_values(true)
+                        .help("a token every request needs in an \"Authorization: Bearer\" header"),
+                )
+                .arg(
+                    Arg::with_name(VERBOSE)
+                        .long(VERBOSE)
+                        .help("print every request with the status it was answered with"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(IMPORT)
+                .about("import chains with their links and notes, merged into the existing chains, missing chains are created.")
+                .arg(
+                    Arg::with_name(FILE)
+                        .required(true)
+                        .index(1)
+                        .help(
+                            "the file to import, - reads from stdin. Loop Habit Tracker backups are either \
+                             the database or the directory of the unzipped csv export",
+                        ),
+                )
+                .arg(format_arg(IMPORT_FORMATS))
+                .arg(
+                    Arg::with_name(MAP)
+                        .long("map")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("FIELD=COLUMN")
+                        .help(
+                            "read a field from another csv column, the fields are chain, date, time, value, \
+                             state, reason and note",
+                        ),
+                )
+                .arg(
+                    Arg::with_name(INPUT_DATE_FORMAT)
+                        .long(INPUT_DATE_FORMAT)
+                        .takes_value(true)
+                        .default_value(FORMAT)
+                        .help("the format of the dates in a csv file"),
+                )
+                .arg(
+                    Arg::with_name(CHAIN_OPTION)
+                        .long(CHAIN_OPTION)
+                        .takes_value(true)
+                        .help("the chain of csv rows without a chain column"),
+                )
+                .arg(
+                    Arg::with_name(REPLACE)
+                        .long(REPLACE)
+                        .required(false)
+                        .help("delete every chain before importing"),
+                )
+                .arg(yes_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name(PROFILE)
+                .about("manage profiles, separate trackers with their own databases.")
+                .subcommand(SubCommand::with_name(LIST).about("list every profile."))
+                .subcommand(
+                    SubCommand::with_name(CREATE).about("create a new profile.").arg(
+                        Arg::with_name(PROFILE_ARG)
+                            .required(true)
+                            .index(1)
+                            .help("the name of the profile"),
+                    ),
+                )
+                .subcommand(
+                    SubCommand::with_name(USE)
+                        .about("switch to PROFILE, a single command can use --profile instead.")
+                        .arg(
+                            Arg::with_name(PROFILE_ARG)
+                                .required(true)
+                                .index(1)
+                                .help("the name of the profile"),
+                        ),
                 ),
         )
-        .get_matches();
+        .subcommand(
+            SubCommand::with_name(CONFIG)
+                .about("show or change the settings in the config file.")
+                .subcommand(SubCommand::with_name(LIST).about("list every setting."))
+                .subcommand(
+                    SubCommand::with_name(GET).about("print a setting.").arg(
+                        Arg::with_name(KEY)
+                            .required(true)
+                            .index(1)
+                            .possible_values(config::KEYS)
+                            .help("the setting"),
+                    ),
+                )
+                .subcommand(
+                    SubCommand::with_name(SET)
+                        .about("change a setting, an empty VALUE unsets it.")
+                        .arg(
+                            Arg::with_name(KEY)
+                                .required(true)
+                                .index(1)
+                                .possible_values(config::KEYS)
+                                .help("the setting"),
+                        )
+                        .arg(
+                            Arg::with_name(SETTING)
+                                .required(false)
+                                .index(2)
+                                .help("the new value"),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(COMPLETIONS)
+                .about("print a completion script for SHELL.")
+                .arg(
+                    Arg::with_name(SHELL)
+                        .required(true)
+                        .index(1)
+                        .possible_values(&Shell::variants())
+                        .help("the shell to complete in"),
+                ),
+        );
+
+    let overrides = [
+        "the database file, or directory with the text backend",
+        "how chains are stored: sqlite or text",
+        "the profile to use, each profile has its own database",
+        "the format dates are displayed in",
+        "the first day of the week, separates weeks in the day strip",
+        "the number of days in the day strip",
+        "the color theme: default or plain",
+        "the hour a new day starts at",
+        "the output format: text or machine",
+    ];
+
+    for ((name, _), help) in OVERRIDES.iter().zip(overrides.iter()) {
+        app = app.arg(
+            Arg::with_name(name)
+                .long(name)
+                .takes_value(true)
+                .global(true)
+                .required(false)
+                .help(help),
+        );
+    }
+
+    app
+}
+
+/// The hidden command the completion scripts call to complete chain names
+/// and dates. It is left out of the generated scripts themselves, clap's
+/// bash generator can't handle subcommand names containing `__`.
+fn complete_subcommand() -> App<'static, 'static> {
+    SubCommand::with_name(COMPLETE)
+        .setting(AppSettings::Hidden)
+        .about("print the candidates for a completion, used by the completion scripts.")
+        .arg(
+            Arg::with_name(KIND)
+                .required(true)
+                .index(1)
+                .possible_values(&[CHAINS, DATES]),
+        )
+}
+
+fn main() -> Result<()> {
+    let matches = build_app().subcommand(complete_subcommand()).get_matches();
+
+    // Completion scripts are generated without touching the database.
+    if let (COMPLETIONS, Some(m)) = matches.subcommand() {
+        completions(m)?;
+
+        return Ok(());
+    }
+
+    // Load the config, flags on the command line override the file.
+    let config_path = match global_value(&matches, CONFIG_FILE) {
+        Some(path) => PathBuf::from(path),
+        None => config::config_path()?,
+    };
+    let mut config = config::load(&config_path)?;
+    let dry_run = is_dry_run(&matches);
+
+    if let (CONFIG, Some(m)) = matches.subcommand() {
+        return self::config(&config_path, config, m, dry_run);
+    }
+
+    for (name, key) in OVERRIDES.iter() {
+        if let Some(value) = global_value(&matches, name) {
+            config.set(key, value)?;
+        }
+    }
+
+    // A database given on the command line wins over the profile.
+    if global_value(&matches, "database").is_some() {
+        config.profile = None;
+    }
+
+    if let (PROFILE, Some(m)) = matches.subcommand() {
+        return profile(&config_path, &config, m, dry_run);
+    }
+
+    if let (STATUS, Some(m)) = matches.subcommand() {
+        if m.is_present(ALL_PROFILES) {
+            return status_all_profiles(&config_path, m, &config, dry_run);
+        }
+    }
+
+    // The servers save every request and exchange as it comes in, there is
+    // nothing to roll back.
+    let is_server = match matches.subcommand() {
+        (SERVE, _) => true,
+        (SYNC, Some(m)) => m.subcommand_name() == Some(SERVE),
+        _ => false,
+    };
+
+    if dry_run && is_server {
+        return Err(ChainError::new("A server can't do a dry run, it saves every change it receives").into());
+    }
 
     // Setup the database
-    let home_dir = dirs::home_dir().ok_or(anyhow!("Failed to locate the users home directory"))?;
-    let config_dir = home_dir.join(".c");
-    let database = config_dir.join("c.db");
+    let database = profile_database(&config_path, &config, config.profile(), dry_run)?;
+
+    let store = store::open(&config.backend, &database);
+
+    // The servers open the store for every request and exchange, so they
+    // never save over changes made by other commands in the meantime.
+    if let (SERVE, Some(m)) = matches.subcommand() {
+        return server::serve(
+            &*store,
+            &config,
+            m.value_of(LISTEN).unwrap(),
+            m.value_of(TOKEN),
+            m.is_present(VERBOSE),
+        );
+    }
 
-    if !config_dir.exists() {
-        fs::create_dir(config_dir)?;
+    if let (SYNC, Some(m)) = matches.subcommand() {
+        if let (SERVE, Some(m)) = m.subcommand() {
+            return sync_serve(&*store, m, &config);
+        }
     }
 
-    let conn = Connection::open(database)?;
+    let mut conn = store.open()?;
+
+    // Every command runs in a single transaction. The changes made by
+    // commands which modify chains or links are recorded so they can be
+    // undone.
+    let conn = conn.transaction()?;
+    let changes = total_changes(&conn)?;
+
+    let is_recorded = matches!(
+        matches.subcommand_name(),
+        Some(
+            ADD | MV
+                | RM
+                | FAIL
+                | SKIP
+                | EXCUSE
+                | NOTE
+                | ADD_CHAIN
+                | EDIT_CHAIN
+                | ARCHIVE
+                | UNARCHIVE
+                | TAG
+                | UNTAG
+                | ALIAS
+                | UNALIAS
+                | RENAME_CHAIN
+                | RM_CHAIN
+                | IMPORT
+                | SYNC
+        )
+    );
+
+    if is_recorded {
+        let command: Vec<String> = std::env::args().skip(1).collect();
 
-    database::setup_tables(&conn)?;
+        history::begin_operation(&conn, &command.join(" "))?;
+    }
 
     // Run subcommand
     match matches.subcommand() {
-        (ADD, Some(m)) => add(&conn, m)?,
-        (MV, Some(m)) => mv(&conn, m)?,
-        (RM, Some(m)) => rm(&conn, m)?,
-        (ADD_CHAIN, Some(m)) => add_chain(&conn, m)?,
+        (ADD, Some(m)) => add(&conn, m, &config)?,
+        (MV, Some(m)) => mv(&conn, m, &config)?,
+        (RM, Some(m)) => rm(&conn, m, &config)?,
+        (FAIL, Some(m)) => mark(&conn, m, State::Failed, &config)?,
+        (SKIP, Some(m)) => mark(&conn, m, State::Skipped, &config)?,
+        (EXCUSE, Some(m)) => mark(&conn, m, State::Excused, &config)?,
+        (NOTE, Some(m)) => note(&conn, m, &config)?,
+        (ADD_CHAIN, Some(m)) => add_chain(&conn, m, &config)?,
+        (EDIT_CHAIN, Some(m)) => edit_chain(&conn, m, &config)?,
+        (ARCHIVE, Some(m)) => archive(&conn, m, true)?,
+        (UNARCHIVE, Some(m)) => archive(&conn, m, false)?,
+        (TAG, Some(m)) => tag(&conn, m, true)?,
+        (UNTAG, Some(m)) => tag(&conn, m, false)?,
+        (ALIAS, Some(m)) => alias(&conn, m)?,
+        (UNALIAS, Some(m)) => unalias(&conn, m)?,
         (RENAME_CHAIN, Some(m)) => rename_chain(&conn, m)?,
         (RM_CHAIN, Some(m)) => rm_chain(&conn, m)?,
-        (DUE, Some(m)) => due(&conn, m)?,
-        (LS, Some(m)) => ls(&conn, m)?,
-        (STATUS, Some(m)) => status(&conn, m)?,
+        (DUE, Some(m)) => due(&conn, m, &config)?,
+        (LS, Some(m)) => ls(&conn, m, &config)?,
+        (STATUS, Some(m)) => status(&conn, m, &config)?,
+        (TAGS, Some(m)) => tags(&conn, m)?,
+        (LOG, Some(m)) => log(&conn, m, &config)?,
+        (SEARCH, Some(m)) => search(&conn, m, &config)?,
+        (UNDO, Some(m)) => undo(&conn, m)?,
+        (REDO, Some(m)) => redo(&conn, m)?,
+        (DB, Some(m)) => db(&conn, m, &database, dry_run)?,
+        (EXPORT, Some(m)) => export(&conn, m, &config, dry_run)?,
+        (IMPORT, Some(m)) => import(&conn, m, &config)?,
+        (SYNC, Some(m)) => sync(&conn, m, &config, &database)?,
+        (COMPLETE, Some(m)) => complete(&conn, m)?,
         _ => return Err(anyhow!("Failed to parse subcommand")),
     };
 
+    // Roll back everything a dry run did, after printing what it changed.
+    if dry_run {
+        let changes = history::get_recorded_changes(&conn)?;
+
+        printer::print_dry_run(&changes);
+        conn.rollback()?;
+
+        return Ok(());
+    }
+
+    commit(conn, &*store, &config, is_recorded, changes)?;
+
     Ok(())
 }
@@ -0,0 +1,1238 @@
+use super::backup::{self, BackupChain, BackupLink, BackupNote};
+use super::chain_error::ChainError;
+use super::database;
+use super::sync;
+use super::{Link, State};
+use anyhow::Result;
+use chrono::{NaiveDate, NaiveTime, Utc};
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::time::Duration;
+
+use super::{FORMAT, TIME_FORMAT};
+
+/// The extension of the operation files in a sync folder.
+pub const EXTENSION: &str = "ops";
+
+/// A change to a chain, link or note, as recorded on the device it was made
+/// on.
+///
+/// Operations are ordered by their hybrid logical clock, the time of the
+/// change in milliseconds, a counter for changes within the same
+/// millisecond and the device, e.g. `1760000000000-00000-9f86d081884c7d65`.
+/// The clock never goes back, even when the clock of a device is behind the
+/// clocks of the devices it synced with, so replaying the same operations
+/// in clock order gives the same chains on every device.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Op {
+    pub hlc: String,
+    pub device: String,
+    pub table: String,
+    pub uuid: String,
+    /// The row after the change, `None` when it was deleted.
+    pub row: Option<Value>,
+}
+
+/// A chain with the uuids of its children, which stay the same when they
+/// are renamed.
+#[derive(Debug, Serialize, Deserialize)]
+struct ChainRow {
+    chain: BackupChain,
+    components: Vec<String>,
+}
+
+/// A link with the uuid of its chain.
+#[derive(Debug, Serialize, Deserialize)]
+struct LinkRow {
+    chain: String,
+    link: BackupLink,
+}
+
+/// A note with the uuid of its chain.
+#[derive(Debug, Serialize, Deserialize)]
+struct NoteRow {
+    chain: String,
+    note: BackupNote,
+}
+
+/// Create the triggers which queue every changed chain, link and note, the
+/// queue becomes operations when the command finishes.
+pub fn setup_triggers(conn: &Connection) -> Result<()> {
+    conn.execute_batch("CREATE TEMP TABLE IF NOT EXISTS location (path TEXT NOT NULL);")?;
+
+    for table in ["chains", "links", "notes"] {
+        conn.execute_batch(&format!(
+            "CREATE TRIGGER IF NOT EXISTS {table}_insert_log AFTER INSERT ON {table}
+            WHEN NEW.uuid IS NOT NULL
+            BEGIN
+                INSERT OR IGNORE INTO pending_ops (uuid, tbl) VALUES (NEW.uuid, '{table}');
+            END;
+            CREATE TRIGGER IF NOT EXISTS {table}_update_log AFTER UPDATE ON {table}
+            BEGIN
+                INSERT OR IGNORE INTO pending_ops (uuid, tbl) SELECT NEW.uuid, '{table}'
+                    WHERE NEW.uuid IS NOT NULL;
+                INSERT OR IGNORE INTO pending_ops (uuid, tbl) SELECT OLD.uuid, '{table}'
+                    WHERE OLD.uuid IS NOT NULL AND OLD.uuid IS NOT NEW.uuid;
+            END;
+            CREATE TRIGGER IF NOT EXISTS {table}_delete_log AFTER DELETE ON {table}
+            WHEN OLD.uuid IS NOT NULL
+            BEGIN
+                INSERT OR IGNORE INTO pending_ops (uuid, tbl) VALUES (OLD.uuid, '{table}');
+            END;",
+            table = table
+        ))?;
+    }
+
+    Ok(())
+}
+
+/// Set where a database which isn't a file is kept, e.g. the folder of the
+/// text store, so a copy of it is told apart like a copied file.
+pub fn set_location(conn: &Connection, path: &Path) -> Result<()> {
+    conn.execute("DELETE FROM temp.location;", params![])?;
+    conn.execute(
+        "INSERT INTO temp.location (path) VALUES (?1);",
+        params![path.to_string_lossy()],
+    )?;
+
+    Ok(())
+}
+
+/// The id of this database, every device has its own.
+pub fn device(conn: &Connection) -> Result<String> {
+    Ok(conn.query_row("SELECT device FROM replica;", params![], |row| row.get(0))?)
+}
+
+fn hostname() -> String {
+    ["/proc/sys/kernel/hostname", "/etc/hostname"]
+        .iter()
+        .filter_map(|path| fs::read_to_string(path).ok())
+        .chain(std::env::var("COMPUTERNAME").ok())
+        .chain(std::env::var("HOSTNAME").ok())
+        .map(|name| name.trim().to_string())
+        .find(|name| !name.is_empty())
+        .unwrap_or_default()
+}
+
+/// Give the database a new device id when it was copied to another device
+/// or path. A copy keeping the id of the original would make its changes
+/// look like changes the original already has, so they would never be sent.
+fn check_device(conn: &Connection) -> Result<()> {
+    let path: String = conn.query_row(
+        "SELECT COALESCE((SELECT path FROM temp.location), file) FROM pragma_database_list WHERE name = 'main';",
+        params![],
+        |row| row.get(0),
+    )?;
+    let location = format!("{}:{}", hostname(), path);
+
+    conn.execute(
+        "UPDATE replica SET device = lower(hex(randomblob(8))), location = ?1
+            WHERE location IS NOT ?1;",
+        params![location],
+    )?;
+
+    // The operations recorded under the old ids are still this database's
+    // to publish.
+    conn.execute(
+        "INSERT OR IGNORE INTO devices (device) SELECT device FROM replica;",
+        params![],
+    )?;
+
+    Ok(())
+}
+
+fn parse_hlc(hlc: &str) -> Option<(i64, i64)> {
+    let mut parts = hlc.splitn(3, '-');
+
+    Some((parts.next()?.parse().ok()?, parts.next()?.parse().ok()?))
+}
+
+/// Advance the clock for a change made on this device.
+fn tick(conn: &Connection) -> Result<String> {
+    let (device, mut physical, mut counter): (String, i64, i64) = conn.query_row(
+        "SELECT device, physical, counter FROM replica;",
+        params![],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    )?;
+
+    let now = Utc::now().timestamp_millis();
+
+    if now > physical {
+        physical = now;
+        counter = 0;
+    } else {
+        counter += 1;
+    }
+
+    conn.execute(
+        "UPDATE replica SET physical = ?1, counter = ?2;",
+        params![physical, counter],
+    )?;
+
+    Ok(format!("{:013}-{:05}-{}", physical, counter, device))
+}
+
+/// Move the clock past an operation from another device, so changes made
+/// here afterwards are ordered after it.
+fn observe(conn: &Connection, hlc: &str) -> Result<()> {
+    if let Some((physical, counter)) = parse_hlc(hlc) {
+        conn.execute(
+            "UPDATE replica SET physical = ?1, counter = ?2
+                WHERE physical < ?1 OR (physical = ?1 AND counter < ?2);",
+            params![physical, counter],
+        )?;
+    }
+
+    Ok(())
+}
+
+fn id_for_uuid(conn: &Connection, table: &str, uuid: &str) -> Result<Option<i64>> {
+    let mut statement = conn.prepare(&format!("SELECT id FROM {} WHERE uuid = ?1;", table))?;
+    let mut id_iter = statement.query_map(params![uuid], |row| row.get(0))?;
+
+    Ok(id_iter.next().transpose()?)
+}
+
+fn uuid_for_id(conn: &Connection, table: &str, id: i64) -> Result<Option<String>> {
+    let mut statement = conn.prepare(&format!("SELECT uuid FROM {} WHERE id = ?1;", table))?;
+    let mut uuid_iter = statement.query_map(params![id], |row| row.get(0))?;
+
+    Ok(uuid_iter.next().transpose()?)
+}
+
+/// The row with `uuid` as it is now, `None` if it doesn't exist.
+fn read_row(conn: &Connection, table: &str, uuid: &str) -> Result<Option<Value>> {
+    let id = match id_for_uuid(conn, table, uuid)? {
+        Some(id) => id,
+        None => return Ok(None),
+    };
+
+    let row = match table {
+        "chains" => {
+            let chain = database::get_chain_for_id(conn, id as i32)?;
+            let aliases = database::get_aliases(conn)?;
+
+            let mut components: Vec<String> = Vec::new();
+            for child_id in chain.children.iter() {
+                components.extend(uuid_for_id(conn, "chains", *child_id)?);
+            }
+
+            let chain = BackupChain {
+                children: Vec::new(),
+                ..backup::export_settings(conn, &chain, &aliases)?
+            };
+
+            serde_json::to_value(ChainRow { chain, components })?
+        }
+        "links" => {
+            let row = conn.query_row(
+                "SELECT chains.uuid, date, time, value, state, reason
+                    FROM links JOIN chains ON chains.id = links.chain_id
+                    WHERE links.id = ?1;",
+                params![id],
+                |row| {
+                    let date: String = row.get(1)?;
+                    let time: Option<String> = row.get(2)?;
+
+                    Ok(LinkRow {
+                        chain: row.get(0)?,
+                        link: BackupLink {
+                            date: NaiveDate::parse_from_str(&date, FORMAT).unwrap(),
+                            time: time.map(|time| NaiveTime::parse_from_str(&time, TIME_FORMAT).unwrap()),
+                            value: row.get(3)?,
+                            state: row.get(4)?,
+                            reason: row.get(5)?,
+                        },
+                    })
+                },
+            )?;
+
+            serde_json::to_value(row)?
+        }
+        _ => {
+            let row = conn.query_row(
+                "SELECT chains.uuid, date, text
+                    FROM notes JOIN chains ON chains.id = notes.chain_id
+                    WHERE notes.id = ?1;",
+                params![id],
+                |row| {
+                    let date: String = row.get(1)?;
+
+                    Ok(NoteRow {
+                        chain: row.get(0)?,
+                        note: BackupNote {
+                            date: NaiveDate::parse_from_str(&date, FORMAT).unwrap(),
+                            text: row.get(2)?,
+                        },
+                    })
+                },
+            )?;
+
+            serde_json::to_value(row)?
+        }
+    };
+
+    Ok(Some(row))
+}
+
+fn op_from_row(row: &rusqlite::Row) -> rusqlite::Result<Op> {
+    let text: Option<String> = row.get(4)?;
+
+    Ok(Op {
+        hlc: row.get(0)?,
+        device: row.get(1)?,
+        table: row.get(2)?,
+        uuid: row.get(3)?,
+        row: text.and_then(|text| serde_json::from_str(&text).ok()),
+    })
+}
+
+/// The newest operation on the row with `uuid`, which decides its state.
+fn latest(conn: &Connection, uuid: &str) -> Result<Option<Op>> {
+    let mut statement = conn.prepare(
+        "SELECT hlc, device, tbl, uuid, row FROM ops WHERE uuid = ?1 ORDER BY hlc DESC LIMIT 1;",
+    )?;
+    let mut op_iter = statement.query_map(params![uuid], op_from_row)?;
+
+    Ok(op_iter.next().transpose()?)
+}
+
+/// Add an operation to the log, false if it was already there.
+fn insert_op(conn: &Connection, op: &Op) -> Result<bool> {
+    let row = match &op.row {
+        Some(row) => Some(serde_json::to_string(row)?),
+        None => None,
+    };
+
+    let inserted = conn.execute(
+        "INSERT OR IGNORE INTO ops (hlc, device, tbl, uuid, row) VALUES (?1, ?2, ?3, ?4, ?5);",
+        params![op.hlc, op.device, op.table, op.uuid, row],
+    )?;
+
+    Ok(inserted > 0)
+}
+
+/// Turn the queued changes into operations, returning how many were added.
+///
+/// Changes which leave a row as its last operation left it, e.g. only its
+/// time of last change, aren't recorded.
+pub fn record(conn: &Connection) -> Result<usize> {
+    check_device(conn)?;
+
+    let mut statement = conn.prepare("SELECT uuid, tbl FROM pending_ops ORDER BY rowid;")?;
+    let pending: Vec<(String, String)> = statement
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .filter_map(Result::ok)
+        .collect();
+
+    if pending.is_empty() {
+        return Ok(0);
+    }
+
+    let device = device(conn)?;
+    let mut count = 0;
+
+    for (uuid, table) in pending {
+        let row = read_row(conn, &table, &uuid)?;
+        let last = latest(conn, &uuid)?;
+
+        let is_unchanged = match &last {
+            Some(op) => op.row == row,
+            None => row.is_none(),
+        };
+        if is_unchanged {
+            continue;
+        }
+
+        insert_op(
+            conn,
+            &Op {
+                hlc: tick(conn)?,
+                device: device.clone(),
+                table,
+                uuid,
+                row,
+            },
+        )?;
+        count += 1;
+    }
+
+    conn.execute("DELETE FROM pending_ops;", params![])?;
+
+    Ok(count)
+}
+
+/// Replay the operations from other devices, returning how many were new.
+///
+/// The newest operation on a row decides its state, so the result doesn't
+/// depend on the order the operations arrive in. Links and notes only exist
+/// while their chain does, and when several chains want the same name the
+/// oldest one gets it.
+pub fn apply(conn: &Connection, ops: &[Op]) -> Result<usize> {
+    // Changes made here first become operations of their own.
+    record(conn)?;
+
+    let mut chains: Vec<String> = Vec::new();
+    let mut links: Vec<String> = Vec::new();
+    let mut notes: Vec<String> = Vec::new();
+    let mut count = 0;
+
+    for op in ops.iter() {
+        observe(conn, &op.hlc)?;
+
+        if !insert_op(conn, op)? {
+            continue;
+        }
+
+        count += 1;
+
+        let uuids = match op.table.as_str() {
+            "chains" => &mut chains,
+            "links" => &mut links,
+            "notes" => &mut notes,
+            _ => continue,
+        };
+        if !uuids.contains(&op.uuid) {
+            uuids.push(op.uuid.clone());
+        }
+    }
+
+    if count == 0 {
+        return Ok(0);
+    }
+
+    for uuid in chains.iter() {
+        if apply_chain(conn, uuid)? {
+            // A restored chain gets back the links and notes it had.
+            links.extend(uuids_of_chain(conn, "links", uuid)?);
+            notes.extend(uuids_of_chain(conn, "notes", uuid)?);
+        }
+    }
+    for uuid in links.iter() {
+        apply_link(conn, uuid)?;
+    }
+    for uuid in notes.iter() {
+        apply_note(conn, uuid)?;
+    }
+
+    settle(conn)?;
+
+    // The replay only brings this device up to date, it is no change of
+    // its own.
+    conn.execute("DELETE FROM pending_ops;", params![])?;
+
+    Ok(count)
+}
+
+/// The links or notes which were ever on the chain with `uuid`.
+fn uuids_of_chain(conn: &Connection, table: &str, uuid: &str) -> Result<Vec<String>> {
+    let mut statement =
+        conn.prepare("SELECT DISTINCT uuid FROM ops WHERE tbl = ?1 AND json_extract(row, '$.chain') = ?2;")?;
+    let uuids = statement
+        .query_map(params![table, uuid], |row| row.get(0))?
+        .filter_map(Result::ok)
+        .collect();
+
+    Ok(uuids)
+}
+
+fn set_uuid(conn: &Connection, table: &str, id: i64, uuid: &str) -> Result<()> {
+    conn.execute(
+        &format!("UPDATE {} SET uuid = ?2 WHERE id = ?1;", table),
+        params![id, uuid],
+    )?;
+    conn.execute("DELETE FROM tombstones WHERE uuid = ?1;", params![uuid])?;
+
+    Ok(())
+}
+
+/// Bring a chain to the state of its newest operation, returning whether it
+/// was added. Its name, aliases and children are set by `settle`.
+fn apply_chain(conn: &Connection, uuid: &str) -> Result<bool> {
+    let id = id_for_uuid(conn, "chains", uuid)?;
+
+    let row: ChainRow = match latest(conn, uuid)?.and_then(|op| op.row) {
+        Some(row) => serde_json::from_value(row)?,
+        None => {
+            if let Some(id) = id {
+                sync::delete_chain(conn, &database::get_chain_for_id(conn, id as i32)?)?;
+            }
+
+            return Ok(false);
+        }
+    };
+
+    let mut chain = backup::to_chain(&row.chain);
+
+    let (chain, is_added) = match id {
+        Some(id) => {
+            chain.id = id;
+            database::edit_chain(conn, &chain)?;
+
+            (database::get_chain_for_id(conn, id as i32)?, false)
+        }
+        None => {
+            // Names are only unique once every chain is replayed.
+            chain.name = uuid.to_string();
+            database::add_chain(conn, &chain)?;
+
+            let chain = database::find_chain_for_name(conn, uuid)?.unwrap();
+            set_uuid(conn, "chains", chain.id, uuid)?;
+
+            (chain, true)
+        }
+    };
+
+    for tag in chain.tags.iter().filter(|tag| !row.chain.tags.contains(tag)) {
+        database::delete_tag(conn, &chain, tag)?;
+    }
+    for tag in row.chain.tags.iter().filter(|tag| !chain.tags.contains(tag)) {
+        database::add_tag(conn, &chain, tag)?;
+    }
+
+    Ok(is_added)
+}
+
+/// Bring a link to the state of its newest operation, links of chains which
+/// don't exist are removed.
+fn apply_link(conn: &Connection, uuid: &str) -> Result<()> {
+    let id = id_for_uuid(conn, "links", uuid)?;
+
+    let row: Option<LinkRow> = match latest(conn, uuid)?.and_then(|op| op.row) {
+        Some(row) => Some(serde_json::from_value(row)?),
+        None => None,
+    };
+    let chain_id = match &row {
+        Some(row) => id_for_uuid(conn, "chains", &row.chain)?,
+        None => None,
+    };
+
+    let (row, chain_id) = match (row, chain_id) {
+        (Some(row), Some(chain_id)) => (row, chain_id),
+        _ => {
+            if let Some(id) = id {
+                database::delete_link_for_id(conn, id)?;
+            }
+
+            return Ok(());
+        }
+    };
+
+    let link = Link {
+        id: id.unwrap_or_default(),
+        chain_id: chain_id as i32,
+        date: row.link.date,
+        time: row.link.time,
+        value: row.link.value,
+        state: State::parse(&row.link.state),
+        reason: row.link.reason,
+    };
+
+    match id {
+        Some(id) => {
+            conn.execute(
+                "UPDATE links SET chain_id = ?2, date = ?3, time = ?4, value = ?5, state = ?6, reason = ?7
+                    WHERE id = ?1;",
+                params![
+                    id,
+                    link.chain_id,
+                    link.date.format(FORMAT).to_string(),
+                    link.time.map(|time| time.format(TIME_FORMAT).to_string()),
+                    link.value,
+                    link.state.as_str(),
+                    link.reason
+                ],
+            )?;
+        }
+        None => {
+            database::insert_link(conn, &link)?;
+            set_uuid(conn, "links", conn.last_insert_rowid(), uuid)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Bring a note to the state of its newest operation, notes of chains which
+/// don't exist are removed.
+fn apply_note(conn: &Connection, uuid: &str) -> Result<()> {
+    let id = id_for_uuid(conn, "notes", uuid)?;
+
+    let row: Option<NoteRow> = match latest(conn, uuid)?.and_then(|op| op.row) {
+        Some(row) => Some(serde_json::from_value(row)?),
+        None => None,
+    };
+    let chain_id = match &row {
+        Some(row) => id_for_uuid(conn, "chains", &row.chain)?,
+        None => None,
+    };
+
+    match (row, chain_id, id) {
+        (Some(row), Some(chain_id), Some(id)) => {
+            conn.execute(
+                "UPDATE notes SET chain_id = ?2, date = ?3, text = ?4 WHERE id = ?1;",
+                params![id, chain_id, row.note.date.format(FORMAT).to_string(), row.note.text],
+            )?;
+        }
+        (Some(row), Some(chain_id), None) => {
+            database::add_note(
+                conn,
+                &super::Note {
+                    id: 0,
+                    chain_id: chain_id as i32,
+                    date: row.note.date,
+                    text: row.note.text,
+                },
+            )?;
+            set_uuid(conn, "notes", conn.last_insert_rowid(), uuid)?;
+        }
+        (_, _, Some(id)) => {
+            conn.execute("DELETE FROM notes WHERE id = ?1;", params![id])?;
+        }
+        _ => (),
+    }
+
+    Ok(())
+}
+
+/// Give every chain the name, aliases and children of its newest operation.
+///
+/// Names and aliases are unique, chains created on several devices with the
+/// same name get it in the order they were created, the others get the
+/// start of their uuid added to it.
+fn settle(conn: &Connection) -> Result<()> {
+    let mut chains: Vec<(String, i64, String, ChainRow)> = Vec::new();
+
+    for chain in database::get_chains(conn)? {
+        let uuid = match uuid_for_id(conn, "chains", chain.id)? {
+            Some(uuid) => uuid,
+            None => continue,
+        };
+        let row = match latest(conn, &uuid)?.and_then(|op| op.row) {
+            Some(row) => serde_json::from_value::<ChainRow>(row)?,
+            None => continue,
+        };
+        let created: String = conn.query_row("SELECT min(hlc) FROM ops WHERE uuid = ?1;", params![uuid], |row| {
+            row.get(0)
+        })?;
+
+        chains.push((created, chain.id, uuid, row));
+    }
+
+    chains.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut names: HashSet<String> = HashSet::new();
+    let mut aliases: Vec<(String, i64)> = Vec::new();
+
+    for (_, id, uuid, row) in chains.iter_mut() {
+        if names.contains(&row.chain.name) {
+            row.chain.name = format!("{} ({})", row.chain.name, &uuid[..6.min(uuid.len())]);
+        }
+        names.insert(row.chain.name.clone());
+
+        for alias in row.chain.aliases.iter() {
+            if !aliases.iter().any(|(taken, _)| taken == alias) {
+                aliases.push((alias.clone(), *id));
+            }
+        }
+    }
+
+    let current: HashMap<i64, String> = database::get_chains(conn)?
+        .into_iter()
+        .map(|chain| (chain.id, chain.name))
+        .collect();
+    let renamed: Vec<&(String, i64, String, ChainRow)> = chains
+        .iter()
+        .filter(|(_, id, _, row)| current.get(id) != Some(&row.chain.name))
+        .collect();
+
+    // Names can be swapped, so they are freed first.
+    for (_, id, uuid, _) in renamed.iter() {
+        conn.execute("UPDATE chains SET name = ?2 WHERE id = ?1;", params![id, uuid])?;
+    }
+    for (_, id, _, row) in renamed.iter() {
+        conn.execute("UPDATE chains SET name = ?2 WHERE id = ?1;", params![id, row.chain.name])?;
+    }
+
+    let mut current_aliases: Vec<(String, i64)> = Vec::new();
+    for (alias, name) in database::get_aliases(conn)? {
+        if let Some(chain) = database::find_chain_for_name(conn, &name)? {
+            current_aliases.push((alias, chain.id));
+        }
+    }
+
+    aliases.sort();
+    current_aliases.sort();
+
+    if aliases != current_aliases {
+        conn.execute("DELETE FROM aliases;", params![])?;
+
+        for (alias, id) in aliases.iter() {
+            conn.execute(
+                "INSERT INTO aliases (chain_id, alias) VALUES (?1, ?2);",
+                params![id, alias],
+            )?;
+        }
+    }
+
+    for (_, id, _, row) in chains.iter() {
+        let mut children: Vec<i64> = Vec::new();
+        for uuid in row.components.iter() {
+            children.extend(id_for_uuid(conn, "chains", uuid)?);
+        }
+
+        let chain = database::get_chain_for_id(conn, *id as i32)?;
+        if chain.children != children {
+            database::set_children(conn, *id, &children)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The newest operation of every device in the log.
+pub fn known(conn: &Connection) -> Result<HashMap<String, String>> {
+    let mut statement = conn.prepare("SELECT device, max(hlc) FROM ops GROUP BY device;")?;
+    let known = statement
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .filter_map(Result::ok)
+        .collect();
+
+    Ok(known)
+}
+
+/// Every id this database had, the current one included.
+fn own_devices(conn: &Connection) -> Result<HashSet<String>> {
+    let mut statement = conn.prepare("SELECT device FROM devices;")?;
+    let devices = statement
+        .query_map([], |row| row.get(0))?
+        .filter_map(Result::ok)
+        .collect();
+
+    Ok(devices)
+}
+
+/// The operations missing on a device which knows the operations up to
+/// `known`, in clock order.
+pub fn missing(conn: &Connection, known: &HashMap<String, String>) -> Result<Vec<Op>> {
+    let mut statement = conn.prepare("SELECT hlc, device, tbl, uuid, row FROM ops ORDER BY hlc;")?;
+    let ops = statement
+        .query_map([], op_from_row)?
+        .filter_map(Result::ok)
+        .filter(|op| known.get(&op.device).is_none_or(|hlc| op.hlc > *hlc))
+        .collect();
+
+    Ok(ops)
+}
+
+/// Exchange operations through a shared folder, e.g. a Syncthing or
+/// Nextcloud folder. Every device only writes the file of its own
+/// operations, so the folder never has conflicting copies. Returns the
+/// number of operations received and newly written, without `publish`
+/// nothing is written.
+///
+/// A database which moved has a new device id, the operations it recorded
+/// under its old ids which aren't in the folder yet are written to the file
+/// of its new id.
+pub fn sync_dir(conn: &Connection, dir: &Path, publish: bool) -> Result<(usize, usize)> {
+    record(conn)?;
+    fs::create_dir_all(dir)?;
+
+    let device = device(conn)?;
+    let own_path = dir.join(format!("{}.{}", device, EXTENSION));
+
+    let mut ops: Vec<Op> = Vec::new();
+    let mut published: HashSet<String> = HashSet::new();
+    let mut written = 0;
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        if path.extension().is_none_or(|extension| extension != EXTENSION) {
+            continue;
+        }
+
+        for (number, line) in fs::read_to_string(&path)?.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let op: Op = serde_json::from_str(line).map_err(|err| {
+                ChainError::new(&format!("Failed to read {} line {}: {}", path.display(), number + 1, err))
+            })?;
+            if path == own_path {
+                written += 1;
+            } else {
+                published.insert(op.hlc.clone());
+            }
+
+            ops.push(op);
+        }
+    }
+
+    ops.sort_by(|a, b| a.hlc.cmp(&b.hlc));
+    let received = apply(conn, &ops)?;
+
+    if !publish {
+        return Ok((received, 0));
+    }
+
+    let devices = own_devices(conn)?;
+    let own: Vec<Op> = missing(conn, &HashMap::new())?
+        .into_iter()
+        .filter(|op| devices.contains(&op.device) && !published.contains(&op.hlc))
+        .collect();
+
+    let mut text = String::new();
+    for op in own.iter() {
+        text.push_str(&serde_json::to_string(op)?);
+        text.push('\n');
+    }
+
+    let temporary = own_path.with_extension("tmp");
+    fs::write(&temporary, text)?;
+    fs::rename(&temporary, &own_path)?;
+
+    Ok((received, own.len().saturating_sub(written)))
+}
+
+/// A message between two peers, the newest operation the sender knows of
+/// each device and the operations it sends. The first message carries the
+/// token of the peer, a refused peer gets an error instead.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Message {
+    known: HashMap<String, String>,
+    ops: Vec<Op>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// How long to wait for a peer before giving up on it.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The longest first message, which is read before the token is checked.
+const MAX_REQUEST: u64 = 1024 * 1024;
+
+/// The longest message with operations.
+const MAX_MESSAGE: u64 = 256 * 1024 * 1024;
+
+fn send(stream: &mut TcpStream, message: &Message) -> Result<()> {
+    let mut line = serde_json::to_string(message)?;
+    line.push('\n');
+
+    stream.write_all(line.as_bytes())?;
+    stream.flush()?;
+
+    Ok(())
+}
+
+/// Read a message of at most `limit` bytes.
+fn receive(reader: &mut BufReader<TcpStream>, limit: u64) -> Result<Message> {
+    let mut line = String::new();
+    let read = reader.by_ref().take(limit).read_line(&mut line)?;
+
+    if read == 0 {
+        return Err(ChainError::new("The peer closed the connection").into());
+    }
+
+    if read as u64 == limit && !line.ends_with('\n') {
+        return Err(ChainError::new(&format!("The peer sent a message longer than {} bytes", limit)).into());
+    }
+
+    Ok(serde_json::from_str(&line)?)
+}
+
+/// Compare a token given by a peer in constant time, so the time of a
+/// refusal doesn't tell how much of the token was right.
+pub fn is_token(given: Option<&str>, token: &str) -> bool {
+    let given = given.unwrap_or_default().as_bytes();
+    let token = token.as_bytes();
+
+    let difference = given
+        .iter()
+        .zip(token.iter())
+        .fold(given.len() ^ token.len(), |difference, (a, b)| difference | (a ^ b) as usize);
+
+    difference == 0
+}
+
+/// Exchange operations with a device running `sync serve`, returning the
+/// number of operations received and sent. Without `publish` nothing is
+/// sent.
+pub fn sync_peer(conn: &Connection, address: &str, token: Option<&str>, publish: bool) -> Result<(usize, usize)> {
+    record(conn)?;
+
+    let mut stream = TcpStream::connect(address)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    send(
+        &mut stream,
+        &Message {
+            known: known(conn)?,
+            token: token.map(|token| token.to_string()),
+            ..Default::default()
+        },
+    )?;
+
+    let reply = receive(&mut reader, MAX_MESSAGE)?;
+
+    if let Some(error) = reply.error {
+        return Err(ChainError::new(&error).into());
+    }
+
+    let received = apply(conn, &reply.ops)?;
+
+    let ops = if publish { missing(conn, &reply.known)? } else { Vec::new() };
+    let sent = ops.len();
+    send(
+        &mut stream,
+        &Message {
+            known: known(conn)?,
+            ops,
+            ..Default::default()
+        },
+    )?;
+
+    Ok((received, sent))
+}
+
+/// Answer a device running `sync peer`, returning the number of operations
+/// received and sent. With a `token` only peers sending it are answered.
+pub fn serve_peer(conn: &Connection, mut stream: TcpStream, token: Option<&str>) -> Result<(usize, usize)> {
+    record(conn)?;
+
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let request = receive(&mut reader, MAX_REQUEST)?;
+
+    if token.is_some_and(|token| !is_token(request.token.as_deref(), token)) {
+        let error = "Missing or wrong token, pass it with --token";
+
+        send(
+            &mut stream,
+            &Message {
+                error: Some(error.to_string()),
+                ..Default::default()
+            },
+        )?;
+
+        return Err(ChainError::new(error).into());
+    }
+
+    let ops = missing(conn, &request.known)?;
+    let sent = ops.len();
+    send(
+        &mut stream,
+        &Message {
+            known: known(conn)?,
+            ops,
+            ..Default::default()
+        },
+    )?;
+
+    let reply = receive(&mut reader, MAX_MESSAGE)?;
+    let received = apply(conn, &reply.ops)?;
+
+    Ok((received, sent))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Chain;
+
+    fn replica() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        database::setup_tables(&conn).unwrap();
+
+        conn
+    }
+
+    fn date(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 1, day).unwrap()
+    }
+
+    fn add_chain(conn: &Connection, name: &str) {
+        database::add_chain(
+            conn,
+            &Chain {
+                name: name.to_string(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        record(conn).unwrap();
+    }
+
+    fn add_link(conn: &Connection, name: &str, day: u32) {
+        let chain = database::get_chain_for_name(conn, name).unwrap();
+
+        database::add_link(
+            conn,
+            &Link {
+                id: 0,
+                chain_id: chain.id as i32,
+                date: date(day),
+                time: None,
+                value: None,
+                state: State::Done,
+                reason: None,
+            },
+        )
+        .unwrap();
+        record(conn).unwrap();
+    }
+
+    fn rename(conn: &Connection, name: &str, new: &str) {
+        let chain = database::get_chain_for_name(conn, name).unwrap();
+
+        database::edit_chain_for_name(conn, &chain, new).unwrap();
+        record(conn).unwrap();
+    }
+
+    fn mv(conn: &Connection, name: &str, from: u32, to: u32) {
+        let chain = database::get_chain_for_name(conn, name).unwrap();
+        let link = |day| Link {
+            id: 0,
+            chain_id: chain.id as i32,
+            date: date(day),
+            time: None,
+            value: None,
+            state: State::Done,
+            reason: None,
+        };
+
+        database::update(conn, &link(from), &link(to)).unwrap();
+        record(conn).unwrap();
+    }
+
+    fn rm_chain(conn: &Connection, name: &str) {
+        let chain = database::get_chain_for_name(conn, name).unwrap();
+
+        sync::delete_chain(conn, &chain).unwrap();
+        record(conn).unwrap();
+    }
+
+    /// Send the operations `from` has and `to` is missing.
+    fn send(from: &Connection, to: &Connection) {
+        apply(to, &missing(from, &known(to).unwrap()).unwrap()).unwrap();
+    }
+
+    /// The chains with their links, which have to be the same on every
+    /// replica.
+    fn chains(conn: &Connection) -> String {
+        let chains = database::get_chains(conn).unwrap();
+
+        backup::to_json(&backup::export(conn, &chains).unwrap()).unwrap()
+    }
+
+    /// Two replicas which start with the same chains.
+    fn replicas(setup: impl Fn(&Connection)) -> (Connection, Connection) {
+        let a = replica();
+        setup(&a);
+
+        let b = replica();
+        send(&a, &b);
+
+        assert_eq!(chains(&a), chains(&b));
+
+        (a, b)
+    }
+
+    /// Apply a change on each replica and exchange them in both orders, the
+    /// replicas have to end up with the same chains either way.
+    fn assert_converges(
+        setup: impl Fn(&Connection),
+        change_a: impl Fn(&Connection),
+        change_b: impl Fn(&Connection),
+    ) -> String {
+        let mut results: Vec<String> = Vec::new();
+
+        for a_first in [true, false] {
+            let (a, b) = replicas(&setup);
+
+            change_a(&a);
+            // The change on `b` is always the later one.
+            std::thread::sleep(Duration::from_millis(2));
+            change_b(&b);
+
+            if a_first {
+                send(&a, &b);
+                send(&b, &a);
+            } else {
+                send(&b, &a);
+                send(&a, &b);
+            }
+
+            assert_eq!(chains(&a), chains(&b));
+            results.push(chains(&a));
+        }
+
+        assert_eq!(results[0], results[1]);
+
+        results.remove(0)
+    }
+
+    fn read_chain(conn: &Connection) {
+        add_chain(conn, "read");
+        add_link(conn, "read", 1);
+    }
+
+    #[test]
+    fn concurrent_renames_converge() {
+        let result = assert_converges(read_chain, |a| rename(a, "read", "books"), |b| rename(b, "read", "novels"));
+
+        // The later rename wins, and the chain keeps its link.
+        assert!(result.contains("\"novels\""));
+        assert!(!result.contains("\"books\""));
+        assert!(result.contains("2024-01-01"));
+    }
+
+    #[test]
+    fn rename_and_delete_converge() {
+        let result = assert_converges(read_chain, |a| rename(a, "read", "books"), |b| rm_chain(b, "read"));
+
+        assert_eq!(result, chains(&replica()));
+
+        let result = assert_converges(read_chain, |a| rm_chain(a, "read"), |b| rename(b, "read", "books"));
+
+        assert!(result.contains("\"books\""));
+    }
+
+    #[test]
+    fn moved_links_converge() {
+        let result = assert_converges(read_chain, |a| mv(a, "read", 1, 2), |b| mv(b, "read", 1, 3));
+
+        assert!(result.contains("2024-01-03"));
+        assert!(!result.contains("2024-01-01"));
+        assert!(!result.contains("2024-01-02"));
+    }
+
+    #[test]
+    fn moved_links_and_renamed_chains_converge() {
+        let result = assert_converges(read_chain, |a| mv(a, "read", 1, 2), |b| rename(b, "read", "books"));
+
+        assert!(result.contains("\"books\""));
+        assert!(result.contains("2024-01-02"));
+        assert!(!result.contains("2024-01-01"));
+    }
+
+    #[test]
+    fn moved_links_of_deleted_chains_are_gone() {
+        let result = assert_converges(read_chain, |a| mv(a, "read", 1, 2), |b| rm_chain(b, "read"));
+
+        assert!(!result.contains("\"read\""));
+    }
+
+    #[test]
+    fn chains_added_on_both_replicas_keep_their_names() {
+        let a = replica();
+        let b = replica();
+
+        add_chain(&a, "read");
+        add_link(&a, "read", 1);
+        std::thread::sleep(Duration::from_millis(2));
+        add_chain(&b, "read");
+
+        send(&b, &a);
+        send(&a, &b);
+
+        assert_eq!(chains(&a), chains(&b));
+
+        // The chain created first keeps the name.
+        let read = database::get_chain_for_name(&b, "read").unwrap();
+        assert_eq!(database::get_links_for_chain_id(&b, read.id as i32).unwrap().len(), 1);
+        assert_eq!(database::get_chains(&b).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn links_belong_to_the_chain_they_name() {
+        let conn = replica();
+        read_chain(&conn);
+        add_chain(&conn, "run");
+
+        let read = uuid_for_id(&conn, "chains", database::get_chain_for_name(&conn, "read").unwrap().id)
+            .unwrap()
+            .unwrap();
+        let run = database::get_chain_for_name(&conn, "run").unwrap();
+
+        // A note mentioning the uuid of another chain stays on its own.
+        database::add_note(
+            &conn,
+            &crate::Note {
+                id: 0,
+                chain_id: run.id as i32,
+                date: date(1),
+                text: read.clone(),
+            },
+        )
+        .unwrap();
+        record(&conn).unwrap();
+
+        assert_eq!(uuids_of_chain(&conn, "links", &read).unwrap().len(), 1);
+        assert!(uuids_of_chain(&conn, "notes", &read).unwrap().is_empty());
+    }
+
+    #[test]
+    fn tokens_are_compared_whole() {
+        assert!(is_token(Some("secret"), "secret"));
+        assert!(!is_token(Some("secre"), "secret"));
+        assert!(!is_token(Some("secrets"), "secret"));
+        assert!(!is_token(Some("Secret"), "secret"));
+        assert!(!is_token(None, "secret"));
+    }
+
+    #[test]
+    fn peers_sending_long_messages_are_refused() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let peer = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            let _ = stream.write_all(&vec![b'x'; MAX_REQUEST as usize + 1]);
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let err = serve_peer(&replica(), stream, Some("secret")).unwrap_err();
+
+        assert!(err.to_string().contains("longer than"));
+        peer.join().unwrap();
+    }
+
+    #[test]
+    fn moved_databases_publish_their_old_operations() {
+        let dir = std::env::temp_dir().join(format!("c-oplog-moved-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let a = replica();
+        read_chain(&a);
+        let old = device(&a).unwrap();
+
+        // Moving the database gives it a new id before anything was
+        // published.
+        a.execute("UPDATE replica SET location = 'elsewhere';", params![]).unwrap();
+        sync_dir(&a, &dir, true).unwrap();
+        assert_ne!(device(&a).unwrap(), old);
+
+        let b = replica();
+        sync_dir(&b, &dir, true).unwrap();
+
+        assert_eq!(chains(&a), chains(&b));
+        assert!(chains(&b).contains("\"read\""));
+
+        // Publishing again doesn't write them twice.
+        let (_, written) = sync_dir(&a, &dir, true).unwrap();
+        assert_eq!(written, 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
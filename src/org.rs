@@ -0,0 +1,412 @@
+use super::backup::{Backup, BackupChain, BackupLink, BackupNote, VERSION};
+use super::habit_apps;
+use super::structs::State;
+use anyhow::Result;
+use chrono::{Duration, NaiveDate, NaiveTime};
+use std::cmp::Reverse;
+
+// Keywords stripped from the start of a heading.
+const KEYWORDS: &[&str] = &["TODO", "DONE", "NEXT", "WAITING", "HOLD", "CANCELLED", "CANCELED"];
+
+/// The tag org-mode uses for archived headings.
+const ARCHIVE_TAG: &str = "ARCHIVE";
+
+/// Format an org timestamp, with the time when there is one.
+fn timestamp(date: NaiveDate, time: Option<NaiveTime>) -> String {
+    match time {
+        Some(time) => format!("{} {}", date.format("%Y-%m-%d %a"), time.format("%H:%M")),
+        None => date.format("%Y-%m-%d %a").to_string(),
+    }
+}
+
+/// Tags may only contain letters, digits, `_`, `@`, `#` and `%`.
+fn org_tag(tag: &str) -> String {
+    tag.chars()
+        .map(|c| match c {
+            c if c.is_alphanumeric() || "_@#%".contains(c) => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// Write every chain as an org habit, with its completions and notes in the
+/// LOGBOOK drawer, newest first like org-mode logs them.
+pub fn write(backup: &Backup, today: NaiveDate) -> String {
+    let mut org = String::new();
+
+    // Composite chains have no links of their own.
+    for chain in backup.chains.iter().filter(|chain| chain.rule.is_none()) {
+        let mut tags: Vec<String> = chain.tags.iter().map(|tag| org_tag(tag)).collect();
+        if chain.archived {
+            tags.push(ARCHIVE_TAG.to_string());
+        }
+
+        if tags.is_empty() {
+            org.push_str(&format!("* TODO {}\n", chain.name));
+        } else {
+            org.push_str(&format!("* TODO {} :{}:\n", chain.name, tags.join(":")));
+        }
+
+        let mut done: Vec<&BackupLink> = chain
+            .links
+            .iter()
+            .filter(|link| link.state == State::Done.as_str())
+            .collect();
+        done.sort_by_key(|link| (link.date, link.time));
+
+        let scheduled = match done.last() {
+            Some(link) => link.date + Duration::days(1),
+            None => today,
+        };
+
+        org.push_str(&format!("  SCHEDULED: <{} .+1d>\n", timestamp(scheduled, None)));
+        org.push_str("  :PROPERTIES:\n  :STYLE:    habit\n");
+        if let Some(link) = done.last() {
+            org.push_str(&format!("  :LAST_REPEAT: [{}]\n", timestamp(link.date, link.time)));
+        }
+        org.push_str("  :END:\n");
+
+        // Completions and notes are logged together, newest first.
+        let mut entries: Vec<(NaiveDate, Option<NaiveTime>, String)> = done
+            .iter()
+            .map(|link| {
+                (
+                    link.date,
+                    link.time,
+                    format!("  - State \"DONE\"       from \"TODO\"       [{}]\n", timestamp(link.date, link.time)),
+                )
+            })
+            .collect();
+
+        for note in chain.notes.iter() {
+            let text: Vec<String> = note.text.lines().map(|line| format!("    {}\n", line)).collect();
+
+            entries.push((
+                note.date,
+                None,
+                format!("  - Note taken on [{}] \\\\\n{}", timestamp(note.date, None), text.concat()),
+            ));
+        }
+
+        entries.sort_by_key(|(date, time, _)| Reverse((*date, *time)));
+
+        if !entries.is_empty() {
+            org.push_str("  :LOGBOOK:\n");
+            for (_, _, entry) in entries.iter() {
+                org.push_str(entry);
+            }
+            org.push_str("  :END:\n");
+        }
+
+        if let Some(description) = &chain.description {
+            org.push_str(&format!("  {}\n", description));
+        }
+    }
+
+    org
+}
+
+/// Read the date and time of the first org timestamp in `line`.
+fn parse_timestamp(line: &str) -> Option<(NaiveDate, Option<NaiveTime>)> {
+    let start = line.find(['[', '<'])?;
+    let end = start + line[start..].find([']', '>'])?;
+    let mut words = line[start + 1..end].split_whitespace();
+
+    let date = NaiveDate::parse_from_str(words.next()?, "%Y-%m-%d").ok()?;
+    let time = words.find_map(|word| NaiveTime::parse_from_str(word, "%H:%M").ok());
+
+    Some((date, time))
+}
+
+/// Describe the repeater of a SCHEDULED timestamp, e.g. `.+2d`.
+fn parse_repeater(line: &str) -> Option<String> {
+    let repeater = line
+        .split(|c: char| c.is_whitespace() || c == '>')
+        .find(|word| word.starts_with('+') || word.starts_with(".+"))?;
+    // Habits can have a maximum interval after a slash, e.g. `.+2d/4d`.
+    let repeater = repeater.trim_start_matches('.').trim_start_matches('+').split('/').next()?;
+
+    let (count, unit) = repeater.split_at(repeater.len().checked_sub(1)?);
+    let count: i64 = count.parse().ok()?;
+
+    match unit {
+        "d" => habit_apps::frequency_goal(1, count),
+        "w" => habit_apps::frequency_goal(1, count * 7),
+        "m" => Some(format!("every {} month(s)", count)),
+        "y" => Some(format!("every {} year(s)", count)),
+        _ => None,
+    }
+}
+
+/// Read the title and tags of a heading, without its keyword and priority.
+fn parse_heading(line: &str) -> (String, Vec<String>) {
+    let mut title = line.trim_start_matches('*').trim();
+
+    if let Some((keyword, rest)) = title.split_once(' ') {
+        if KEYWORDS.contains(&keyword) {
+            title = rest.trim_start();
+        }
+    }
+
+    if title.starts_with("[#") {
+        if let Some((_, rest)) = title.split_once(']') {
+            title = rest.trim_start();
+        }
+    }
+
+    let mut tags: Vec<String> = Vec::new();
+
+    if title.ends_with(':') {
+        if let Some(index) = title.rfind(' ') {
+            let candidate = &title[index + 1..];
+
+            if candidate.starts_with(':') && candidate.len() > 1 {
+                tags = candidate
+                    .split(':')
+                    .filter(|tag| !tag.is_empty())
+                    .map(|tag| tag.to_string())
+                    .collect();
+                title = title[..index].trim_end();
+            }
+        }
+    }
+
+    (title.to_string(), tags)
+}
+
+/// The chain of the heading being read.
+#[derive(Default)]
+struct Heading {
+    chain: BackupChain,
+    is_habit: bool,
+    last_repeat: Option<(NaiveDate, Option<NaiveTime>)>,
+    description: Vec<String>,
+}
+
+impl Heading {
+    fn finish(mut self, chains: &mut Vec<BackupChain>) {
+        if !self.is_habit || self.chain.name.is_empty() {
+            return;
+        }
+
+        // Habits completed without logging only have LAST_REPEAT.
+        if let Some((date, time)) = self.last_repeat {
+            if !self.chain.links.iter().any(|link| link.date == date) {
+                self.chain.links.push(done(date, time));
+            }
+        }
+
+        self.chain.links.sort_by_key(|link| (link.date, link.time));
+        self.chain.notes.sort_by_key(|note| note.date);
+
+        if !self.description.is_empty() {
+            self.chain.description = Some(self.description.join(" "));
+        }
+
+        chains.push(self.chain);
+    }
+}
+
+fn done(date: NaiveDate, time: Option<NaiveTime>) -> BackupLink {
+    BackupLink {
+        date,
+        time,
+        value: None,
+        state: State::Done.as_str().to_string(),
+        reason: None,
+    }
+}
+
+/// Read the headings with `:STYLE: habit` as chains, each `State "DONE"`
+/// entry in their logbook becomes a link and each logged note a note.
+pub fn read(org: &str) -> Result<Backup> {
+    let mut chains: Vec<BackupChain> = Vec::new();
+    let mut heading: Option<Heading> = None;
+    let mut drawer: Option<String> = None;
+    let mut note: Option<BackupNote> = None;
+
+    for line in org.lines() {
+        let trimmed = line.trim();
+
+        // A note continues until the next entry or the end of the drawer.
+        if note.is_some() && (trimmed.starts_with("- ") || trimmed.starts_with(':') || line.starts_with('*')) {
+            if let (Some(note), Some(heading)) = (note.take(), heading.as_mut()) {
+                heading.chain.notes.push(note);
+            }
+        }
+
+        if line.starts_with('*') && line.trim_start_matches('*').starts_with(' ') {
+            if let Some(heading) = heading.take() {
+                heading.finish(&mut chains);
+            }
+
+            let (title, tags) = parse_heading(line);
+            let archived = tags.iter().any(|tag| tag == ARCHIVE_TAG);
+
+            heading = Some(Heading {
+                chain: BackupChain {
+                    name: title,
+                    archived,
+                    tags: tags.into_iter().filter(|tag| tag != ARCHIVE_TAG).collect(),
+                    ..Default::default()
+                },
+                ..Default::default()
+            });
+            drawer = None;
+            continue;
+        }
+
+        let heading = match heading.as_mut() {
+            Some(heading) => heading,
+            None => continue,
+        };
+
+        if let Some(text) = note.as_mut() {
+            if !trimmed.is_empty() {
+                if !text.text.is_empty() {
+                    text.text.push('\n');
+                }
+                text.text.push_str(trimmed);
+            }
+            continue;
+        }
+
+        if trimmed == ":END:" {
+            drawer = None;
+            continue;
+        }
+
+        if trimmed.len() > 2 && trimmed.starts_with(':') && trimmed.ends_with(':') && drawer.is_none() {
+            drawer = Some(trimmed.trim_matches(':').to_string());
+            continue;
+        }
+
+        match drawer.as_deref() {
+            Some("PROPERTIES") => {
+                let (key, value) = match trimmed.trim_start_matches(':').split_once(':') {
+                    Some((key, value)) => (key, value.trim()),
+                    None => continue,
+                };
+
+                match key {
+                    "STYLE" if value == "habit" => heading.is_habit = true,
+                    "LAST_REPEAT" => heading.last_repeat = parse_timestamp(value),
+                    _ => (),
+                }
+            }
+            Some("LOGBOOK") => {
+                if trimmed.starts_with("- State \"DONE\"") {
+                    if let Some((date, time)) = parse_timestamp(trimmed) {
+                        heading.chain.links.push(done(date, time));
+                    }
+                } else if trimmed.starts_with("- Note taken on") {
+                    if let Some((date, _)) = parse_timestamp(trimmed) {
+                        // The text may follow on the same line after `\\`.
+                        let text = trimmed
+                            .split_once("\\\\")
+                            .map(|(_, text)| text.trim().to_string())
+                            .unwrap_or_default();
+
+                        note = Some(BackupNote { date, text });
+                    }
+                }
+            }
+            Some(_) => (),
+            None => {
+                if trimmed.starts_with("SCHEDULED:") || trimmed.starts_with("DEADLINE:") || trimmed.starts_with("CLOSED:") {
+                    if let Some(index) = trimmed.find("SCHEDULED:") {
+                        heading.chain.goal = parse_repeater(&trimmed[index..]);
+                    }
+                } else if !trimmed.is_empty() && !trimmed.starts_with('#') {
+                    heading.description.push(trimmed.to_string());
+                }
+            }
+        }
+    }
+
+    if let (Some(note), Some(heading)) = (note, heading.as_mut()) {
+        heading.chain.notes.push(note);
+    }
+
+    if let Some(heading) = heading {
+        heading.finish(&mut chains);
+    }
+
+    Ok(Backup {
+        version: VERSION,
+        chains,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 1, day).unwrap()
+    }
+
+    #[test]
+    fn written_habits_read_back() {
+        let chain = BackupChain {
+            name: "read".to_string(),
+            description: Some("A chapter a day".to_string()),
+            archived: true,
+            tags: vec!["books".to_string()],
+            links: vec![
+                done(date(1), None),
+                done(date(2), Some(NaiveTime::from_hms_opt(21, 30, 0).unwrap())),
+            ],
+            notes: vec![BackupNote {
+                date: date(2),
+                text: "Finished the book\nStarted another".to_string(),
+            }],
+            ..Default::default()
+        };
+
+        let org = write(
+            &Backup {
+                version: VERSION,
+                chains: vec![chain.clone()],
+            },
+            date(5),
+        );
+
+        assert!(org.starts_with("* TODO read :books:ARCHIVE:\n  SCHEDULED: <2024-01-03 Wed .+1d>\n"));
+        assert!(org.contains("  :LAST_REPEAT: [2024-01-02 Tue 21:30]\n"));
+
+        let backup = read(&org).unwrap();
+
+        assert_eq!(backup.chains, vec![chain]);
+    }
+
+    #[test]
+    fn only_habits_are_read() {
+        let org = "#+TITLE: Habits\n\
+                   * Project\n\
+                   ** TODO [#A] Stretch :health:\n   \
+                      SCHEDULED: <2024-01-05 Fri .+2d/4d>\n   \
+                      :PROPERTIES:\n   \
+                      :STYLE:    habit\n   \
+                      :LAST_REPEAT: [2024-01-03 Wed 08:00]\n   \
+                      :END:\n   \
+                      :LOGBOOK:\n   \
+                      - Note taken on [2024-01-01 Mon 09:00] \\\\ Sore\n   \
+                      - State \"DONE\"       from \"TODO\"       [2024-01-01 Mon 08:00]\n   \
+                      :END:\n\
+                   ** TODO Call mom\n   \
+                      SCHEDULED: <2024-01-07 Sun +1w>\n";
+
+        let backup = read(org).unwrap();
+        assert_eq!(backup.chains.len(), 1);
+
+        let chain = &backup.chains[0];
+        assert_eq!(chain.name, "Stretch");
+        assert_eq!(chain.tags, vec!["health".to_string()]);
+        assert_eq!(chain.goal.as_deref(), Some("once every 2 days"));
+        // The completion which was only recorded in LAST_REPEAT counts too.
+        let dates: Vec<NaiveDate> = chain.links.iter().map(|link| link.date).collect();
+        assert_eq!(dates, vec![date(1), date(3)]);
+        assert_eq!(chain.notes[0].text, "Sore");
+    }
+}
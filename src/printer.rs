@@ -1,70 +1,267 @@
-use super::structs::{Chain, Day, Link, Streak};
+use super::logic;
+use super::config::Config;
+use super::structs::{
+    Chain, DatabaseInfo, Day, ImportSummary, Kind, Link, Note, Operation, State, Streak, SyncSummary,
+};
+use std::path::Path;
+use std::io::{self, IsTerminal};
 
-pub fn print_days(days: &Vec<Day>) {
-    if days.len() == 0 {
-        return
+const COLORS: &[(&str, &str)] = &[
+    ("black", "30"),
+    ("red", "31"),
+    ("green", "32"),
+    ("yellow", "33"),
+    ("blue", "34"),
+    ("magenta", "35"),
+    ("cyan", "36"),
+    ("white", "37"),
+];
+
+/// The ANSI escape code of a named color.
+pub fn color_code(color: &str) -> Option<&'static str> {
+    COLORS
+        .iter()
+        .find(|(name, _)| *name == color)
+        .map(|(_, code)| *code)
+}
+
+/// Format the name of a chain with its icon, in its color when printing to
+/// a terminal.
+fn format_name(name: &str, color: &Option<String>, icon: &Option<String>, config: &Config) -> String {
+    let name = match icon {
+        Some(icon) => format!("{} {}", icon, name),
+        None => name.to_string(),
+    };
+
+    paint(name, color.as_deref().and_then(color_code), config)
+}
+
+/// Wrap text in an ANSI color when the theme uses colors and the output is
+/// a terminal.
+fn paint(text: String, code: Option<&str>, config: &Config) -> String {
+    match code {
+        Some(code) if config.is_colored() && io::stdout().is_terminal() => {
+            format!("\x1b[{}m{}\x1b[0m", code, text)
+        }
+        _ => text,
+    }
+}
+
+/// The color of a day in the day strip.
+fn state_color(state: State) -> Option<&'static str> {
+    match state {
+        State::Done => color_code("green"),
+        State::Missed | State::Failed => color_code("red"),
+        State::Skipped | State::Excused => color_code("yellow"),
+        State::Untracked => None,
+    }
+}
+
+/// Format a value without a trailing `.0` for whole numbers.
+pub fn format_value(value: f64) -> String {
+    if value.fract() == 0.0 {
+        format!("{}", value as i64)
+    } else {
+        format!("{:.1}", value)
+    }
+}
+
+fn format_amount(value: f64, unit: &Option<String>) -> String {
+    match unit {
+        Some(unit) => format!("{} {}", format_value(value), unit),
+        None => format_value(value),
+    }
+}
+
+fn format_state(state: State) -> &'static str {
+    match state {
+        State::Done => "XX",
+        State::Missed => "--",
+        State::Failed => "FF",
+        State::Skipped => "SS",
+        State::Excused => "EE",
+        State::Untracked => "  ",
+    }
+}
+
+pub fn print_days(days: &[Day], config: &Config) {
+    print_days_with_breakdown(days, &[], config);
+}
+
+/// The separator printed before a day, weeks are separated when the config
+/// sets the first day of the week.
+fn separator(days: &[Day], index: usize, config: &Config) -> &'static str {
+    if index > 0 && config.week_start() == Some(days[index].weekday) {
+        "| "
+    } else {
+        ""
+    }
+}
+
+/// Print the days of a chain followed by a row for each of its chains.
+fn print_days_with_breakdown(days: &[Day], children: &[(Streak, Vec<Day>)], config: &Config) {
+    if days.is_empty() {
+        return;
     }
-    
+
+    let has_values = days.iter().any(|day| day.value.is_some());
+    let width = days
+        .iter()
+        .filter_map(|day| day.value)
+        .map(|value| format_value(value).len())
+        .fold(2, usize::max);
+
+    let states = |days: &[Day]| {
+        let mut row = String::new();
+
+        for (index, day) in days.iter().enumerate() {
+            row.push_str(separator(days, index, config));
+            row.push_str(&paint(
+                format!("{:>width$}", format_state(day.state), width = width),
+                state_color(day.state),
+                config,
+            ));
+            row.push(' ');
+        }
+
+        row
+    };
+
     let mut dates = String::new();
-    let mut is_done = String::new();
+    let mut values = String::new();
 
-    for day in days.iter() {
-        dates.push_str(&format!("{:02} ", day.day));
-        is_done.push_str(&format!("{:>2} ", if day.is_done { "XX" } else { "--" }));
+    for (index, day) in days.iter().enumerate() {
+        dates.push_str(separator(days, index, config));
+        dates.push_str(&format!("{:0>width$} ", day.day, width = width));
+        values.push_str(separator(days, index, config));
+        values.push_str(&format!(
+            "{:>width$} ",
+            day.value.map(format_value).unwrap_or_default(),
+            width = width
+        ));
     }
 
     println!("{}", dates);
-    println!("{}", is_done);
-    println!("");
+    println!("{}", states(days));
+    if has_values {
+        println!("{}", values);
+    }
+    for (streak, days) in children.iter() {
+        println!(
+            "{}{} ({})",
+            states(days),
+            format_name(&streak.name, &streak.color, &streak.icon, config),
+            streak.streak
+        );
+    }
+    println!();
 }
 
-pub fn print_streaks(streaks: &Vec<(Streak, Vec<Day>)>) {
-    if streaks.len() > 0 {
-        for (streak, days) in streaks.iter() {
-            println!("{}", streak.name);
-            println!("Current streak: {}", streak.streak);
-            println!("Longest streak: {}", streak.longest_streak);
+fn print_totals(streak: &Streak) {
+    if let Some(total) = streak.total {
+        println!("Total: {}", format_amount(total, &streak.unit));
+    }
 
-            print_days(&days);
+    if let Some(average) = streak.average {
+        println!("Average: {}", format_amount(average, &streak.unit));
+    }
+}
+
+pub fn print_streaks(streaks: &[(Streak, Vec<Day>)], config: &Config) {
+    if !streaks.is_empty() {
+        for (streak, days) in streaks.iter() {
+            print_streak(streak, days, config);
         }
     } else {
         println!("Congratulations. You completed all of your chains for today.");
     }
 }
 
-pub fn print_streak(streak: &Streak, days: &Vec<Day>) {
-    println!("{}", streak.name);
-    println!("Current streak: {}", streak.streak);
-    println!("Longest streak: {}", streak.longest_streak);
+pub fn print_streak(streak: &Streak, days: &[Day], config: &Config) {
+    println!("{}", format_name(&streak.name, &streak.color, &streak.icon, config));
+    if let Some(description) = &streak.description {
+        println!("{}", description);
+    }
+    if let Some(goal) = &streak.goal {
+        println!("Goal: {}", goal);
+    }
+    if let Some(created) = streak.created {
+        println!("Created: {}", config.format_date(created));
+    }
+    if let Some(start) = streak.start {
+        println!("Started: {}", config.format_date(start));
+    }
+    if streak.kind == Kind::Negative {
+        println!("Days since last relapse: {}", streak.streak);
+        println!("Longest clean streak: {}", streak.longest_streak);
+    } else {
+        println!("Current streak: {}", streak.streak);
+        println!("Longest streak: {}", streak.longest_streak);
+        println!(
+            "Days: {} done, {} missed, {} failed, {} skipped, {} excused",
+            streak.done_days,
+            streak.missed_days,
+            streak.failed_days,
+            streak.skipped_days,
+            streak.excused_days
+        );
+    }
+    print_totals(streak);
 
-    print_days(days);
+    print_days_with_breakdown(days, &streak.children, config);
 }
 
-pub fn print_streaks_machine(streaks: &Vec<(Streak, Vec<Day>)>) {
+pub fn print_streaks_machine(streaks: &[(Streak, Vec<Day>)]) {
     println!("{}", streaks.len());
 }
 
-pub fn print_add(chain: &Chain, link: &Link) {
+pub fn print_add(chain: &Chain, link: &Link, config: &Config) {
+    if chain.kind == Kind::Negative {
+        println!(
+            "Recorded relapse on \"{}\" for \"{}\"",
+            config.format_date(link.date),
+            chain.name
+        );
+        return;
+    }
+
+    match link.value {
+        Some(value) => println!(
+            "Added {} for \"{}\" to \"{}\"",
+            format_amount(value, &chain.unit),
+            config.format_date(link.date),
+            chain.name
+        ),
+        None => println!(
+            "Added link for \"{}\" to \"{}\"",
+            config.format_date(link.date),
+            chain.name
+        ),
+    }
+}
+
+pub fn print_mark(chain: &Chain, link: &Link, config: &Config) {
     println!(
-        "Added link for \"{}\" to \"{}\"",
-        link.date.format("%Y-%m-%d"),
+        "Marked \"{}\" as {} for \"{}\"",
+        config.format_date(link.date),
+        link.state.as_str(),
         chain.name
     );
 }
 
-pub fn print_mv(chain: &Chain, current: &Link, new: &Link) {
+pub fn print_mv(chain: &Chain, current: &Link, new: &Link, config: &Config) {
     println!(
         "Update link from \"{}\" to \"{}\" for \"{}\"",
-        current.date.format("%Y-%m-%d"),
-        new.date.format("%Y-%m-%d"),
+        config.format_date(current.date),
+        config.format_date(new.date),
         chain.name
     );
 }
 
-pub fn print_rm(chain: &Chain, link: &Link) {
+pub fn print_rm(chain: &Chain, link: &Link, config: &Config) {
     println!(
         "Deleted link for \"{}\" from \"{}\"",
-        link.date.format("%Y-%m-%d"),
+        config.format_date(link.date),
         chain.name
     );
 }
@@ -73,6 +270,18 @@ pub fn print_add_chain(chain: &Chain) {
     println!("Added \"{}\"", chain.name);
 }
 
+pub fn print_edit_chain(chain: &Chain) {
+    println!("Updated \"{}\"", chain.name);
+}
+
+pub fn print_archive(chain: &Chain) {
+    if chain.archived {
+        println!("Archived \"{}\"", chain.name);
+    } else {
+        println!("Restored \"{}\"", chain.name);
+    }
+}
+
 pub fn print_rename_chain(chain: &Chain, new: &str) {
     println!("Renamed \"{}\" to \"{}\"", &chain.name, new);
 }
@@ -81,9 +290,404 @@ pub fn print_rm_chain(chain: &Chain) {
     println!("Deleted \"{}\"", &chain.name);
 }
 
-pub fn print_ls(chains: &Vec<Chain>) {
+pub fn print_ls(chains: &[Chain], all: &[Chain], config: &Config) {
     for chain in chains.iter() {
-        println!("{}", chain.name);
+        print_chain(chain, all, config);
     }
 }
 
+/// Print the chains under a heading for each tag.
+pub fn print_ls_grouped(groups: &[(String, Vec<Chain>)], all: &[Chain], config: &Config) {
+    for (tag, chains) in groups.iter() {
+        if tag.is_empty() {
+            println!("(untagged)");
+        } else {
+            println!("#{}", tag);
+        }
+
+        for chain in chains.iter() {
+            print!("  ");
+            print_chain(chain, all, config);
+        }
+    }
+}
+
+pub fn print_tags(tags: &[(String, i64)]) {
+    for (tag, count) in tags.iter() {
+        println!("#{} ({})", tag, count);
+    }
+}
+
+pub fn print_tag(chain: &Chain, tags: &[&str], is_tagged: bool) {
+    let tags: Vec<String> = tags.iter().map(|tag| format!("#{}", tag)).collect();
+
+    if is_tagged {
+        println!("Tagged \"{}\" with {}", chain.name, tags.join(" "));
+    } else {
+        println!("Removed {} from \"{}\"", tags.join(" "), chain.name);
+    }
+}
+
+pub fn print_alias(chain: &Chain, alias: &str) {
+    println!("\"{}\" now refers to \"{}\"", alias, chain.name);
+}
+
+pub fn print_unalias(alias: &str) {
+    println!("Removed alias \"{}\"", alias);
+}
+
+pub fn print_aliases(aliases: &[(String, String)]) {
+    for (alias, name) in aliases.iter() {
+        println!("{} -> {}", alias, name);
+    }
+}
+
+/// Print a chain with a summary of its settings, `all` is used to look up
+/// the names of the chains a composite chain is made of.
+fn print_chain(chain: &Chain, all: &[Chain], config: &Config) {
+    let mut goals: Vec<String> = Vec::new();
+
+    if let Some(rule) = chain.rule {
+        let children: Vec<&str> = chain
+            .children
+            .iter()
+            .filter_map(|id| all.iter().find(|chain| chain.id == *id))
+            .map(|chain| chain.name.as_str())
+            .collect();
+
+        goals.push(format!("{} of {}", rule.as_str(), children.join(", ")));
+    }
+
+    match (&chain.unit, chain.target) {
+        (unit, Some(target)) => goals.push(format!("{} per day", format_amount(target, unit))),
+        (Some(unit), None) => goals.push(unit.to_string()),
+        (None, None) => (),
+    }
+
+    if let Some(quota) = chain.quota {
+        goals.push(format!("{}x per day", quota));
+    }
+
+    if chain.kind == Kind::Negative {
+        goals.push("days since".to_string());
+    }
+
+    if let Some(start) = chain.start {
+        goals.push(format!("started {}", config.format_date(start)));
+    }
+
+    if let Some(end) = chain.end {
+        goals.push(format!("ends {}", config.format_date(end)));
+    }
+
+    if chain.archived {
+        goals.push("archived".to_string());
+    }
+
+    let mut line = format_name(&chain.name, &chain.color, &chain.icon, config);
+
+    if !goals.is_empty() {
+        line.push_str(&format!(" ({})", goals.join(", ")));
+    }
+
+    if !chain.tags.is_empty() {
+        let tags: Vec<String> = chain.tags.iter().map(|tag| format!("#{}", tag)).collect();
+        line.push_str(&format!(" {}", tags.join(" ")));
+    }
+
+    if let Some(description) = &chain.description {
+        line.push_str(&format!(" - {}", description));
+    }
+
+    if let Some(created) = chain.created {
+        line.push_str(&format!(" [created {}]", config.format_date(created)));
+    }
+
+    println!("{}", line);
+}
+
+pub fn print_note(chain: &Chain, note: &Note, config: &Config) {
+    println!(
+        "Added note for \"{}\" to \"{}\"",
+        config.format_date(note.date),
+        chain.name
+    );
+}
+
+/// Print the links of a chain from newest to oldest with their notes.
+pub fn print_log(chain: &Chain, links: &[Link], notes: &[Note], config: &Config) {
+    println!("{}", chain.name);
+
+    for total in logic::daily_totals(links).iter().rev() {
+        let mark = match chain.kind {
+            Kind::Positive => format_state(logic::day_state(chain, total)),
+            Kind::Negative => format_state(State::Missed),
+        };
+        let mut line = format!("{} {}", config.format_date(total.date), mark);
+
+        if let Some(quota) = chain.quota {
+            line.push_str(&format!(" {}/{}", total.count, quota));
+        }
+
+        if let Some(value) = total.value {
+            line.push_str(&format!(" {}", format_amount(value, &chain.unit)));
+        }
+
+        println!("{}", line);
+
+        let times: Vec<String> = links
+            .iter()
+            .filter(|link| link.date == total.date)
+            .filter_map(|link| link.time)
+            .map(|time| time.format("%H:%M").to_string())
+            .collect();
+
+        if chain.quota.is_some() && !times.is_empty() {
+            println!("    {}", times.join(" "));
+        }
+
+        for link in links.iter().filter(|link| link.date == total.date) {
+            if let Some(reason) = &link.reason {
+                println!("    {}: {}", link.state.as_str(), reason);
+            }
+        }
+
+        for note in notes.iter().filter(|note| note.date == total.date) {
+            println!("    {}", note.text);
+        }
+    }
+}
+
+pub fn print_search(results: &[(Chain, Note)], config: &Config) {
+    for (chain, note) in results.iter() {
+        println!(
+            "{} {}: {}",
+            config.format_date(note.date),
+            chain.name,
+            note.text
+        );
+    }
+}
+
+pub fn print_operations(operations: &[Operation]) {
+    for operation in operations.iter() {
+        let state = if operation.state == "applied" {
+            String::new()
+        } else {
+            format!(" ({})", operation.state)
+        };
+
+        println!(
+            "{} {} {}: {}{}",
+            operation.id,
+            operation.time.format("%Y-%m-%d %H:%M:%S"),
+            operation.user,
+            operation.command,
+            state
+        );
+    }
+}
+
+pub fn print_undo(operations: &[Operation]) {
+    if operations.is_empty() {
+        println!("Nothing to undo");
+    }
+
+    for operation in operations.iter() {
+        println!("Undid \"{}\"", operation.command);
+    }
+}
+
+pub fn print_redo(operations: &[Operation]) {
+    if operations.is_empty() {
+        println!("Nothing to redo");
+    }
+
+    for operation in operations.iter() {
+        println!("Redid \"{}\"", operation.command);
+    }
+}
+
+pub fn print_db_info(info: &DatabaseInfo) {
+    println!("Path: {}", info.path.display());
+    println!("Size: {} bytes", info.size);
+    println!("Schema version: {}", info.version);
+    println!("Chains: {}", info.chains);
+    println!("Links: {}", info.links);
+    println!("Notes: {}", info.notes);
+    println!("Operations: {}", info.operations);
+}
+
+pub fn print_import(summary: &ImportSummary) {
+    for name in summary.added.iter() {
+        println!("Added \"{}\"", name);
+    }
+
+    for conflict in summary.conflicts.iter() {
+        println!("{}", conflict);
+    }
+
+    println!(
+        "Imported {} chain(s), merged {} chain(s)",
+        summary.chains_added, summary.chains_merged
+    );
+    println!(
+        "Imported {} link(s), skipped {} duplicate link(s)",
+        summary.links_added, summary.links_skipped
+    );
+    println!(
+        "Imported {} note(s), skipped {} duplicate note(s)",
+        summary.notes_added, summary.notes_skipped
+    );
+}
+
+pub fn print_sync(path: &Path, summary: &SyncSummary) {
+    println!("Merged {}", path.display());
+
+    for name in summary.added.iter() {
+        println!("Added \"{}\"", name);
+    }
+    for (from, to) in summary.renamed.iter() {
+        println!("Renamed \"{}\" to \"{}\"", from, to);
+    }
+    for name in summary.updated.iter() {
+        println!("Updated \"{}\"", name);
+    }
+    for name in summary.deleted.iter() {
+        println!("Deleted \"{}\"", name);
+    }
+
+    for conflict in summary.conflicts.iter() {
+        println!("{}", conflict);
+    }
+
+    println!(
+        "Added {} link(s), updated {}, deleted {}, skipped {} duplicate link(s)",
+        summary.links_added, summary.links_updated, summary.links_deleted, summary.links_skipped
+    );
+    println!(
+        "Added {} note(s), updated {}, deleted {}, skipped {} duplicate note(s)",
+        summary.notes_added, summary.notes_updated, summary.notes_deleted, summary.notes_skipped
+    );
+}
+
+pub fn print_exchange(with: &str, received: usize, sent: usize) {
+    println!("Synced with {}: received {} change(s), sent {}", with, received, sent);
+}
+
+pub fn print_listening(address: &str) {
+    println!("Listening on {}", address);
+}
+
+pub fn print_no_token() {
+    eprintln!("Warning: every device on the network can change the chains, protect them with --token");
+}
+
+pub fn print_request(method: &str, url: &str, status: u16) {
+    println!("{} {} {}", method, url, status);
+}
+
+pub fn print_convert(count: usize, backend: &str, path: &Path) {
+    println!("Copied {} chain(s) to {}", count, path.display());
+    println!(
+        "Use it with: c config set backend {} && c config set database {}",
+        backend,
+        path.display()
+    );
+}
+
+pub fn print_moved_database(from: &Path, to: &Path) {
+    println!("Moved the database from {} to {}", from.display(), to.display());
+}
+
+pub fn print_kept_database(path: &Path, config_path: &Path) {
+    println!(
+        "Keeping the database at {}, set in {}",
+        path.display(),
+        config_path.display()
+    );
+}
+
+pub fn print_profiles(profiles: &[String], active: &str) {
+    for profile in profiles.iter() {
+        let marker = if profile == active { "*" } else { " " };
+
+        println!("{} {}", marker, profile);
+    }
+}
+
+pub fn print_create_profile(profile: &str, path: &Path) {
+    println!("Created profile \"{}\" at {}", profile, path.display());
+}
+
+pub fn print_use_profile(profile: &str) {
+    println!("Using profile \"{}\"", profile);
+}
+
+pub fn print_profile_header(profile: &str) {
+    println!("== {} ==", profile);
+}
+
+pub fn print_config(settings: &[(&str, String)]) {
+    for (key, value) in settings.iter() {
+        println!("{} = {}", key, value);
+    }
+}
+
+pub fn print_setting(value: &str) {
+    println!("{}", value);
+}
+
+pub fn print_set_setting(key: &str, value: &str) {
+    if value.is_empty() {
+        println!("Unset {}", key);
+    } else {
+        println!("Set {} to \"{}\"", key, value);
+    }
+}
+
+/// Print completion candidates for the shell, one per line.
+pub fn print_candidates(candidates: &[String]) {
+    for candidate in candidates.iter() {
+        println!("{}", candidate);
+    }
+}
+
+pub fn print_aborted() {
+    println!("Aborted");
+}
+
+pub fn print_dry_run_write(path: &Path) {
+    println!("Dry run, {} was not written.", path.display());
+}
+
+/// Summarize the changes a dry run would have made, e.g. "2 deleted from links".
+pub fn print_dry_run(changes: &[String]) {
+    let mut counts: Vec<(String, usize)> = Vec::new();
+
+    for change in changes.iter() {
+        let words: Vec<&str> = change.split_whitespace().collect();
+        let summary = match words.as_slice() {
+            ["INSERT", "INTO", table, ..] => format!("added to {}", table),
+            ["UPDATE", table, ..] => format!("updated in {}", table),
+            ["DELETE", "FROM", table, ..] => format!("deleted from {}", table),
+            _ => continue,
+        };
+
+        match counts.iter_mut().find(|(existing, _)| *existing == summary) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((summary, 1)),
+        }
+    }
+
+    if counts.is_empty() {
+        println!("Dry run, nothing was saved.");
+    } else {
+        let counts: Vec<String> = counts
+            .iter()
+            .map(|(summary, count)| format!("{} {}", count, summary))
+            .collect();
+
+        println!("Dry run, nothing was saved. Would have changed: {}", counts.join(", "));
+    }
+}
@@ -0,0 +1,490 @@
+use super::backup::{self, Backup, BackupChain, BackupLink, VERSION};
+use super::chain_error::ChainError;
+use super::config::Config;
+use super::database;
+use super::history;
+use super::logic;
+use super::oplog;
+use super::printer;
+use super::store::Store;
+use super::structs::{Chain, Link, State};
+use super::sync;
+use anyhow::{anyhow, Result};
+use chrono::{Duration, NaiveDate, NaiveTime};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::error::Error;
+use std::fmt;
+use std::io::Read;
+use std::net::TcpListener;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// The longest body a request may have, far more than any change needs.
+const MAX_BODY: usize = 64 * 1024;
+
+/// A chain with its settings, streak and recent days.
+#[derive(Serialize)]
+struct ChainResponse {
+    #[serde(flatten)]
+    chain: BackupChain,
+    streak: i32,
+    longest_streak: i32,
+    total: Option<f64>,
+    average: Option<f64>,
+    due: bool,
+    days: Vec<DayResponse>,
+}
+
+#[derive(Serialize)]
+struct DayResponse {
+    date: NaiveDate,
+    state: String,
+    value: Option<f64>,
+}
+
+/// The body of `POST /chains/{name}/links`, every field is optional.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct NewLink {
+    date: Option<String>,
+    time: Option<String>,
+    value: Option<f64>,
+    note: Option<String>,
+}
+
+/// The body of `PATCH /chains/{name}`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Rename {
+    name: String,
+}
+
+/// The body of `PATCH /chains/{name}/links/{date}`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Move {
+    date: String,
+}
+
+/// An error answered with its own status code.
+#[derive(Debug)]
+struct ApiError {
+    status: u16,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: u16, message: &str) -> ApiError {
+        ApiError {
+            status,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for ApiError {}
+
+/// The status code of an error, mistakes in a request are bad requests and
+/// anything else is a failure of the server.
+fn status(err: &anyhow::Error) -> u16 {
+    match err.downcast_ref::<ApiError>() {
+        Some(err) => err.status,
+        None if err.is::<ChainError>() || err.is::<chrono::ParseError>() => 400,
+        None => 500,
+    }
+}
+
+/// Answer requests to the JSON API on `address` until stopped.
+///
+/// When a `token` is given every request needs it as a bearer token. Each
+/// request opens the store and runs in its own transaction, so it sees the
+/// changes of commands run meanwhile. Changes are saved and recorded like
+/// the commands making them, so they can be undone and synced. With
+/// `verbose` every request is printed.
+pub fn serve(
+    store: &dyn Store,
+    config: &Config,
+    address: &str,
+    token: Option<&str>,
+    verbose: bool,
+) -> Result<()> {
+    let listener = TcpListener::bind(address)?;
+
+    printer::print_listening(&listener.local_addr()?.to_string());
+
+    // Anyone who can connect could change every chain.
+    if token.is_none() && !listener.local_addr()?.ip().is_loopback() {
+        printer::print_no_token();
+    }
+
+    let server = Server::from_listener(listener, None).map_err(|err| anyhow!(err))?;
+
+    for mut request in server.incoming_requests() {
+        let (status, body) = match handle(store, config, token, &mut request) {
+            Ok(response) => response,
+            Err(err) => (status(&err), json!({ "error": err.to_string() })),
+        };
+
+        if verbose {
+            printer::print_request(request.method().as_str(), request.url(), status);
+        }
+
+        let body = match body {
+            Value::Null => String::new(),
+            body => body.to_string(),
+        };
+        let response = Response::from_string(body)
+            .with_status_code(status)
+            .with_header(Header::from_bytes("Content-Type", "application/json").unwrap());
+
+        if let Err(err) = request.respond(response) {
+            eprintln!("Failed to respond: {}", err);
+        }
+    }
+
+    Ok(())
+}
+
+fn handle(
+    store: &dyn Store,
+    config: &Config,
+    token: Option<&str>,
+    request: &mut Request,
+) -> Result<(u16, Value)> {
+    if let Some(token) = token {
+        let given = request
+            .headers()
+            .iter()
+            .find(|header| header.field.equiv("Authorization"))
+            .map(|header| header.value.as_str());
+
+        if !oplog::is_token(given, &format!("Bearer {}", token)) {
+            return Err(ApiError::new(401, "Missing or wrong token").into());
+        }
+    }
+
+    // Bodies are read whole, so their length has to be known up front.
+    let has_body = matches!(request.method(), Method::Post | Method::Patch)
+        || request
+            .headers()
+            .iter()
+            .any(|header| header.field.equiv("Transfer-Encoding"));
+
+    match request.body_length() {
+        Some(length) if length > MAX_BODY => {
+            let message = format!("The body is longer than {} bytes", MAX_BODY);
+            return Err(ApiError::new(413, &message).into());
+        }
+        None if has_body => return Err(ApiError::new(411, "The request needs a Content-Length").into()),
+        _ => (),
+    }
+
+    let mut body = String::new();
+    request
+        .as_reader()
+        .take(MAX_BODY as u64)
+        .read_to_string(&mut body)
+        .map_err(|_| ApiError::new(400, "The body is not valid UTF-8"))?;
+
+    let method = request.method().clone();
+    let url = request.url().to_string();
+    let path: Vec<String> = url
+        .split('?')
+        .next()
+        .unwrap_or_default()
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(decode)
+        .collect();
+    let path: Vec<&str> = path.iter().map(|segment| segment.as_str()).collect();
+
+    let is_change = method != Method::Get;
+    let mut conn = store.open()?;
+    let transaction = conn.transaction()?;
+    let changes = super::total_changes(&transaction)?;
+
+    if is_change {
+        history::begin_operation(&transaction, &format!("serve {} {}", method, url))?;
+    }
+
+    let response = route(&transaction, config, &method, &path, &body)?;
+
+    super::commit(transaction, store, config, is_change, changes)?;
+
+    Ok(response)
+}
+
+fn route(conn: &Connection, config: &Config, method: &Method, path: &[&str], body: &str) -> Result<(u16, Value)> {
+    match (method, path) {
+        (Method::Get, ["chains"]) => list_chains(conn, config),
+        (Method::Post, ["chains"]) => add_chain(conn, config, body),
+        (Method::Get, ["chains", name]) => Ok((200, chain_value(conn, &find_chain(conn, name)?, config)?)),
+        (Method::Patch, ["chains", name]) => rename_chain(conn, config, name, body),
+        (Method::Delete, ["chains", name]) => rm_chain(conn, name),
+        (Method::Get, ["chains", name, "links"]) => links(conn, name),
+        (Method::Post, ["chains", name, "links"]) => add(conn, config, name, body),
+        (Method::Patch, ["chains", name, "links", date]) => mv(conn, config, name, date, body),
+        (Method::Delete, ["chains", name, "links", date]) => rm(conn, config, name, date),
+        (Method::Get, ["due"]) => due(conn, config),
+        _ => Err(ApiError::new(404, "Not found").into()),
+    }
+}
+
+/// Decode the percent-encoded bytes of a path segment, e.g. `%20`.
+fn decode(segment: &str) -> String {
+    let bytes = segment.as_bytes();
+    let mut decoded: Vec<u8> = Vec::new();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let byte = match bytes[i] {
+            b'%' => segment
+                .get(i + 1..i + 3)
+                .filter(|hex| hex.bytes().all(|byte| byte.is_ascii_hexdigit()))
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+
+        match byte {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+fn parse_body<'a, T: Deserialize<'a>>(body: &'a str) -> Result<T> {
+    serde_json::from_str(body).map_err(|err| ApiError::new(400, &format!("Invalid body: {}", err)).into())
+}
+
+/// Find a chain the way the commands do, by name, alias, id or prefix.
+fn find_chain(conn: &Connection, name: &str) -> Result<Chain> {
+    database::get_chain_for_name(conn, name).map_err(|err| match err.downcast::<ChainError>() {
+        Ok(err) => ApiError::new(404, &err.to_string()).into(),
+        Err(err) => err,
+    })
+}
+
+fn check_name_is_free(conn: &Connection, name: &str) -> Result<()> {
+    if name.trim().is_empty() {
+        return Err(ApiError::new(400, "The name can't be empty").into());
+    }
+
+    if database::find_chain_for_name(conn, name)?.is_some() {
+        return Err(ApiError::new(409, &format!("A chain named \"{}\" already exists", name)).into());
+    }
+
+    Ok(())
+}
+
+/// The links of a chain on `date`, answered with 404 if there are none.
+fn day_links(conn: &Connection, config: &Config, chain: &Chain, date: &str) -> Result<Vec<Link>> {
+    let date = super::parse_date(date, config)?;
+    let links: Vec<Link> = database::get_links_for_chain_id(conn, chain.id as i32)?
+        .into_iter()
+        .filter(|link| link.date == date)
+        .collect();
+
+    if links.is_empty() {
+        return Err(ApiError::new(404, &format!("\"{}\" has no link on {}", chain.name, date)).into());
+    }
+
+    Ok(links)
+}
+
+/// A link standing for every link on the day of `link`.
+fn day_link(link: &Link) -> Link {
+    Link {
+        id: -1,
+        chain_id: link.chain_id,
+        date: link.date,
+        time: None,
+        value: None,
+        state: State::Done,
+        reason: None,
+    }
+}
+
+fn chain_response(conn: &Connection, chain: &Chain, aliases: &[(String, String)], config: &Config) -> Result<ChainResponse> {
+    let links = super::get_links(conn, chain)?;
+    let streak = logic::calculate_streak(chain, &links, config.today());
+    let days = logic::create_days(chain, &links, config.days, config.today());
+
+    // The days end today.
+    let start_date = config.today() - Duration::days(days.len() as i64 - 1);
+
+    Ok(ChainResponse {
+        chain: backup::export_settings(conn, chain, aliases)?,
+        streak: streak.streak,
+        longest_streak: streak.longest_streak,
+        total: streak.total,
+        average: streak.average,
+        due: super::is_due(chain, &links, config.today()),
+        days: days
+            .iter()
+            .zip(start_date.iter_days())
+            .map(|(day, date)| DayResponse {
+                date,
+                state: day.state.as_str().to_string(),
+                value: day.value,
+            })
+            .collect(),
+    })
+}
+
+fn chain_value(conn: &Connection, chain: &Chain, config: &Config) -> Result<Value> {
+    let aliases = database::get_aliases(conn)?;
+
+    Ok(serde_json::to_value(chain_response(conn, chain, &aliases, config)?)?)
+}
+
+fn list_chains(conn: &Connection, config: &Config) -> Result<(u16, Value)> {
+    let aliases = database::get_aliases(conn)?;
+    let mut chains: Vec<ChainResponse> = Vec::new();
+
+    for chain in database::get_chains(conn)?.iter() {
+        chains.push(chain_response(conn, chain, &aliases, config)?);
+    }
+
+    Ok((200, serde_json::to_value(chains)?))
+}
+
+fn due(conn: &Connection, config: &Config) -> Result<(u16, Value)> {
+    let aliases = database::get_aliases(conn)?;
+    let mut chains: Vec<ChainResponse> = Vec::new();
+
+    for chain in database::get_chains(conn)?.iter() {
+        let chain = chain_response(conn, chain, &aliases, config)?;
+
+        if chain.due {
+            chains.push(chain);
+        }
+    }
+
+    Ok((200, serde_json::to_value(chains)?))
+}
+
+/// Add a chain given in the format of a backup, with its tags, aliases and
+/// any links and notes.
+fn add_chain(conn: &Connection, config: &Config, body: &str) -> Result<(u16, Value)> {
+    let mut chain: BackupChain = parse_body(body)?;
+
+    check_name_is_free(conn, &chain.name)?;
+
+    if chain.created.is_none() {
+        chain.created = Some(config.today());
+    }
+
+    let name = chain.name.clone();
+
+    backup::import(
+        conn,
+        &Backup {
+            version: VERSION,
+            chains: vec![chain],
+        },
+        false,
+    )?;
+
+    Ok((201, chain_value(conn, &find_chain(conn, &name)?, config)?))
+}
+
+fn rename_chain(conn: &Connection, config: &Config, name: &str, body: &str) -> Result<(u16, Value)> {
+    let chain = find_chain(conn, name)?;
+    let rename: Rename = parse_body(body)?;
+
+    if rename.name != chain.name {
+        check_name_is_free(conn, &rename.name)?;
+        database::edit_chain_for_name(conn, &chain, &rename.name)?;
+    }
+
+    Ok((200, chain_value(conn, &find_chain(conn, &rename.name)?, config)?))
+}
+
+/// Delete a chain with its links and notes. Unlike the other requests it
+/// needs the full name, a prefix could delete the wrong chain.
+fn rm_chain(conn: &Connection, name: &str) -> Result<(u16, Value)> {
+    let chain = match database::find_chain_for_name(conn, name)? {
+        Some(chain) => chain,
+        None => return Err(ApiError::new(404, &format!("No chain named \"{}\"", name)).into()),
+    };
+
+    sync::delete_chain(conn, &chain)?;
+
+    Ok((204, Value::Null))
+}
+
+fn links(conn: &Connection, name: &str) -> Result<(u16, Value)> {
+    let chain = find_chain(conn, name)?;
+    let links: Vec<BackupLink> = super::get_links(conn, &chain)?
+        .into_iter()
+        .map(|link| BackupLink {
+            date: link.date,
+            time: link.time,
+            value: link.value,
+            state: link.state.as_str().to_string(),
+            reason: link.reason,
+        })
+        .collect();
+
+    Ok((200, serde_json::to_value(links)?))
+}
+
+fn add(conn: &Connection, config: &Config, name: &str, body: &str) -> Result<(u16, Value)> {
+    let chain = find_chain(conn, name)?;
+    let link: NewLink = match body.trim() {
+        "" => NewLink::default(),
+        body => parse_body(body)?,
+    };
+
+    let date = match &link.date {
+        Some(date) => super::parse_date(date, config)?,
+        None => config.today(),
+    };
+    let time = match &link.time {
+        Some(time) => Some(NaiveTime::parse_from_str(time, "%H:%M")?),
+        None => None,
+    };
+
+    super::add_link(conn, &chain, date, time, link.value, link.note.as_deref(), config)?;
+
+    Ok((201, chain_value(conn, &chain, config)?))
+}
+
+fn mv(conn: &Connection, config: &Config, name: &str, date: &str, body: &str) -> Result<(u16, Value)> {
+    let chain = find_chain(conn, name)?;
+    let links = day_links(conn, config, &chain, date)?;
+    let new: Move = parse_body(body)?;
+
+    let new = Link {
+        date: super::parse_date(&new.date, config)?,
+        ..day_link(&links[0])
+    };
+
+    database::update(conn, &day_link(&links[0]), &new)?;
+
+    Ok((200, chain_value(conn, &chain, config)?))
+}
+
+fn rm(conn: &Connection, config: &Config, name: &str, date: &str) -> Result<(u16, Value)> {
+    let chain = find_chain(conn, name)?;
+    let links = day_links(conn, config, &chain, date)?;
+
+    database::delete_link(conn, &links[0])?;
+
+    Ok((200, chain_value(conn, &chain, config)?))
+}
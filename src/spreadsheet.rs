@@ -0,0 +1,321 @@
+use super::backup::{Backup, BackupChain, BackupLink, BackupNote, VERSION};
+use super::chain_error::ChainError;
+use super::structs::State;
+use anyhow::Result;
+use chrono::{NaiveDate, NaiveTime};
+use std::io::{Read, Write};
+
+use super::{FORMAT, TIME_FORMAT};
+
+/// The fields of a row, which are also the column names of exported files.
+pub const FIELDS: &[&str] = &["chain", "date", "time", "value", "state", "reason", "note"];
+
+/// The state of rows which only hold a note.
+const NOTE_STATE: &str = "note";
+
+/// The column each field is read from, found by name in the header row.
+pub struct Columns {
+    names: Vec<(&'static str, String)>,
+}
+
+impl Columns {
+    /// Read the columns from `FIELD=COLUMN` mappings, unmapped fields are
+    /// read from the column named like the field.
+    pub fn parse(mappings: &[&str]) -> Result<Columns> {
+        let mut names: Vec<(&'static str, String)> = FIELDS
+            .iter()
+            .map(|field| (*field, field.to_string()))
+            .collect();
+
+        for mapping in mappings.iter() {
+            let (field, column) = mapping.split_once('=').ok_or_else(|| {
+                ChainError::new(&format!("Invalid mapping \"{}\", expected FIELD=COLUMN", mapping))
+            })?;
+
+            match names.iter_mut().find(|(name, _)| *name == field) {
+                Some((_, name)) => *name = column.to_string(),
+                None => {
+                    return Err(ChainError::new(&format!(
+                        "Unknown field \"{}\", the fields are {}",
+                        field,
+                        FIELDS.join(", ")
+                    ))
+                    .into())
+                }
+            }
+        }
+
+        Ok(Columns { names })
+    }
+
+    /// The index of each field in `header`, ignoring case.
+    fn indices(&self, header: &csv::StringRecord) -> Vec<(&'static str, Option<usize>)> {
+        self.names
+            .iter()
+            .map(|(field, name)| {
+                let index = header
+                    .iter()
+                    .position(|column| column.trim().eq_ignore_ascii_case(name));
+
+                (*field, index)
+            })
+            .collect()
+    }
+}
+
+/// Write one row per link, with the first note of a day on its first link.
+/// Every other note gets a row of its own.
+pub fn write(backup: &Backup, out: &mut dyn Write) -> Result<()> {
+    let mut writer = csv::Writer::from_writer(out);
+
+    writer.write_record(FIELDS)?;
+
+    for chain in backup.chains.iter() {
+        let mut dates: Vec<NaiveDate> = chain
+            .links
+            .iter()
+            .map(|link| link.date)
+            .chain(chain.notes.iter().map(|note| note.date))
+            .collect();
+        dates.sort();
+        dates.dedup();
+
+        for date in dates {
+            let mut notes = chain
+                .notes
+                .iter()
+                .filter(|note| note.date == date)
+                .map(|note| note.text.as_str());
+
+            for (index, link) in chain.links.iter().filter(|link| link.date == date).enumerate() {
+                writer.write_record([
+                    chain.name.as_str(),
+                    &link.date.format(FORMAT).to_string(),
+                    &link.time
+                        .map(|time| time.format(TIME_FORMAT).to_string())
+                        .unwrap_or_default(),
+                    &link.value.map(|value| value.to_string()).unwrap_or_default(),
+                    &link.state,
+                    link.reason.as_deref().unwrap_or_default(),
+                    if index == 0 { notes.next().unwrap_or_default() } else { "" },
+                ])?;
+            }
+
+            for note in notes {
+                let date = date.format(FORMAT).to_string();
+                writer.write_record([chain.name.as_str(), &date, "", "", NOTE_STATE, "", note])?;
+            }
+        }
+    }
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn invalid(row: usize, field: &str, value: &str) -> anyhow::Error {
+    ChainError::new(&format!("Invalid {} \"{}\" in row {}", field, value, row)).into()
+}
+
+/// Read rows into a backup, so they are imported like a JSON backup.
+///
+/// Rows without a chain column belong to `chain`, rows with the state
+/// `note` only add their note.
+pub fn read(input: &mut dyn Read, columns: &Columns, date_format: &str, chain: Option<&str>) -> Result<Backup> {
+    let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(input);
+    let indices = columns.indices(reader.headers()?);
+
+    let index = |field: &str| indices.iter().find(|(name, _)| *name == field).and_then(|(_, index)| *index);
+
+    if index("date").is_none() {
+        return Err(ChainError::new("The file has no date column, map one with --map date=COLUMN").into());
+    }
+
+    if index("chain").is_none() && chain.is_none() {
+        return Err(ChainError::new(
+            "The file has no chain column, map one with --map chain=COLUMN or pass --chain",
+        )
+        .into());
+    }
+
+    let mut chains: Vec<BackupChain> = Vec::new();
+
+    for (row, record) in reader.records().enumerate() {
+        let record = record?;
+        // The header is the first row.
+        let row = row + 2;
+
+        let cell = |field: &str| {
+            index(field)
+                .and_then(|index| record.get(index))
+                .map(|value| value.trim())
+                .filter(|value| !value.is_empty())
+        };
+
+        let name = match cell("chain").or(chain) {
+            Some(name) => name,
+            None => continue,
+        };
+
+        let date = match cell("date") {
+            Some(date) => NaiveDate::parse_from_str(date, date_format)
+                .map_err(|_| invalid(row, "date", date))?,
+            None => continue,
+        };
+
+        let position = match chains.iter().position(|chain| chain.name == name) {
+            Some(position) => position,
+            None => {
+                chains.push(BackupChain {
+                    name: name.to_string(),
+                    ..Default::default()
+                });
+
+                chains.len() - 1
+            }
+        };
+        let backup_chain = &mut chains[position];
+
+        if let Some(text) = cell("note") {
+            backup_chain.notes.push(BackupNote {
+                date,
+                text: text.to_string(),
+            });
+        }
+
+        let state = match cell("state") {
+            Some(NOTE_STATE) => continue,
+            Some(state) => match State::parse(state) {
+                State::Done if state != State::Done.as_str() => return Err(invalid(row, "state", state)),
+                State::Missed => return Err(invalid(row, "state", state)),
+                state => state,
+            },
+            None => State::Done,
+        };
+
+        let time = match cell("time") {
+            Some(time) => Some(
+                NaiveTime::parse_from_str(time, TIME_FORMAT)
+                    .or_else(|_| NaiveTime::parse_from_str(time, "%H:%M"))
+                    .map_err(|_| invalid(row, "time", time))?,
+            ),
+            None => None,
+        };
+
+        let value = match cell("value") {
+            Some(value) => Some(value.parse::<f64>().map_err(|_| invalid(row, "value", value))?),
+            None => None,
+        };
+
+        backup_chain.links.push(BackupLink {
+            date,
+            time,
+            value,
+            state: state.as_str().to_string(),
+            reason: cell("reason").map(|reason| reason.to_string()),
+        });
+    }
+
+    Ok(Backup {
+        version: VERSION,
+        chains,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 1, day).unwrap()
+    }
+
+    fn read_csv(csv: &str, mappings: &[&str], date_format: &str, chain: Option<&str>) -> Result<Backup> {
+        read(&mut csv.as_bytes(), &Columns::parse(mappings)?, date_format, chain)
+    }
+
+    #[test]
+    fn written_rows_read_back() {
+        let chain = BackupChain {
+            name: "read".to_string(),
+            links: vec![
+                BackupLink {
+                    date: date(1),
+                    time: Some(NaiveTime::from_hms_opt(21, 30, 0).unwrap()),
+                    value: Some(2.25),
+                    state: "done".to_string(),
+                    reason: None,
+                },
+                BackupLink {
+                    date: date(2),
+                    time: None,
+                    value: None,
+                    state: "excused".to_string(),
+                    reason: Some("ill, in bed".to_string()),
+                },
+            ],
+            notes: vec![
+                BackupNote {
+                    date: date(1),
+                    text: "A good \"book\"".to_string(),
+                },
+                BackupNote {
+                    date: date(1),
+                    text: "Read it twice".to_string(),
+                },
+                BackupNote {
+                    date: date(3),
+                    text: "Nothing to read\nat all".to_string(),
+                },
+                BackupNote {
+                    date: date(3),
+                    text: "Library closed".to_string(),
+                },
+            ],
+            ..Default::default()
+        };
+
+        let mut csv: Vec<u8> = Vec::new();
+        write(
+            &Backup {
+                version: VERSION,
+                chains: vec![chain.clone()],
+            },
+            &mut csv,
+        )
+        .unwrap();
+
+        let backup = read_csv(&String::from_utf8(csv).unwrap(), &[], FORMAT, None).unwrap();
+
+        assert_eq!(backup.chains, vec![chain]);
+    }
+
+    #[test]
+    fn columns_are_mapped() {
+        let csv = "Day,Amount,Comment\n01/01/2024,3,\n02/01/2024,,Rest day\n";
+
+        let backup = read_csv(csv, &["date=day", "value=Amount", "note=comment"], "%d/%m/%Y", Some("gym")).unwrap();
+        let chain = &backup.chains[0];
+
+        assert_eq!(chain.name, "gym");
+        assert_eq!(chain.links.len(), 2);
+        assert_eq!((chain.links[0].date, chain.links[0].value), (date(1), Some(3.0)));
+        assert_eq!(chain.notes[0].text, "Rest day");
+    }
+
+    #[test]
+    fn invalid_rows_and_mappings_are_reported() {
+        let err = read_csv("chain,date,value\nread,2024-01-01,3\nread,2024-01-02,lots\n", &[], FORMAT, None)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Invalid value \"lots\" in row 3");
+
+        let err = read_csv("chain,date,state\nread,2024-01-01,missed\n", &[], FORMAT, None).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid state \"missed\" in row 2");
+
+        let err = read_csv("chain,value\n", &[], FORMAT, None).unwrap_err();
+        assert!(err.to_string().starts_with("The file has no date column"));
+
+        let err = Columns::parse(&["amount=Value"]).err().unwrap();
+        assert!(err.to_string().starts_with("Unknown field \"amount\""));
+    }
+}
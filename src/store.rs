@@ -0,0 +1,31 @@
+use super::database::SqliteStore;
+use super::text_store::TextStore;
+use anyhow::Result;
+use rusqlite::Connection;
+use std::path::Path;
+
+pub const SQLITE: &str = "sqlite";
+pub const TEXT: &str = "text";
+
+pub const BACKENDS: &[&str] = &[SQLITE, TEXT];
+
+/// Where chains are kept between commands.
+///
+/// Every command works on a SQLite connection, so a store only has to load
+/// its chains into a connection and save them back after a change.
+pub trait Store {
+    /// Open a connection to the chains, with the tables set up.
+    fn open(&self) -> Result<Connection>;
+
+    /// Save the changes made on `conn`, called before its transaction is
+    /// committed.
+    fn save(&self, conn: &Connection) -> Result<()>;
+}
+
+/// The store of `backend` at `path`.
+pub fn open(backend: &str, path: &Path) -> Box<dyn Store> {
+    match backend {
+        TEXT => Box::new(TextStore::new(path)),
+        _ => Box::new(SqliteStore::new(path)),
+    }
+}
@@ -1,21 +1,149 @@
-use chrono::NaiveDate;
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime, Weekday};
+use std::path::PathBuf;
 
-#[derive(Debug)]
+/// Whether links on a chain mark a day as done or as a relapse.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Kind {
+    #[default]
+    Positive,
+    Negative,
+}
+
+impl Kind {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Kind::Positive => "positive",
+            Kind::Negative => "negative",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Kind {
+        match kind {
+            "negative" => Kind::Negative,
+            _ => Kind::Positive,
+        }
+    }
+}
+
+/// How the links of a composite chain are derived from its chains.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rule {
+    /// A day is done when every chain was done.
+    All,
+    /// A day is done when at least one chain was done.
+    Any,
+}
+
+impl Rule {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Rule::All => "all",
+            Rule::Any => "any",
+        }
+    }
+
+    pub fn parse(rule: &str) -> Option<Rule> {
+        match rule {
+            "all" => Some(Rule::All),
+            "any" => Some(Rule::Any),
+            _ => None,
+        }
+    }
+}
+
+/// The state of a day on a chain.
+///
+/// `Missed` and `Untracked` are never stored. `Missed` is the state of a day
+/// without any links and `Untracked` the state of a day before the chain
+/// was started.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+    Done,
+    Missed,
+    Failed,
+    Skipped,
+    Excused,
+    Untracked,
+}
+
+impl State {
+    pub fn as_str(&self) -> &str {
+        match self {
+            State::Done => "done",
+            State::Missed => "missed",
+            State::Failed => "failed",
+            State::Skipped => "skipped",
+            State::Excused => "excused",
+            State::Untracked => "untracked",
+        }
+    }
+
+    pub fn parse(state: &str) -> State {
+        match state {
+            "failed" => State::Failed,
+            "skipped" => State::Skipped,
+            "excused" => State::Excused,
+            "missed" => State::Missed,
+            _ => State::Done,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
 pub struct Chain {
     pub id: i64,
     pub name: String,
+    pub unit: Option<String>,
+    pub target: Option<f64>,
+    pub quota: Option<i32>,
+    pub kind: Kind,
+    pub description: Option<String>,
+    pub created: Option<NaiveDate>,
+    pub start: Option<NaiveDate>,
+    pub color: Option<String>,
+    pub icon: Option<String>,
+    pub goal: Option<String>,
+    pub end: Option<NaiveDate>,
+    pub archived: bool,
+    pub tags: Vec<String>,
+    pub rule: Option<Rule>,
+    pub children: Vec<i64>,
 }
 
 #[derive(Debug)]
 pub struct Day {
     pub day: i32,
-    pub is_done: bool,
+    pub weekday: Weekday,
+    pub state: State,
+    pub value: Option<f64>,
 }
 
 #[derive(Debug)]
 pub struct Link {
+    pub id: i64,
     pub chain_id: i32,
     pub date: NaiveDate,
+    pub time: Option<NaiveTime>,
+    pub value: Option<f64>,
+    pub state: State,
+    pub reason: Option<String>,
+}
+
+/// The combined links of a chain on a single day.
+#[derive(Debug)]
+pub struct DayTotal {
+    pub date: NaiveDate,
+    pub value: Option<f64>,
+    pub count: i32,
+    pub state: State,
+}
+
+#[derive(Debug)]
+pub struct Note {
+    pub id: i64,
+    pub chain_id: i32,
+    pub date: NaiveDate,
+    pub text: String,
 }
 
 #[derive(Debug)]
@@ -23,5 +151,73 @@ pub struct Streak {
     pub name: String,
     pub streak: i32,
     pub longest_streak: i32,
+    pub total: Option<f64>,
+    pub average: Option<f64>,
+    pub unit: Option<String>,
+    pub kind: Kind,
+    pub description: Option<String>,
+    pub goal: Option<String>,
+    pub created: Option<NaiveDate>,
+    pub start: Option<NaiveDate>,
+    pub color: Option<String>,
+    pub icon: Option<String>,
+    pub done_days: i32,
+    pub missed_days: i32,
+    pub failed_days: i32,
+    pub skipped_days: i32,
+    pub excused_days: i32,
+    pub children: Vec<(Streak, Vec<Day>)>,
+}
+
+/// A command which changed the database, recorded so it can be undone.
+#[derive(Debug)]
+pub struct Operation {
+    pub id: i64,
+    pub time: NaiveDateTime,
+    pub user: String,
+    pub command: String,
+    pub state: String,
 }
 
+/// A summary of the database, printed by `db info`.
+#[derive(Debug)]
+pub struct DatabaseInfo {
+    pub path: PathBuf,
+    pub size: u64,
+    pub version: i64,
+    pub chains: i64,
+    pub links: i64,
+    pub notes: i64,
+    pub operations: i64,
+}
+
+/// What an import added, skipped and could not import.
+#[derive(Debug, Default)]
+pub struct ImportSummary {
+    pub added: Vec<String>,
+    pub chains_added: i64,
+    pub chains_merged: i64,
+    pub links_added: i64,
+    pub links_skipped: i64,
+    pub notes_added: i64,
+    pub notes_skipped: i64,
+    pub conflicts: Vec<String>,
+}
+
+/// What merging another database changed, see `sync::merge`.
+#[derive(Debug, Default)]
+pub struct SyncSummary {
+    pub added: Vec<String>,
+    pub updated: Vec<String>,
+    pub renamed: Vec<(String, String)>,
+    pub deleted: Vec<String>,
+    pub links_added: i64,
+    pub links_updated: i64,
+    pub links_deleted: i64,
+    pub links_skipped: i64,
+    pub notes_added: i64,
+    pub notes_updated: i64,
+    pub notes_deleted: i64,
+    pub notes_skipped: i64,
+    pub conflicts: Vec<String>,
+}
@@ -0,0 +1,879 @@
+use super::chain_error::ChainError;
+use super::database::{self, SqliteStore};
+use super::store::Store;
+use super::structs::SyncSummary;
+use super::{Chain, Link, Note, State};
+use anyhow::Result;
+use chrono::NaiveTime;
+use rusqlite::{params, Connection};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use super::{FORMAT, NAME, TIME_FORMAT};
+
+/// The time of a change in UTC, so devices in different time zones agree on
+/// which change is newer.
+const NOW: &str = "strftime('%Y-%m-%dT%H:%M:%fZ', 'now')";
+
+/// Tables whose rows have a uuid, which identifies them on every device.
+const SYNCED_TABLES: &[&str] = &["chains", "links", "notes"];
+
+/// Tables whose changes count as a change of their chain.
+const CHAIN_TABLES: &[&str] = &["tags", "components", "aliases"];
+
+const EXTENSION: &str = "db";
+
+/// Create the triggers which give new rows a uuid, keep the time of the last
+/// change of every row and leave a tombstone for every deleted row, so a
+/// merge can tell a deleted row from one which never existed.
+pub fn setup_triggers(conn: &Connection) -> Result<()> {
+    for table in SYNCED_TABLES.iter() {
+        conn.execute_batch(&format!(
+            "CREATE TRIGGER IF NOT EXISTS {table}_insert_sync AFTER INSERT ON {table}
+            BEGIN
+                UPDATE {table}
+                    SET uuid = COALESCE(NEW.uuid, lower(hex(randomblob(16)))),
+                        updated = COALESCE(NEW.updated, {now})
+                    WHERE rowid = NEW.rowid AND (NEW.uuid IS NULL OR NEW.updated IS NULL);
+                DELETE FROM tombstones WHERE uuid = NEW.uuid;
+            END;
+            CREATE TRIGGER IF NOT EXISTS {table}_update_sync AFTER UPDATE ON {table}
+            WHEN NEW.updated IS OLD.updated
+            BEGIN
+                UPDATE {table} SET updated = {now} WHERE rowid = NEW.rowid;
+            END;
+            CREATE TRIGGER IF NOT EXISTS {table}_delete_sync AFTER DELETE ON {table}
+            WHEN OLD.uuid IS NOT NULL
+            BEGIN
+                INSERT OR REPLACE INTO tombstones (uuid, deleted) VALUES (OLD.uuid, {now});
+            END;",
+            table = table,
+            now = NOW
+        ))?;
+    }
+
+    for table in CHAIN_TABLES.iter() {
+        conn.execute_batch(&format!(
+            "CREATE TRIGGER IF NOT EXISTS {table}_insert_sync AFTER INSERT ON {table}
+            BEGIN
+                UPDATE chains SET updated = {now} WHERE id = NEW.chain_id;
+            END;
+            CREATE TRIGGER IF NOT EXISTS {table}_delete_sync AFTER DELETE ON {table}
+            BEGIN
+                UPDATE chains SET updated = {now} WHERE id = OLD.chain_id;
+            END;",
+            table = table,
+            now = NOW
+        ))?;
+    }
+
+    Ok(())
+}
+
+/// A row with the uuid identifying it on every device and the time of its
+/// last change.
+struct Synced<T> {
+    uuid: String,
+    updated: String,
+    item: T,
+}
+
+/// The chains, links, notes and tombstones of a database.
+struct Replica {
+    chains: Vec<Synced<Chain>>,
+    links: Vec<Synced<Link>>,
+    notes: Vec<Synced<Note>>,
+    aliases: Vec<(String, String)>,
+    tombstones: HashMap<String, String>,
+}
+
+impl Replica {
+    fn load(conn: &Connection) -> Result<Replica> {
+        let chain_ids = identities(conn, "chains")?;
+        let link_ids = identities(conn, "links")?;
+        let note_ids = identities(conn, "notes")?;
+
+        let mut replica = Replica {
+            chains: Vec::new(),
+            links: Vec::new(),
+            notes: Vec::new(),
+            aliases: database::get_aliases(conn)?,
+            tombstones: HashMap::new(),
+        };
+
+        for chain in database::get_chains(conn)? {
+            for link in database::get_links_for_chain_id(conn, chain.id as i32)? {
+                replica.links.push(synced(&link_ids, link.id, link));
+            }
+            for note in database::get_notes_for_chain_id(conn, chain.id as i32)? {
+                replica.notes.push(synced(&note_ids, note.id, note));
+            }
+
+            replica.chains.push(synced(&chain_ids, chain.id, chain));
+        }
+
+        let mut statement = conn.prepare("SELECT uuid, deleted FROM tombstones;")?;
+        replica.tombstones = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .filter_map(Result::ok)
+            .collect();
+
+        Ok(replica)
+    }
+
+    fn chain(&self, uuid: &str) -> Option<&Synced<Chain>> {
+        self.chains.iter().find(|chain| chain.uuid == uuid)
+    }
+
+    fn chain_for_id(&self, id: i64) -> Option<&Synced<Chain>> {
+        self.chains.iter().find(|chain| chain.item.id == id)
+    }
+
+    fn aliases(&self, name: &str) -> Vec<&str> {
+        self.aliases
+            .iter()
+            .filter(|(_, chain)| chain == name)
+            .map(|(alias, _)| alias.as_str())
+            .collect()
+    }
+
+    /// The time of the last change of a chain, its links or its notes.
+    fn changed(&self, chain: &Synced<Chain>) -> String {
+        let links = self
+            .links
+            .iter()
+            .filter(|link| link.item.chain_id as i64 == chain.item.id)
+            .map(|link| &link.updated);
+        let notes = self
+            .notes
+            .iter()
+            .filter(|note| note.item.chain_id as i64 == chain.item.id)
+            .map(|note| &note.updated);
+
+        links
+            .chain(notes)
+            .chain([&chain.updated])
+            .max()
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+/// The uuid and the time of the last change of every row of `table` by id.
+/// Rows from before the migration were never changed as far as a merge is
+/// concerned.
+fn identities(conn: &Connection, table: &str) -> Result<HashMap<i64, (String, String)>> {
+    let mut statement = conn.prepare(&format!(
+        "SELECT id, uuid, COALESCE(updated, '') FROM {};",
+        table
+    ))?;
+
+    let identities = statement
+        .query_map([], |row| Ok((row.get(0)?, (row.get(1)?, row.get(2)?))))?
+        .filter_map(Result::ok)
+        .collect();
+
+    Ok(identities)
+}
+
+fn synced<T>(identities: &HashMap<i64, (String, String)>, id: i64, item: T) -> Synced<T> {
+    let (uuid, updated) = identities.get(&id).cloned().unwrap_or_default();
+
+    Synced { uuid, updated, item }
+}
+
+/// Give a row the uuid and time of last change it has on the other device.
+fn set_identity(conn: &Connection, table: &str, id: i64, uuid: &str, updated: &str) -> Result<()> {
+    conn.execute(
+        &format!("UPDATE {} SET uuid = ?2, updated = ?3 WHERE id = ?1;", table),
+        params![id, uuid, updated],
+    )?;
+    conn.execute("DELETE FROM tombstones WHERE uuid = ?1;", params![uuid])?;
+
+    Ok(())
+}
+
+/// Delete a chain with its links, notes, tags, children and aliases.
+pub fn delete_chain(conn: &Connection, chain: &Chain) -> Result<()> {
+    conn.execute("DELETE FROM links WHERE chain_id = ?1;", params![chain.id])?;
+    conn.execute("DELETE FROM notes WHERE chain_id = ?1;", params![chain.id])?;
+
+    database::delete_chain_for_name(conn, &chain.name)
+}
+
+/// Merge the chains, links and notes of `other` into `conn`.
+///
+/// Rows are matched by their uuid, so renamed chains and moved links are
+/// recognized. When a row changed on both devices the newer change wins,
+/// a deletion wins unless the row changed after it was deleted. Chains
+/// which were added on both devices with the same name are merged.
+pub fn merge(conn: &Connection, other: &Connection) -> Result<SyncSummary> {
+    let mut summary = SyncSummary::default();
+    let theirs = Replica::load(other)?;
+
+    delete(conn, &theirs, &mut summary)?;
+
+    let ours = Replica::load(conn)?;
+    let (chain_ids, restored) = merge_chains(conn, &ours, &theirs, &mut summary)?;
+
+    let ours = Replica::load(conn)?;
+    merge_links(conn, &ours, &theirs, &chain_ids, &restored, &mut summary)?;
+    merge_notes(conn, &ours, &theirs, &chain_ids, &restored, &mut summary)?;
+
+    Ok(summary)
+}
+
+/// Delete the rows which were deleted on the other device.
+fn delete(conn: &Connection, theirs: &Replica, summary: &mut SyncSummary) -> Result<()> {
+    let ours = Replica::load(conn)?;
+
+    // Chains go first, the links and notes of a chain which is kept stay
+    // with it.
+    let mut tombstones: Vec<(&String, &String)> = theirs.tombstones.iter().collect();
+    tombstones.sort_by_key(|(uuid, _)| ours.chain(uuid).is_none());
+
+    let mut kept: HashSet<i64> = HashSet::new();
+    let mut removed: HashSet<i64> = HashSet::new();
+
+    for (uuid, deleted) in tombstones {
+        if let Some(chain) = ours.chain(uuid) {
+            if ours.changed(chain) > *deleted {
+                summary.conflicts.push(format!(
+                    "\"{}\" was deleted on the other device but changed here since, it was kept",
+                    chain.item.name
+                ));
+
+                // Make the chain newer than the deletion, so the other
+                // device restores it.
+                conn.execute(
+                    &format!("UPDATE chains SET updated = {} WHERE id = ?1;", NOW),
+                    params![chain.item.id],
+                )?;
+                kept.insert(chain.item.id);
+                continue;
+            }
+
+            delete_chain(conn, &chain.item)?;
+            summary.deleted.push(chain.item.name.clone());
+            removed.insert(chain.item.id);
+        } else if let Some(link) = ours.links.iter().find(|link| link.uuid == *uuid) {
+            let chain_id = link.item.chain_id as i64;
+
+            if link.updated > *deleted || kept.contains(&chain_id) {
+                continue;
+            }
+
+            if !removed.contains(&chain_id) {
+                database::delete_link_for_id(conn, link.item.id)?;
+                summary.links_deleted += 1;
+            }
+        } else if let Some(note) = ours.notes.iter().find(|note| note.uuid == *uuid) {
+            let chain_id = note.item.chain_id as i64;
+
+            if note.updated > *deleted || kept.contains(&chain_id) {
+                continue;
+            }
+
+            if !removed.contains(&chain_id) {
+                conn.execute("DELETE FROM notes WHERE id = ?1;", params![note.item.id])?;
+                summary.notes_deleted += 1;
+            }
+        } else if ours.tombstones.contains_key(uuid) {
+            continue;
+        }
+
+        // Keep the tombstone, so the deletion reaches devices which only
+        // sync with this one.
+        conn.execute(
+            "INSERT OR REPLACE INTO tombstones (uuid, deleted) VALUES (?1, ?2);",
+            params![uuid, deleted],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Add and update chains, returning the ids of their chains here by uuid
+/// and the uuids of the chains restored after they were deleted here.
+fn merge_chains(
+    conn: &Connection,
+    ours: &Replica,
+    theirs: &Replica,
+    summary: &mut SyncSummary,
+) -> Result<(HashMap<String, i64>, HashSet<String>)> {
+    let mut chain_ids: HashMap<String, i64> = HashMap::new();
+    let mut restored: HashSet<String> = HashSet::new();
+    let mut applied: Vec<(i64, &Synced<Chain>)> = Vec::new();
+
+    for their in theirs.chains.iter() {
+        if let Some(our) = ours.chain(&their.uuid) {
+            chain_ids.insert(their.uuid.clone(), our.item.id);
+
+            if their.updated > our.updated {
+                update_chain(conn, ours, our, theirs, their, summary)?;
+                applied.push((our.item.id, their));
+            }
+            continue;
+        }
+
+        if let Some(deleted) = ours.tombstones.get(&their.uuid) {
+            if theirs.changed(their) <= *deleted {
+                continue;
+            }
+
+            summary.conflicts.push(format!(
+                "\"{}\" was deleted here but changed on the other device since, it was restored",
+                their.item.name
+            ));
+            restored.insert(their.uuid.clone());
+        }
+
+        // Chains added on both devices before they were synced.
+        if let Some(our) = ours.chains.iter().find(|chain| chain.item.name == their.item.name) {
+            summary.conflicts.push(format!(
+                "\"{}\" was added on both devices, its links and notes were merged",
+                our.item.name
+            ));
+
+            // Both devices keep the smaller uuid, so they agree after
+            // syncing in either direction.
+            if their.uuid < our.uuid {
+                set_identity(conn, "chains", our.item.id, &their.uuid, &our.updated)?;
+            }
+
+            chain_ids.insert(their.uuid.clone(), our.item.id);
+            continue;
+        }
+
+        database::add_chain(conn, &their.item)?;
+
+        let chain = database::find_chain_for_name(conn, &their.item.name)?.unwrap();
+
+        for tag in their.item.tags.iter() {
+            database::add_tag(conn, &chain, tag)?;
+        }
+        add_aliases(conn, ours, &chain, theirs.aliases(&their.item.name), summary)?;
+
+        summary.added.push(chain.name.clone());
+        chain_ids.insert(their.uuid.clone(), chain.id);
+        applied.push((chain.id, their));
+    }
+
+    // Composite chains can be made of chains added after them, and changing
+    // a chain counts as a change, so the times are set last.
+    for (id, their) in applied.iter() {
+        if their.item.rule.is_some() {
+            let children: Vec<i64> = their
+                .item
+                .children
+                .iter()
+                .filter_map(|child| theirs.chain_for_id(*child))
+                .filter_map(|child| chain_ids.get(&child.uuid).copied())
+                .collect();
+
+            database::set_children(conn, *id, &children)?;
+        }
+
+        set_identity(conn, "chains", *id, &their.uuid, &their.updated)?;
+    }
+
+    Ok((chain_ids, restored))
+}
+
+/// Replace the settings, name, tags and aliases of a chain with the newer
+/// ones from the other device.
+fn update_chain(
+    conn: &Connection,
+    ours: &Replica,
+    our: &Synced<Chain>,
+    theirs: &Replica,
+    their: &Synced<Chain>,
+    summary: &mut SyncSummary,
+) -> Result<()> {
+    let chain = Chain {
+        id: our.item.id,
+        ..their.item.clone()
+    };
+
+    database::edit_chain(conn, &chain)?;
+
+    let mut name = our.item.name.clone();
+
+    if their.item.name != our.item.name {
+        if ours.chains.iter().any(|chain| chain.item.name == their.item.name) {
+            summary.conflicts.push(format!(
+                "\"{}\" was renamed to \"{}\" on the other device, which is taken here",
+                our.item.name, their.item.name
+            ));
+        } else {
+            database::edit_chain_for_name(conn, &our.item, &their.item.name)?;
+            summary
+                .renamed
+                .push((our.item.name.clone(), their.item.name.clone()));
+            name = their.item.name.clone();
+        }
+    }
+
+    for tag in our.item.tags.iter().filter(|tag| !their.item.tags.contains(tag)) {
+        database::delete_tag(conn, &chain, tag)?;
+    }
+    for tag in their.item.tags.iter().filter(|tag| !our.item.tags.contains(tag)) {
+        database::add_tag(conn, &chain, tag)?;
+    }
+
+    let their_aliases = theirs.aliases(&their.item.name);
+
+    for alias in ours.aliases(&our.item.name) {
+        if !their_aliases.contains(&alias) {
+            database::delete_alias(conn, alias)?;
+        }
+    }
+
+    let ours_aliases = ours.aliases(&our.item.name);
+    let new_aliases: Vec<&str> = their_aliases
+        .into_iter()
+        .filter(|alias| !ours_aliases.contains(alias))
+        .collect();
+    add_aliases(conn, ours, &chain, new_aliases, summary)?;
+
+    summary.updated.push(name);
+
+    Ok(())
+}
+
+fn add_aliases(
+    conn: &Connection,
+    ours: &Replica,
+    chain: &Chain,
+    aliases: Vec<&str>,
+    summary: &mut SyncSummary,
+) -> Result<()> {
+    for alias in aliases {
+        match ours.aliases.iter().find(|(existing, _)| existing == alias) {
+            Some((_, name)) => summary.conflicts.push(format!(
+                "The alias \"{}\" of \"{}\" already refers to \"{}\"",
+                alias, chain.name, name
+            )),
+            None => database::add_alias(conn, chain, alias)?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether a row the other device has should be added here, rows deleted
+/// here are only added back when they changed after they were deleted or
+/// their chain was restored.
+fn is_wanted<T>(ours: &Replica, their: &Synced<T>, chain_uuid: &str, restored: &HashSet<String>) -> bool {
+    match ours.tombstones.get(&their.uuid) {
+        Some(deleted) => their.updated > *deleted || restored.contains(chain_uuid),
+        None => true,
+    }
+}
+
+fn merge_links(
+    conn: &Connection,
+    ours: &Replica,
+    theirs: &Replica,
+    chain_ids: &HashMap<String, i64>,
+    restored: &HashSet<String>,
+    summary: &mut SyncSummary,
+) -> Result<()> {
+    // Formats like org-mode keep times to the minute, a link imported on
+    // both devices only differs in its seconds.
+    let minute = |time: Option<NaiveTime>| time.map(|time| time.format("%H:%M").to_string());
+
+    let mut existing: Vec<(i64, Link)> = Vec::new();
+
+    // Our links the other device doesn't have by uuid may still be the same
+    // check-in, imported on both devices. Each stands in for one of theirs,
+    // so repeated check-ins of a day are all kept.
+    let their_uuids: HashSet<&str> = theirs.links.iter().map(|link| link.uuid.as_str()).collect();
+    let mut matched: HashSet<i64> = HashSet::new();
+
+    for their in theirs.links.iter() {
+        let chain_uuid = match theirs.chain_for_id(their.item.chain_id as i64) {
+            Some(chain) => &chain.uuid,
+            None => continue,
+        };
+        let chain_id = match chain_ids.get(chain_uuid) {
+            Some(id) => *id,
+            None => continue,
+        };
+
+        let link = Link {
+            id: 0,
+            chain_id: chain_id as i32,
+            date: their.item.date,
+            time: their.item.time,
+            value: their.item.value,
+            state: their.item.state,
+            reason: their.item.reason.clone(),
+        };
+
+        if let Some(our) = ours.links.iter().find(|link| link.uuid == their.uuid) {
+            let is_changed = our.item.chain_id != link.chain_id
+                || our.item.date != link.date
+                || our.item.time != link.time
+                || our.item.value != link.value
+                || our.item.state != link.state
+                || our.item.reason != link.reason;
+
+            if is_changed && their.updated > our.updated {
+                conn.execute(
+                    "UPDATE links
+                        SET chain_id = ?2, date = ?3, time = ?4, value = ?5, state = ?6, reason = ?7,
+                            updated = ?8
+                        WHERE id = ?1;",
+                    params![
+                        our.item.id,
+                        link.chain_id,
+                        link.date.format(FORMAT).to_string(),
+                        link.time.map(|time| time.format(TIME_FORMAT).to_string()),
+                        link.value,
+                        link.state.as_str(),
+                        link.reason,
+                        their.updated
+                    ],
+                )?;
+                summary.links_updated += 1;
+            }
+            continue;
+        }
+
+        if !is_wanted(ours, their, chain_uuid, restored) {
+            continue;
+        }
+
+        let duplicate = ours.links.iter().find(|our| {
+            our.item.chain_id == link.chain_id
+                && !their_uuids.contains(our.uuid.as_str())
+                && !matched.contains(&our.item.id)
+                && our.item.date == link.date
+                && minute(our.item.time) == minute(link.time)
+                && our.item.state == link.state
+                && our.item.value == link.value
+        });
+
+        if let Some(duplicate) = duplicate {
+            matched.insert(duplicate.item.id);
+            summary.links_skipped += 1;
+            continue;
+        }
+
+        let is_marked = ours
+            .links
+            .iter()
+            .map(|link| &link.item)
+            .chain(existing.iter().map(|(_, link)| link))
+            .any(|existing| {
+                existing.chain_id == link.chain_id && existing.date == link.date && existing.state != State::Done
+            });
+
+        // Marks replace each other, a day can't be both skipped and failed.
+        if is_marked && link.state != State::Done {
+            let name = &theirs.chain_for_id(their.item.chain_id as i64).unwrap().item.name;
+
+            summary.conflicts.push(format!(
+                "\"{}\" is marked differently on {} on each device, the {} mark was skipped",
+                name,
+                link.date,
+                link.state.as_str()
+            ));
+            continue;
+        }
+
+        database::insert_link(conn, &link)?;
+        set_identity(conn, "links", conn.last_insert_rowid(), &their.uuid, &their.updated)?;
+
+        existing.push((chain_id, link));
+        summary.links_added += 1;
+    }
+
+    Ok(())
+}
+
+fn merge_notes(
+    conn: &Connection,
+    ours: &Replica,
+    theirs: &Replica,
+    chain_ids: &HashMap<String, i64>,
+    restored: &HashSet<String>,
+    summary: &mut SyncSummary,
+) -> Result<()> {
+    let mut existing: Vec<Note> = Vec::new();
+
+    for their in theirs.notes.iter() {
+        let chain_uuid = match theirs.chain_for_id(their.item.chain_id as i64) {
+            Some(chain) => &chain.uuid,
+            None => continue,
+        };
+        let chain_id = match chain_ids.get(chain_uuid) {
+            Some(id) => *id,
+            None => continue,
+        };
+
+        let note = Note {
+            id: 0,
+            chain_id: chain_id as i32,
+            date: their.item.date,
+            text: their.item.text.clone(),
+        };
+
+        if let Some(our) = ours.notes.iter().find(|note| note.uuid == their.uuid) {
+            let is_changed =
+                our.item.chain_id != note.chain_id || our.item.date != note.date || our.item.text != note.text;
+
+            if is_changed && their.updated > our.updated {
+                conn.execute(
+                    "UPDATE notes SET chain_id = ?2, date = ?3, text = ?4, updated = ?5 WHERE id = ?1;",
+                    params![
+                        our.item.id,
+                        note.chain_id,
+                        note.date.format(FORMAT).to_string(),
+                        note.text,
+                        their.updated
+                    ],
+                )?;
+                summary.notes_updated += 1;
+            }
+            continue;
+        }
+
+        if !is_wanted(ours, their, chain_uuid, restored) {
+            continue;
+        }
+
+        let is_duplicate = ours
+            .notes
+            .iter()
+            .map(|note| &note.item)
+            .chain(existing.iter())
+            .any(|existing| {
+                existing.chain_id == note.chain_id && existing.date == note.date && existing.text == note.text
+            });
+
+        if is_duplicate {
+            summary.notes_skipped += 1;
+            continue;
+        }
+
+        database::add_note(conn, &note)?;
+        set_identity(conn, "notes", conn.last_insert_rowid(), &their.uuid, &their.updated)?;
+
+        existing.push(note);
+        summary.notes_added += 1;
+    }
+
+    Ok(())
+}
+
+/// Merge the database at `path` into `conn`.
+///
+/// The other database is migrated on a copy, so it is never changed, it may
+/// belong to another device which is still using it.
+pub fn merge_file(conn: &Connection, path: &Path) -> Result<SyncSummary> {
+    if !path.is_file() {
+        return Err(ChainError::new(&format!("{} doesn't exist", path.display())).into());
+    }
+
+    let copy = std::env::temp_dir().join(format!("{}-sync-{}.{}", NAME, std::process::id(), EXTENSION));
+    fs::copy(path, &copy)?;
+
+    let summary = SqliteStore::new(&copy)
+        .open()
+        .and_then(|other| merge(conn, &other));
+
+    // The merge already happened, failing to clean up the copy shouldn't
+    // hide its result.
+    let _ = fs::remove_file(&copy);
+
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn database() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        database::setup_tables(&conn).unwrap();
+
+        conn
+    }
+
+    /// Changes are timed to the millisecond, make the next one newer.
+    fn later() {
+        std::thread::sleep(std::time::Duration::from_millis(2));
+    }
+
+    fn add_chain(conn: &Connection, name: &str) -> Chain {
+        database::add_chain(
+            conn,
+            &Chain {
+                name: name.to_string(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        database::find_chain_for_name(conn, name).unwrap().unwrap()
+    }
+
+    fn add_link(conn: &Connection, name: &str, day: u32) {
+        let chain = database::find_chain_for_name(conn, name).unwrap().unwrap();
+
+        database::insert_link(
+            conn,
+            &Link {
+                id: 0,
+                chain_id: chain.id as i32,
+                date: NaiveDate::from_ymd_opt(2024, 1, day).unwrap(),
+                time: None,
+                value: None,
+                state: State::Done,
+                reason: None,
+            },
+        )
+        .unwrap();
+    }
+
+    fn links(conn: &Connection, name: &str) -> usize {
+        match database::find_chain_for_name(conn, name).unwrap() {
+            Some(chain) => database::get_links_for_chain_id(conn, chain.id as i32).unwrap().len(),
+            None => 0,
+        }
+    }
+
+    fn names(conn: &Connection) -> Vec<String> {
+        database::get_chains(conn)
+            .unwrap()
+            .into_iter()
+            .map(|chain| chain.name)
+            .collect()
+    }
+
+    /// Two devices with the same chain "read" and a link on it.
+    fn devices() -> (Connection, Connection) {
+        let a = database();
+        add_chain(&a, "read");
+        add_link(&a, "read", 1);
+
+        let b = database();
+        merge(&b, &a).unwrap();
+        later();
+
+        (a, b)
+    }
+
+    #[test]
+    fn renamed_chains_are_matched_by_uuid() {
+        let (a, b) = devices();
+
+        let read = database::find_chain_for_name(&b, "read").unwrap().unwrap();
+        database::edit_chain_for_name(&b, &read, "books").unwrap();
+        add_link(&b, "books", 2);
+
+        let summary = merge(&a, &b).unwrap();
+
+        assert_eq!(summary.renamed, vec![("read".to_string(), "books".to_string())]);
+        assert_eq!(names(&a), vec!["books".to_string()]);
+        assert_eq!(links(&a, "books"), 2);
+    }
+
+    #[test]
+    fn deletions_are_merged() {
+        let (a, b) = devices();
+
+        let link = database::get_links_for_chain_id(&a, 1).unwrap().remove(0);
+        database::delete_link_for_id(&a, link.id).unwrap();
+
+        let summary = merge(&b, &a).unwrap();
+        assert_eq!(summary.links_deleted, 1);
+        assert_eq!(links(&b, "read"), 0);
+
+        // Merging again doesn't bring the link back.
+        merge(&a, &b).unwrap();
+        assert_eq!(links(&a, "read"), 0);
+
+        delete_chain(&a, &database::find_chain_for_name(&a, "read").unwrap().unwrap()).unwrap();
+
+        let summary = merge(&b, &a).unwrap();
+        assert_eq!(summary.deleted, vec!["read".to_string()]);
+        assert!(names(&b).is_empty());
+    }
+
+    #[test]
+    fn chains_changed_after_they_were_deleted_are_kept() {
+        let (a, b) = devices();
+
+        delete_chain(&a, &database::find_chain_for_name(&a, "read").unwrap().unwrap()).unwrap();
+        later();
+        add_link(&b, "read", 2);
+
+        let summary = merge(&b, &a).unwrap();
+        assert!(summary.deleted.is_empty());
+        assert_eq!(summary.conflicts.len(), 1);
+        assert_eq!(links(&b, "read"), 2);
+
+        // The other device restores it.
+        later();
+        merge(&a, &b).unwrap();
+        assert_eq!(links(&a, "read"), 2);
+    }
+
+    #[test]
+    fn repeated_check_ins_are_all_merged() {
+        let a = database();
+        database::add_chain(
+            &a,
+            &Chain {
+                name: "water".to_string(),
+                quota: Some(3),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        for _ in 0..3 {
+            add_link(&a, "water", 1);
+        }
+
+        let b = database();
+        let summary = merge(&b, &a).unwrap();
+        assert_eq!((summary.links_added, summary.links_skipped), (3, 0));
+        assert_eq!(links(&b, "water"), 3);
+
+        // Check-ins made on both devices before they first synced are
+        // matched one to one.
+        let c = database();
+        add_chain(&c, "water");
+        add_link(&c, "water", 1);
+
+        let summary = merge(&c, &a).unwrap();
+        assert_eq!((summary.links_added, summary.links_skipped), (2, 1));
+        assert_eq!(links(&c, "water"), 3);
+
+        merge(&a, &c).unwrap();
+        assert_eq!(links(&a, "water"), 3);
+    }
+
+    #[test]
+    fn chains_added_on_both_devices_are_merged_by_name() {
+        let a = database();
+        add_chain(&a, "read");
+        add_link(&a, "read", 1);
+
+        let b = database();
+        add_chain(&b, "read");
+        add_link(&b, "read", 2);
+
+        merge(&a, &b).unwrap();
+        merge(&b, &a).unwrap();
+
+        assert_eq!(names(&a), names(&b));
+        assert_eq!(links(&a, "read"), 2);
+        assert_eq!(links(&b, "read"), 2);
+    }
+}
@@ -0,0 +1,744 @@
+use super::backup::{self, Backup, BackupChain, BackupLink, BackupNote, VERSION};
+use super::chain_error::ChainError;
+use super::database;
+use super::history;
+use super::oplog;
+use super::store::Store;
+use super::structs::{Chain, Kind, Link, Note, State};
+use super::sync;
+use anyhow::{anyhow, Result};
+use chrono::{NaiveDate, NaiveTime};
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::{FORMAT, TIME_FORMAT};
+
+const EXTENSION: &str = "chain";
+
+/// Separates the settings of a chain from its ledger.
+const SEPARATOR: &str = "---";
+
+const NOTE: &str = "note";
+
+/// The file with everything which isn't in the chain files: the ids and
+/// uuids of the chains, links and notes, the undo history and the operation
+/// log.
+const STATE: &str = ".state";
+
+/// The command recorded for changes made to the chain files outside of `c`.
+const EDIT_FILES: &str = "edit chain files";
+
+/// A row of the state file, a line of JSON.
+#[derive(Serialize, Deserialize)]
+struct StateRow {
+    table: String,
+    row: Map<String, Value>,
+}
+
+/// A directory with a file per chain, which can be edited by hand and
+/// versioned with git.
+///
+/// A file starts with the settings of the chain in TOML, followed by a line
+/// with `---` and a ledger with a line per link or note:
+///
+/// ```text
+/// name = "read"
+/// unit = "pages"
+/// ---
+/// 2024-01-01
+/// 2024-01-02 21:30:00 12
+/// 2024-01-03 skipped # ill
+/// 2024-01-03 note Stayed in bed
+/// ```
+///
+/// The chains are loaded into an in-memory database for each command, along
+/// with the ids, uuids, undo history and operation log kept in `.state`, so
+/// the store can be undone and synced like the SQLite store. Changes made to
+/// the chain files by hand or by git are recorded as a change of their own
+/// when the chains are next loaded.
+pub struct TextStore {
+    dir: PathBuf,
+}
+
+impl TextStore {
+    pub fn new(dir: &Path) -> TextStore {
+        TextStore { dir: dir.to_path_buf() }
+    }
+
+    fn chain_files(&self) -> Result<Vec<PathBuf>> {
+        let mut files: Vec<PathBuf> = Vec::new();
+
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+
+            if path.extension().is_some_and(|extension| extension == EXTENSION) {
+                files.push(path);
+            }
+        }
+
+        files.sort();
+
+        Ok(files)
+    }
+}
+
+impl Store for TextStore {
+    fn open(&self) -> Result<Connection> {
+        fs::create_dir_all(&self.dir)?;
+
+        let mut chains: Vec<BackupChain> = Vec::new();
+
+        for path in self.chain_files()? {
+            let text = fs::read_to_string(&path)?;
+
+            chains.push(read_chain(&text).map_err(|err| anyhow!("Failed to read {}: {}", path.display(), err))?);
+        }
+
+        let conn = Connection::open_in_memory()?;
+
+        database::setup_tables(&conn)?;
+        oplog::set_location(&conn, &fs::canonicalize(&self.dir)?)?;
+
+        let state = self.dir.join(STATE);
+
+        let is_changed = if state.exists() {
+            let rows = read_state(&fs::read_to_string(&state)?)
+                .map_err(|err| anyhow!("Failed to read {}: {}", state.display(), err))?;
+            database::load(&conn, &rows)?;
+
+            history::begin_operation(&conn, EDIT_FILES)?;
+            let is_changed = reconcile(&conn, &chains)?;
+            history::end_operation(&conn)?;
+
+            is_changed
+        } else {
+            backup::import(
+                &conn,
+                &Backup {
+                    version: VERSION,
+                    chains,
+                },
+                false,
+            )?;
+
+            true
+        };
+
+        // Keep the uuids given to new rows, so the next command sees the
+        // same ones, and write the files as they are read back.
+        if is_changed {
+            oplog::record(&conn)?;
+            self.save(&conn)?;
+        }
+
+        Ok(conn)
+    }
+
+    fn save(&self, conn: &Connection) -> Result<()> {
+        let chains = database::get_chains(conn)?;
+        let backup = backup::export(conn, &chains)?;
+
+        let mut written: Vec<PathBuf> = Vec::new();
+
+        for chain in backup.chains.iter() {
+            let mut path = self.dir.join(format!("{}.{}", file_name(&chain.name), EXTENSION));
+
+            // Names which only differ in characters not allowed in file
+            // names get a number.
+            let mut number = 2;
+            while written.contains(&path) {
+                path = self
+                    .dir
+                    .join(format!("{}-{}.{}", file_name(&chain.name), number, EXTENSION));
+                number += 1;
+            }
+
+            let text = write_chain(chain)?;
+
+            // Only touch files which changed, to keep the history in git clean.
+            if fs::read_to_string(&path).ok().as_deref() != Some(text.as_str()) {
+                fs::write(&path, text)?;
+            }
+
+            written.push(path);
+        }
+
+        for path in self.chain_files()? {
+            if !written.contains(&path) {
+                fs::remove_file(path)?;
+            }
+        }
+
+        self.write_state(conn)
+    }
+}
+
+impl TextStore {
+    fn write_state(&self, conn: &Connection) -> Result<()> {
+        let path = self.dir.join(STATE);
+        let text = write_state(conn)?;
+
+        if fs::read_to_string(&path).ok().as_deref() != Some(text.as_str()) {
+            let temporary = path.with_extension("tmp");
+            fs::write(&temporary, text)?;
+            fs::rename(&temporary, &path)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn write_state(conn: &Connection) -> Result<String> {
+    let mut text = String::new();
+
+    for (table, row) in database::dump(conn)? {
+        text.push_str(&serde_json::to_string(&StateRow { table, row })?);
+        text.push('\n');
+    }
+
+    Ok(text)
+}
+
+fn read_state(text: &str) -> Result<Vec<(String, Map<String, Value>)>> {
+    let mut rows: Vec<(String, Map<String, Value>)> = Vec::new();
+
+    for (number, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let row: StateRow = serde_json::from_str(line).map_err(|err| anyhow!("Invalid line {}: {}", number + 1, err))?;
+        rows.push((row.table, row.row));
+    }
+
+    Ok(rows)
+}
+
+/// The settings of a chain without its links and notes, with its tags,
+/// aliases and children sorted, their order doesn't matter.
+fn settings(chain: &BackupChain) -> BackupChain {
+    let mut settings = BackupChain {
+        links: Vec::new(),
+        notes: Vec::new(),
+        ..chain.clone()
+    };
+
+    settings.tags.sort();
+    settings.aliases.sort();
+    settings.children.sort();
+
+    settings
+}
+
+/// Bring the database in line with the chain files, which may have been
+/// edited since they were written. Returns whether anything changed.
+///
+/// Chains are matched by name, a chain whose file was renamed by hand is
+/// deleted and added again.
+fn reconcile(conn: &Connection, files: &[BackupChain]) -> Result<bool> {
+    let chains = database::get_chains(conn)?;
+    let file = |name: &str| files.iter().find(|file| file.name == name);
+
+    let mut is_changed = false;
+
+    for chain in chains.iter().filter(|chain| file(&chain.name).is_none()) {
+        sync::delete_chain(conn, chain)?;
+        is_changed = true;
+    }
+
+    // Aliases are freed first, they can move to another chain.
+    for (alias, name) in database::get_aliases(conn)? {
+        if file(&name).is_none_or(|file| !file.aliases.contains(&alias)) {
+            database::delete_alias(conn, &alias)?;
+            is_changed = true;
+        }
+    }
+
+    let added: Vec<BackupChain> = files
+        .iter()
+        .filter(|file| !chains.iter().any(|chain| chain.name == file.name))
+        .cloned()
+        .collect();
+
+    if !added.is_empty() {
+        let summary = backup::import(
+            conn,
+            &Backup {
+                version: VERSION,
+                chains: added,
+            },
+            false,
+        )?;
+
+        if let Some(conflict) = summary.conflicts.first() {
+            return Err(ChainError::new(conflict).into());
+        }
+
+        is_changed = true;
+    }
+
+    for chain in chains.iter() {
+        if let Some(file) = file(&chain.name) {
+            is_changed |= reconcile_settings(conn, chain, file)?;
+            is_changed |= reconcile_links(conn, chain, file)?;
+            is_changed |= reconcile_notes(conn, chain, file)?;
+        }
+    }
+
+    Ok(is_changed)
+}
+
+fn reconcile_settings(conn: &Connection, chain: &Chain, file: &BackupChain) -> Result<bool> {
+    let aliases = database::get_aliases(conn)?;
+
+    if settings(&backup::export_settings(conn, chain, &aliases)?) == settings(file) {
+        return Ok(false);
+    }
+
+    let edited = Chain {
+        id: chain.id,
+        ..backup::to_chain(file)
+    };
+
+    database::edit_chain(conn, &edited)?;
+    conn.execute(
+        "UPDATE chains SET kind = ?2, created = ?3 WHERE id = ?1;",
+        params![
+            chain.id,
+            Kind::parse(&file.kind).as_str(),
+            file.created.map(|date| date.format(FORMAT).to_string())
+        ],
+    )?;
+
+    for tag in chain.tags.iter().filter(|tag| !file.tags.contains(tag)) {
+        database::delete_tag(conn, chain, tag)?;
+    }
+    for tag in file.tags.iter().filter(|tag| !chain.tags.contains(tag)) {
+        database::add_tag(conn, chain, tag)?;
+    }
+
+    for alias in file.aliases.iter() {
+        match aliases.iter().find(|(existing, _)| existing == alias) {
+            Some((_, name)) if *name == chain.name => (),
+            Some((_, name)) => {
+                return Err(ChainError::new(&format!(
+                    "The alias \"{}\" of \"{}\" already refers to \"{}\"",
+                    alias, chain.name, name
+                ))
+                .into())
+            }
+            None => database::add_alias(conn, chain, alias)?,
+        }
+    }
+
+    let mut children: Vec<i64> = Vec::new();
+    for name in file.children.iter() {
+        children.extend(database::find_chain_for_name(conn, name)?.map(|child| child.id));
+    }
+    database::set_children(conn, chain.id, &children)?;
+
+    Ok(true)
+}
+
+fn reconcile_links(conn: &Connection, chain: &Chain, file: &BackupChain) -> Result<bool> {
+    let mut wanted: Vec<&BackupLink> = file.links.iter().collect();
+    let mut is_changed = false;
+
+    for link in database::get_links_for_chain_id(conn, chain.id as i32)? {
+        let index = wanted.iter().position(|wanted| {
+            wanted.date == link.date
+                && wanted.time == link.time
+                && wanted.value == link.value
+                && wanted.state == link.state.as_str()
+                && wanted.reason == link.reason
+        });
+
+        match index {
+            Some(index) => {
+                wanted.remove(index);
+            }
+            None => {
+                database::delete_link_for_id(conn, link.id)?;
+                is_changed = true;
+            }
+        }
+    }
+
+    for link in wanted {
+        database::insert_link(
+            conn,
+            &Link {
+                id: 0,
+                chain_id: chain.id as i32,
+                date: link.date,
+                time: link.time,
+                value: link.value,
+                state: State::parse(&link.state),
+                reason: link.reason.clone(),
+            },
+        )?;
+        is_changed = true;
+    }
+
+    Ok(is_changed)
+}
+
+fn reconcile_notes(conn: &Connection, chain: &Chain, file: &BackupChain) -> Result<bool> {
+    let mut wanted: Vec<&BackupNote> = file.notes.iter().collect();
+    let mut is_changed = false;
+
+    for note in database::get_notes_for_chain_id(conn, chain.id as i32)? {
+        match wanted.iter().position(|wanted| wanted.date == note.date && wanted.text == note.text) {
+            Some(index) => {
+                wanted.remove(index);
+            }
+            None => {
+                conn.execute("DELETE FROM notes WHERE id = ?1;", params![note.id])?;
+                is_changed = true;
+            }
+        }
+    }
+
+    for note in wanted {
+        database::add_note(
+            conn,
+            &Note {
+                id: 0,
+                chain_id: chain.id as i32,
+                date: note.date,
+                text: note.text.clone(),
+            },
+        )?;
+        is_changed = true;
+    }
+
+    Ok(is_changed)
+}
+
+fn file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            c if c.is_alphanumeric() || "-_ ".contains(c) => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// Notes are kept on one line.
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some(c) => unescaped.push(c),
+            None => unescaped.push('\\'),
+        }
+    }
+
+    unescaped
+}
+
+fn write_chain(chain: &BackupChain) -> Result<String> {
+    let settings = BackupChain {
+        links: Vec::new(),
+        notes: Vec::new(),
+        ..chain.clone()
+    };
+
+    let mut text = toml::to_string(&settings)?;
+    text.push_str(SEPARATOR);
+    text.push('\n');
+
+    let mut lines: Vec<(NaiveDate, String)> = Vec::new();
+
+    for link in chain.links.iter() {
+        let mut line = link.date.format(FORMAT).to_string();
+
+        if let Some(time) = link.time {
+            line.push_str(&format!(" {}", time.format(TIME_FORMAT)));
+        }
+        if link.state != State::Done.as_str() {
+            line.push_str(&format!(" {}", link.state));
+        }
+        // The shortest form which reads back as the same value.
+        if let Some(value) = link.value {
+            line.push_str(&format!(" {}", value));
+        }
+        if let Some(reason) = &link.reason {
+            line.push_str(&format!(" # {}", escape(reason)));
+        }
+
+        lines.push((link.date, line));
+    }
+
+    for note in chain.notes.iter() {
+        lines.push((
+            note.date,
+            format!("{} {} {}", note.date.format(FORMAT), NOTE, escape(&note.text)),
+        ));
+    }
+
+    // Keep each day's links and notes together.
+    lines.sort_by_key(|(date, _)| *date);
+
+    for (_, line) in lines {
+        text.push_str(&line);
+        text.push('\n');
+    }
+
+    Ok(text)
+}
+
+fn read_chain(text: &str) -> Result<BackupChain> {
+    let (settings, ledger) = match text.split_once(&format!("\n{}\n", SEPARATOR)) {
+        Some((settings, ledger)) => (settings, ledger),
+        None => match text.strip_suffix(&format!("\n{}", SEPARATOR)) {
+            Some(settings) => (settings, ""),
+            None => (text, ""),
+        },
+    };
+
+    let mut chain: BackupChain = toml::from_str(settings)?;
+
+    if chain.name.is_empty() {
+        return Err(ChainError::new("The chain has no name").into());
+    }
+
+    backup::check_chain(&chain)?;
+
+    // Line numbers count from the start of the file.
+    let offset = settings.lines().count() + 2;
+
+    for (number, line) in ledger.lines().enumerate() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        read_line(&mut chain, line).ok_or_else(|| anyhow!("Invalid line {}: {}", number + offset, line))?;
+    }
+
+    Ok(chain)
+}
+
+/// Read a line of the ledger into the chain, `None` if it is invalid.
+fn read_line(chain: &mut BackupChain, line: &str) -> Option<()> {
+    let (date, rest) = line.split_once(' ').unwrap_or((line, ""));
+    let date = NaiveDate::parse_from_str(date, FORMAT).ok()?;
+    let rest = rest.trim();
+
+    if let Some(text) = rest.strip_prefix(NOTE) {
+        chain.notes.push(BackupNote {
+            date,
+            text: unescape(text.trim()),
+        });
+
+        return Some(());
+    }
+
+    let (mut rest, reason) = match rest.split_once("# ") {
+        Some((rest, reason)) => (rest.trim(), Some(unescape(reason.trim()))),
+        None => (rest, None),
+    };
+
+    let mut link = BackupLink {
+        date,
+        time: None,
+        value: None,
+        state: State::Done.as_str().to_string(),
+        reason,
+    };
+
+    while !rest.is_empty() {
+        let (word, remaining) = rest.split_once(' ').unwrap_or((rest, ""));
+        rest = remaining.trim();
+
+        if let Ok(time) = NaiveTime::parse_from_str(word, TIME_FORMAT) {
+            link.time = Some(time);
+        } else if let Ok(value) = word.parse::<f64>() {
+            link.value = Some(value);
+        } else if ["done", "failed", "skipped", "excused"].contains(&word) {
+            link.state = word.to_string();
+        } else {
+            return None;
+        }
+    }
+
+    chain.links.push(link);
+
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(text: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(text, FORMAT).unwrap()
+    }
+
+    fn store(name: &str) -> TextStore {
+        let dir = std::env::temp_dir().join(format!("c-text-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        TextStore::new(&dir)
+    }
+
+    fn uuids(conn: &Connection) -> Vec<String> {
+        let mut statement = conn
+            .prepare("SELECT uuid FROM chains UNION ALL SELECT uuid FROM links ORDER BY 1;")
+            .unwrap();
+        let uuid_iter = statement.query_map(params![], |row| row.get(0)).unwrap();
+
+        uuid_iter.map(Result::unwrap).collect()
+    }
+
+    fn links(conn: &Connection) -> usize {
+        let chain = database::get_chain_for_name(conn, "read").unwrap();
+
+        database::get_links_for_chain_id(conn, chain.id as i32).unwrap().len()
+    }
+
+    #[test]
+    fn chains_read_back_as_written() {
+        let chain = BackupChain {
+            name: "read".to_string(),
+            kind: Kind::Positive.as_str().to_string(),
+            unit: Some("pages".to_string()),
+            target: Some(20.0),
+            created: Some(date("2024-01-01")),
+            tags: vec!["books".to_string()],
+            aliases: vec!["r".to_string()],
+            rule: Some("all".to_string()),
+            children: vec!["novels".to_string()],
+            links: vec![
+                BackupLink {
+                    date: date("2024-01-01"),
+                    time: None,
+                    value: None,
+                    state: "done".to_string(),
+                    reason: None,
+                },
+                BackupLink {
+                    date: date("2024-01-02"),
+                    time: Some(NaiveTime::from_hms_opt(21, 30, 0).unwrap()),
+                    value: Some(2.25),
+                    state: "done".to_string(),
+                    reason: None,
+                },
+                BackupLink {
+                    date: date("2024-01-03"),
+                    time: None,
+                    value: Some(-0.125),
+                    state: "skipped".to_string(),
+                    reason: Some("ill # in bed".to_string()),
+                },
+            ],
+            notes: vec![BackupNote {
+                date: date("2024-01-03"),
+                text: "Stayed in bed\nall day \\ night".to_string(),
+            }],
+            ..Default::default()
+        };
+
+        let text = write_chain(&chain).unwrap();
+
+        assert!(text.contains("\n---\n2024-01-01\n2024-01-02 21:30:00 2.25\n"));
+        assert_eq!(read_chain(&text).unwrap(), chain);
+    }
+
+    #[test]
+    fn invalid_lines_are_reported_by_number() {
+        let err = read_chain("name = \"read\"\n---\n2024-01-01\n2024-01-02 maybe\n").unwrap_err();
+
+        assert_eq!(err.to_string(), "Invalid line 4: 2024-01-02 maybe");
+    }
+
+    #[test]
+    fn reopening_keeps_uuids_and_history() {
+        let store = store("reopen");
+
+        let conn = store.open().unwrap();
+        history::begin_operation(&conn, "add-chain read").unwrap();
+        backup::import(
+            &conn,
+            &Backup {
+                version: VERSION,
+                chains: vec![BackupChain {
+                    name: "read".to_string(),
+                    kind: Kind::Positive.as_str().to_string(),
+                    links: vec![BackupLink {
+                        date: date("2024-01-01"),
+                        time: None,
+                        value: Some(3.0),
+                        state: "done".to_string(),
+                        reason: None,
+                    }],
+                    ..Default::default()
+                }],
+            },
+            false,
+        )
+        .unwrap();
+        history::end_operation(&conn).unwrap();
+        oplog::record(&conn).unwrap();
+        store.save(&conn).unwrap();
+
+        let reopened = store.open().unwrap();
+        assert_eq!(uuids(&reopened), uuids(&conn));
+        assert_eq!(database::dump(&reopened).unwrap(), database::dump(&conn).unwrap());
+
+        // The undo history came along.
+        assert_eq!(history::undo(&reopened, 1).unwrap()[0].command, "add-chain read");
+        store.save(&reopened).unwrap();
+        assert!(database::get_chains(&store.open().unwrap()).unwrap().is_empty());
+
+        fs::remove_dir_all(&store.dir).unwrap();
+    }
+
+    #[test]
+    fn edited_files_are_recorded_as_an_operation() {
+        let store = store("edit");
+        let path = store.dir.join("read.chain");
+
+        fs::create_dir_all(&store.dir).unwrap();
+        fs::write(&path, "name = \"read\"\n---\n2024-01-01\n").unwrap();
+
+        let conn = store.open().unwrap();
+        let before = uuids(&conn);
+        assert_eq!(links(&conn), 1);
+
+        let mut text = fs::read_to_string(&path).unwrap();
+        text.push_str("2024-01-02 skipped # ill\n");
+        fs::write(&path, text).unwrap();
+
+        let conn = store.open().unwrap();
+        assert_eq!(links(&conn), 2);
+        // The link which didn't change keeps its uuid.
+        assert!(before.iter().all(|uuid| uuids(&conn).contains(uuid)));
+
+        let undone = history::undo(&conn, 1).unwrap();
+        assert_eq!(undone[0].command, EDIT_FILES);
+        store.save(&conn).unwrap();
+
+        assert!(!fs::read_to_string(&path).unwrap().contains("skipped"));
+        assert_eq!(links(&store.open().unwrap()), 1);
+
+        fs::remove_dir_all(&store.dir).unwrap();
+    }
+}
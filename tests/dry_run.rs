@@ -0,0 +1,103 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+/// A home directory of its own for the commands of a test.
+struct Home {
+    path: PathBuf,
+}
+
+impl Home {
+    fn new(name: &str) -> Home {
+        let path = std::env::temp_dir().join(format!("c-dry-run-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+
+        Home { path }
+    }
+
+    fn output(&self, args: &[&str]) -> Output {
+        Command::new(env!("CARGO_BIN_EXE_c"))
+            .env("HOME", &self.path)
+            .env("XDG_CONFIG_HOME", self.path.join(".config"))
+            .env("XDG_DATA_HOME", self.path.join(".local").join("share"))
+            .args(args)
+            .output()
+            .unwrap()
+    }
+
+    fn run(&self, args: &[&str]) -> String {
+        let output = self.output(args);
+
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    fn config_file(&self) -> PathBuf {
+        self.path.join(".config").join("chain").join("config.toml")
+    }
+
+    fn file(&self, name: &str) -> PathBuf {
+        self.path.join(name)
+    }
+}
+
+impl Drop for Home {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+fn path(path: &Path) -> &str {
+    path.to_str().unwrap()
+}
+
+#[test]
+fn export_writes_no_file() {
+    let home = Home::new("export");
+    home.run(&["add-chain", "read"]);
+
+    let file = home.file("chains.ics");
+    let output = home.run(&["export", "--format", "ics", "--file", path(&file), "--dry-run"]);
+
+    assert!(output.contains("was not written"));
+    assert!(!file.exists());
+
+    home.run(&["export", "--format", "ics", "--file", path(&file)]);
+    assert!(file.exists());
+}
+
+#[test]
+fn convert_creates_no_store() {
+    let home = Home::new("convert");
+    home.run(&["add-chain", "read"]);
+
+    let target = home.file("text");
+    home.run(&["--dry-run", "db", "convert", "text", path(&target)]);
+
+    assert!(!target.exists());
+}
+
+#[test]
+fn config_and_profiles_are_not_saved() {
+    let home = Home::new("config");
+
+    home.run(&["config", "set", "days", "14", "--dry-run"]);
+    assert!(!home.config_file().exists());
+
+    home.run(&["profile", "create", "work", "--dry-run"]);
+    assert!(!home.run(&["profile"]).contains("work"));
+
+    home.run(&["profile", "create", "work"]);
+    home.run(&["profile", "use", "work", "--dry-run"]);
+    assert!(!home.config_file().exists());
+}
+
+#[test]
+fn servers_are_refused() {
+    let home = Home::new("serve");
+
+    assert!(!home.output(&["serve", "--dry-run"]).status.success());
+    assert!(!home.output(&["sync", "serve", "--dry-run"]).status.success());
+}
@@ -0,0 +1,327 @@
+use serde_json::{json, Value};
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+
+/// A running `c serve` with its own home directory.
+struct Server {
+    child: Child,
+    address: String,
+    home: PathBuf,
+    token: Option<String>,
+    /// Global options, given to every command run on the server's database.
+    options: Vec<String>,
+}
+
+impl Server {
+    fn start(name: &str, token: Option<&str>) -> Server {
+        Server::start_with(name, token, &[])
+    }
+
+    fn start_with(name: &str, token: Option<&str>, options: &[&str]) -> Server {
+        let home = std::env::temp_dir().join(format!("c-server-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&home);
+        fs::create_dir_all(&home).unwrap();
+
+        let mut command = command(&home);
+        command
+            .args(options)
+            .args(["serve", "--listen", "127.0.0.1:0"])
+            .stdout(Stdio::piped());
+
+        if let Some(token) = token {
+            command.args(["--token", token]);
+        }
+
+        let mut child = command.spawn().unwrap();
+        let mut stdout = BufReader::new(child.stdout.take().unwrap());
+
+        let mut line = String::new();
+        stdout.read_line(&mut line).unwrap();
+        let address = line.trim().strip_prefix("Listening on ").unwrap().to_string();
+
+        Server {
+            child,
+            address,
+            home,
+            token: token.map(|token| token.to_string()),
+            options: options.iter().map(|option| option.to_string()).collect(),
+        }
+    }
+
+    fn request(&self, method: &str, path: &str, body: Option<Value>) -> (u16, Value) {
+        self.send(method, path, body.map(|body| body.to_string()), self.token.as_deref())
+    }
+
+    fn send(&self, method: &str, path: &str, body: Option<String>, token: Option<&str>) -> (u16, Value) {
+        let body = body.unwrap_or_default();
+        let mut request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Type: application/json\r\nContent-Length: {}\r\n",
+            method,
+            path,
+            self.address,
+            body.len()
+        );
+
+        if let Some(token) = token {
+            request.push_str(&format!("Authorization: Bearer {}\r\n", token));
+        }
+
+        request.push_str("\r\n");
+        request.push_str(&body);
+
+        self.send_raw(&request)
+    }
+
+    /// Send a request as is, the server sees the end of the body after it.
+    fn send_raw(&self, request: &str) -> (u16, Value) {
+        let mut stream = TcpStream::connect(&self.address).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+        let body = match body {
+            "" => Value::Null,
+            body => serde_json::from_str(body).unwrap(),
+        };
+
+        (status, body)
+    }
+
+    /// Run a command on the database of the server.
+    fn run(&self, args: &[&str]) -> String {
+        let output = command(&self.home).args(&self.options).args(args).output().unwrap();
+
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+        String::from_utf8(output.stdout).unwrap()
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.home);
+    }
+}
+
+fn command(home: &PathBuf) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_c"));
+    command
+        .env("HOME", home)
+        .env("XDG_CONFIG_HOME", home.join(".config"))
+        .env("XDG_DATA_HOME", home.join(".local").join("share"))
+        .env_remove("C_API_TOKEN");
+
+    command
+}
+
+fn names(chains: &Value) -> Vec<&str> {
+    chains
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|chain| chain["name"].as_str().unwrap())
+        .collect()
+}
+
+fn dates(links: &Value) -> Vec<&str> {
+    links
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|link| link["date"].as_str().unwrap())
+        .collect()
+}
+
+#[test]
+fn chains_are_created_renamed_and_deleted() {
+    let server = Server::start("chains", None);
+
+    let (status, chain) = server.request("POST", "/chains", Some(json!({ "name": "read", "unit": "pages", "tags": ["books"] })));
+    assert_eq!(status, 201);
+    assert_eq!(chain["name"], "read");
+    assert_eq!(chain["unit"], "pages");
+    assert_eq!(chain["tags"], json!(["books"]));
+    assert_eq!(chain["streak"], 0);
+
+    let (status, _) = server.request("POST", "/chains", Some(json!({ "name": "read" })));
+    assert_eq!(status, 409);
+
+    let (status, chains) = server.request("GET", "/chains", None);
+    assert_eq!(status, 200);
+    assert_eq!(names(&chains), vec!["read"]);
+
+    let (status, chain) = server.request("PATCH", "/chains/read", Some(json!({ "name": "books" })));
+    assert_eq!(status, 200);
+    assert_eq!(chain["name"], "books");
+
+    let (status, _) = server.request("GET", "/chains/read", None);
+    assert_eq!(status, 404);
+
+    let (status, body) = server.request("DELETE", "/chains/books", None);
+    assert_eq!(status, 204);
+    assert_eq!(body, Value::Null);
+
+    let (_, chains) = server.request("GET", "/chains", None);
+    assert_eq!(chains, json!([]));
+}
+
+#[test]
+fn links_are_added_moved_and_removed() {
+    let server = Server::start("links", None);
+
+    server.request("POST", "/chains", Some(json!({ "name": "morning run", "unit": "km" })));
+
+    let (status, chain) = server.request("POST", "/chains/morning%20run/links", Some(json!({ "date": "yesterday", "value": 5 })));
+    assert_eq!(status, 201);
+    assert_eq!(chain["streak"], 1);
+    assert_eq!(chain["due"], true);
+
+    let (status, chain) = server.request("POST", "/chains/morning%20run/links", None);
+    assert_eq!(status, 201);
+    assert_eq!(chain["streak"], 2);
+    assert_eq!(chain["due"], false);
+    assert_eq!(chain["total"], 5.0);
+
+    let days = chain["days"].as_array().unwrap();
+    assert_eq!(days.last().unwrap()["state"], "done");
+    assert_eq!(days[days.len() - 2]["value"], 5.0);
+
+    server.request("POST", "/chains/morning%20run/links", Some(json!({ "date": "2024-01-01", "note": "Rain" })));
+
+    let (status, _) = server.request("PATCH", "/chains/morning%20run/links/2024-01-01", Some(json!({ "date": "2024-01-02" })));
+    assert_eq!(status, 200);
+
+    let (_, links) = server.request("GET", "/chains/morning%20run/links", None);
+    assert!(dates(&links).contains(&"2024-01-02"));
+    assert!(!dates(&links).contains(&"2024-01-01"));
+
+    let (status, _) = server.request("DELETE", "/chains/morning%20run/links/2024-01-02", None);
+    assert_eq!(status, 200);
+
+    let (status, _) = server.request("DELETE", "/chains/morning%20run/links/2024-01-02", None);
+    assert_eq!(status, 404);
+
+    let (_, links) = server.request("GET", "/chains/morning%20run/links", None);
+    assert_eq!(links.as_array().unwrap().len(), 2);
+}
+
+#[test]
+fn due_lists_chains_not_done_today() {
+    let server = Server::start("due", None);
+
+    server.request("POST", "/chains", Some(json!({ "name": "read" })));
+    server.request("POST", "/chains", Some(json!({ "name": "run" })));
+    server.request("POST", "/chains", Some(json!({ "name": "smoke", "kind": "negative" })));
+    server.request("POST", "/chains/run/links", None);
+
+    let (status, due) = server.request("GET", "/due", None);
+    assert_eq!(status, 200);
+    assert_eq!(names(&due), vec!["read"]);
+}
+
+#[test]
+fn requests_need_the_token() {
+    let server = Server::start("token", Some("secret"));
+
+    let (status, body) = server.send("GET", "/chains", None, None);
+    assert_eq!(status, 401);
+    assert!(body["error"].is_string());
+
+    let (status, _) = server.send("GET", "/chains", None, Some("wrong"));
+    assert_eq!(status, 401);
+
+    let (status, _) = server.send("POST", "/chains", Some(json!({ "name": "read" }).to_string()), None);
+    assert_eq!(status, 401);
+
+    let (status, chains) = server.request("GET", "/chains", None);
+    assert_eq!(status, 200);
+    assert_eq!(chains, json!([]));
+}
+
+#[test]
+fn invalid_requests_are_refused() {
+    let server = Server::start("invalid", None);
+
+    server.request("POST", "/chains", Some(json!({ "name": "read" })));
+
+    let (status, body) = server.send("POST", "/chains", Some("{".to_string()), None);
+    assert_eq!(status, 400);
+    assert!(body["error"].as_str().unwrap().starts_with("Invalid body"));
+
+    let (status, _) = server.request("POST", "/chains", Some(json!({ "name": "" })));
+    assert_eq!(status, 400);
+
+    let (status, _) = server.request("POST", "/chains/read/links", Some(json!({ "date": "someday" })));
+    assert_eq!(status, 400);
+
+    let (status, _) = server.request("POST", "/chains/run/links", None);
+    assert_eq!(status, 404);
+
+    let (status, _) = server.request("GET", "/unknown", None);
+    assert_eq!(status, 404);
+
+    // Deleting needs the full name.
+    let (status, _) = server.request("DELETE", "/chains/re", None);
+    assert_eq!(status, 404);
+
+    let (_, chains) = server.request("GET", "/chains", None);
+    assert_eq!(names(&chains), vec!["read"]);
+}
+
+#[test]
+fn bodies_need_a_known_and_limited_length() {
+    let server = Server::start("length", None);
+    let head = format!("POST /chains HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n", server.address);
+
+    let (status, body) = server.send_raw(&format!("{}Content-Length: 10000000\r\n\r\n{{}}", head));
+    assert_eq!(status, 413);
+    assert!(body["error"].as_str().unwrap().contains("longer than"));
+
+    let (status, _) = server.send_raw(&format!("{}\r\n{{\"name\": \"read\"}}", head));
+    assert_eq!(status, 411);
+
+    let (status, _) = server.send_raw(&format!(
+        "{}Transfer-Encoding: chunked\r\n\r\n10\r\n{{\"name\": \"read\"}}\r\n0\r\n\r\n",
+        head
+    ));
+    assert_eq!(status, 411);
+
+    let (_, chains) = server.request("GET", "/chains", None);
+    assert_eq!(chains, json!([]));
+}
+
+#[test]
+fn changes_are_saved_and_can_be_undone() {
+    let server = Server::start("undo", None);
+
+    server.request("POST", "/chains", Some(json!({ "name": "read" })));
+    server.request("POST", "/chains/read/links", None);
+
+    assert!(server.run(&["ls"]).contains("read"));
+
+    server.run(&["undo", "2"]);
+
+    let (_, chains) = server.request("GET", "/chains", None);
+    assert_eq!(chains, json!([]));
+}
+
+#[test]
+fn changes_made_meanwhile_are_kept() {
+    let server = Server::start_with("text", None, &["--backend", "text"]);
+
+    server.run(&["add-chain", "read"]);
+    server.request("POST", "/chains", Some(json!({ "name": "run" })));
+
+    let (_, chains) = server.request("GET", "/chains", None);
+    assert_eq!(names(&chains), vec!["read", "run"]);
+    assert!(server.run(&["ls"]).contains("read"));
+}